@@ -1,12 +1,21 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::{
   ffi::{OsStr, OsString},
   path::{Path, PathBuf},
 };
 
+use crate::comparer_config::FunctionStatus;
+use crate::config_command::{ConfigCommand, DiscoverInfo, ExportInfo, ImportInfo, InferSizesInfo, ValidateInfo};
+use crate::diff::DiffStyle;
 use crate::generate_report::GenerateReportOpts;
+use crate::output::{OutputDir, OutputFormat, OutputOpts};
+use crate::renaming::RenameOpts;
+use crate::symbol_files::{SymbolExportFormat, SymbolFileFormat};
 
-use super::{Command, CompareCommandInfo, CompareOpts, DisasmOpts, GenerateFullCommandInfo, GenerateReportCommandInfo};
+use super::{
+  Command, CompareCommandInfo, CompareOpts, CoverageCommandInfo, DisasmOpts, GenerateFullCommandInfo,
+  GenerateReportCommandInfo, GlobalOpts, StatusCommandInfo,
+};
 
 /// Generates orig.asm and compare.asm in the current working directory.
 /// Finds the function specified in the starsource binary, disassembles it,
@@ -31,11 +40,58 @@ struct Cli {
   #[arg(long = "no-imms")]
   no_imms: bool,
 
+  /// Pairs up the addresses that both binaries reference at the same instructions and prints each pair
+  /// under a shared `ref_<n>` name, so moved functions and globals don't count as differences.
+  #[arg(long = "pair-refs")]
+  pair_refs: bool,
+
   /// Truncate the number bytes disassembled in the compared binary to the length of the
   /// original function instead of the reported length in the pdb file.
   #[arg(long = "truncate-to-original")]
   truncate_to_original: bool,
 
+  /// Accept functions that only differ in which registers they use, as long as each register of the original
+  /// is consistently replaced by the same one. These are reported as equivalent modulo registers, together
+  /// with the renaming.
+  #[arg(long = "rename-registers")]
+  rename_registers: bool,
+
+  /// Accept functions whose local variables ended up in different stack slots, as long as each `ebp`- or
+  /// `esp`-relative slot of the original is consistently replaced by the same one. The moved variables are
+  /// named by the PDB where possible.
+  #[arg(long = "rename-stack-slots")]
+  rename_stack_slots: bool,
+
+  /// Accept functions whose basic blocks all match up, with the same control flow between them, even if
+  /// they are laid out in a different order, like swapped if/else blocks. These are reported as equivalent
+  /// modulo block layout.
+  #[arg(long = "match-blocks")]
+  match_blocks: bool,
+
+  /// Directory to write the output files into, `-` writes them to stdout instead.
+  /// Comparing functions with an output directory writes one pair of files per function.
+  #[arg(long = "out-dir", global = true)]
+  out_dir: Option<String>,
+
+  /// File name template for the output files. `{symbol}` is replaced by the function name,
+  /// `{side}` by `orig` or `compare`.
+  #[arg(long = "name-template", global = true)]
+  name_template: Option<String>,
+
+  /// Output format for everything the command reports. `json` prints one record per line to stdout,
+  /// with the function, its addresses, sizes and match ratio, or a warning or error.
+  #[arg(long, value_enum, default_value = "text", global = true)]
+  format: FormatArg,
+
+  /// Path to the comparer-config.toml to use. Defaults to $STARSOURCE_COMPARER_CONFIG, then the first
+  /// comparer-config.toml found from the current directory upwards, then the one next to the executable.
+  #[arg(long = "config", global = true)]
+  config: Option<PathBuf>,
+
+  /// Prints additional information, like the config file in use.
+  #[arg(short, long, global = true)]
+  verbose: bool,
+
   #[command(subcommand)]
   command: Commands,
 }
@@ -47,6 +103,18 @@ impl Cli {
       orig_file: args.orig_file,
       disasm_opts: self.parse_disasm_opts(),
       truncate_to_original: self.truncate_to_original,
+      output: self.parse_output_opts(),
+    }
+  }
+
+  fn parse_output_opts(&self) -> OutputOpts {
+    OutputOpts {
+      dir: self.out_dir.as_deref().map(OutputDir::parse),
+      name_template: self.name_template.clone(),
+      format: match self.format {
+        FormatArg::Text => OutputFormat::Text,
+        FormatArg::Json => OutputFormat::Json,
+      },
     }
   }
 
@@ -55,24 +123,170 @@ impl Cli {
       print_addresses: self.show_ip,
       show_mem_disp: !self.no_mem_disp,
       show_imms: !self.no_imms,
+      show_source: false,
+      pair_refs: self.pair_refs,
     }
   }
 
-  fn parse_compare_args(&self, args: &CompareArgs) -> CompareCommandInfo {
-    let compare_file_path: PathBuf = PathBuf::from(&args.starsource_file);
+  fn parse_rename_opts(&self) -> RenameOpts {
+    RenameOpts {
+      registers: self.rename_registers,
+      stack_slots: self.rename_stack_slots,
+    }
+  }
+
+  /// The arguments `compare`, `check` and `cfg` share, set up for `compare`.
+  fn parse_compare_command_info(
+    &self,
+    starcraft_file: &str,
+    starsource_file: &str,
+    debug_symbols: &[String],
+    from_file: Option<&String>,
+  ) -> CompareCommandInfo {
+    let compare_file_path: PathBuf = PathBuf::from(starsource_file);
     let compare_pdb_file = compare_file_path.with_extension("pdb");
 
     CompareCommandInfo {
       compare_opts: CompareOpts {
-        orig: PathBuf::from(&args.starcraft_file),
+        orig: PathBuf::from(starcraft_file),
         compare_file_path,
         compare_pdb_file,
-        debug_symbol: args.debug_symbol.clone(),
+        debug_symbols: debug_symbols.to_vec(),
+        symbols_file: from_file.map(PathBuf::from),
       },
       disasm_opts: self.parse_disasm_opts(),
-      enable_watcher: args.watch,
-      last_offset_size: None,
+      enable_watcher: false,
       truncate_to_original: self.truncate_to_original,
+      rename: self.parse_rename_opts(),
+      match_blocks: self.match_blocks,
+      unmatched_blocks: false,
+      write_graph: false,
+      diff_style: None,
+      output: self.parse_output_opts(),
+      fail_on_mismatch: false,
+      write_asm_files: true,
+    }
+  }
+
+  fn parse_compare_args(&self, args: &CompareArgs) -> CompareCommandInfo {
+    let info = self.parse_compare_command_info(
+      &args.starcraft_file,
+      &args.starsource_file,
+      &args.debug_symbols,
+      args.from_file.as_ref(),
+    );
+
+    CompareCommandInfo {
+      disasm_opts: DisasmOpts {
+        show_source: args.show_source,
+        ..info.disasm_opts
+      },
+      enable_watcher: args.watch,
+      match_blocks: info.match_blocks || args.unmatched_blocks,
+      unmatched_blocks: args.unmatched_blocks,
+      diff_style: args.diff.map(|diff| match diff {
+        DiffArg::Unified => DiffStyle::Unified,
+        DiffArg::SideBySide => DiffStyle::SideBySide,
+      }),
+      fail_on_mismatch: args.fail_on_mismatch,
+      ..info
+    }
+  }
+
+  fn parse_status_args(&self, args: &StatusArgs) -> StatusCommandInfo {
+    StatusCommandInfo {
+      status: args.status.map(|status| match status {
+        StatusArg::Todo => FunctionStatus::Todo,
+        StatusArg::Wip => FunctionStatus::Wip,
+        StatusArg::Matching => FunctionStatus::Matching,
+        StatusArg::Equivalent => FunctionStatus::Equivalent,
+        StatusArg::Nonmatching => FunctionStatus::NonMatching,
+      }),
+      owner: args.owner.clone(),
+      output: self.parse_output_opts(),
+    }
+  }
+
+  fn parse_coverage_args(&self, args: &CoverageArgs) -> CoverageCommandInfo {
+    CoverageCommandInfo {
+      orig: PathBuf::from(&args.starcraft_file),
+      output: self.parse_output_opts(),
+    }
+  }
+
+  fn parse_config_args(&self, args: &ConfigArgs) -> ConfigCommand {
+    match &args.command {
+      ConfigCommands::InferSizes(args) => ConfigCommand::InferSizes(InferSizesInfo {
+        orig: PathBuf::from(&args.starcraft_file),
+        dry_run: args.dry_run,
+        output: self.parse_output_opts(),
+      }),
+      ConfigCommands::Import(args) => ConfigCommand::Import(ImportInfo {
+        orig: PathBuf::from(&args.starcraft_file),
+        symbol_file: PathBuf::from(&args.symbol_file),
+        format: args.symbol_format.map(|format| match format {
+          SymbolFormatArg::Map => SymbolFileFormat::Map,
+          SymbolFormatArg::Idc => SymbolFileFormat::Idc,
+          SymbolFormatArg::GhidraCsv => SymbolFileFormat::GhidraCsv,
+          SymbolFormatArg::Csv => SymbolFileFormat::Csv,
+        }),
+        dry_run: args.dry_run,
+        output: self.parse_output_opts(),
+      }),
+      ConfigCommands::Discover(args) => ConfigCommand::Discover(DiscoverInfo {
+        orig: PathBuf::from(&args.starcraft_file),
+        dry_run: args.dry_run,
+        output: self.parse_output_opts(),
+      }),
+      ConfigCommands::Export(args) => ConfigCommand::Export(ExportInfo {
+        orig: PathBuf::from(&args.starcraft_file),
+        format: match args.export_format {
+          ExportFormatArg::Idc => SymbolExportFormat::Idc,
+          ExportFormatArg::Ghidra => SymbolExportFormat::Ghidra,
+          ExportFormatArg::X64dbg => SymbolExportFormat::X64dbg,
+          ExportFormatArg::Map => SymbolExportFormat::Map,
+        },
+        output: self.parse_output_opts(),
+      }),
+      ConfigCommands::Validate(args) => ConfigCommand::Validate(ValidateInfo {
+        orig: PathBuf::from(&args.starcraft_file),
+        compare_file_path: args.starsource_file.as_ref().map(PathBuf::from),
+        output: self.parse_output_opts(),
+      }),
+    }
+  }
+
+  fn parse_check_args(&self, args: &CheckArgs) -> CompareCommandInfo {
+    let info = self.parse_compare_command_info(
+      &args.starcraft_file,
+      &args.starsource_file,
+      &args.debug_symbols,
+      args.from_file.as_ref(),
+    );
+
+    CompareCommandInfo {
+      fail_on_mismatch: true,
+      // the disassembly is only needed to look into failures
+      write_asm_files: info.output.dir.is_some(),
+      ..info
+    }
+  }
+
+  fn parse_cfg_args(&self, args: &CfgArgs) -> CompareCommandInfo {
+    let mut info = self.parse_compare_command_info(
+      &args.starcraft_file,
+      &args.starsource_file,
+      &args.debug_symbols,
+      args.from_file.as_ref(),
+    );
+    // DOT is meant to be piped into Graphviz
+    info.output.dir.get_or_insert(OutputDir::Stdout);
+
+    CompareCommandInfo {
+      match_blocks: true,
+      write_graph: true,
+      write_asm_files: false,
+      ..info
     }
   }
 
@@ -87,7 +301,11 @@ impl Cli {
         compare_pdb_file,
       },
       disasm_opts: self.parse_disasm_opts(),
+      output: self.parse_output_opts(),
       truncate_to_original: self.truncate_to_original,
+      rename: self.parse_rename_opts(),
+      match_blocks: self.match_blocks,
+      graphs: args.graphs,
     }
   }
 }
@@ -101,14 +319,91 @@ struct CompareArgs {
   /// The respective .pdb file needs to exist in the same folder as well.
   starsource_file: String,
 
-  /// Function name/debug symbol to compare. This has to be defined for the original
-  /// binary in the comparer-config.toml. Is the size attribute missing, starsource-comparer
-  /// will use the size of the starsource function for the original binary as well.
-  debug_symbol: String,
+  /// Function names/debug symbols to compare, either as short name (`Bar::foo`) or as full signature.
+  /// Functions sharing a name can be picked by source file (`foo@file.cpp`) or address (`0x4A1230`).
+  /// Globs (`Unit*`) and regexes (`/^CImage::/`) select all matching functions of the config.
+  /// These have to be defined for the original binary in the comparer-config.toml. Is the size
+  /// attribute missing, starsource-comparer will use the size of the starsource function for the
+  /// original binary as well.
+  #[arg(required_unless_present = "from_file")]
+  debug_symbols: Vec<String>,
+
+  /// Read additional symbols, one per line, from the given file.
+  #[arg(long = "from-file")]
+  from_file: Option<String>,
 
   /// Enable watching for changes to the PDB file, updating the output files on change.
   #[arg(short, long)]
   watch: bool,
+
+  /// Show `file:line` markers and the source text above the instructions they produced in
+  /// compare.asm. orig.asm gets matching blank lines to keep both files aligned.
+  #[arg(long = "show-source")]
+  show_source: bool,
+
+  /// Print a diff of both disassemblies and their match ratio to the terminal instead of
+  /// writing orig.asm and compare.asm.
+  #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "unified")]
+  diff: Option<DiffArg>,
+
+  /// Match the basic blocks of both functions like `--match-blocks` and list the ones without a counterpart
+  /// instead of writing orig.asm and compare.asm, to tell code that is only laid out differently from code
+  /// that really differs.
+  #[arg(long = "unmatched-blocks", conflicts_with = "diff")]
+  unmatched_blocks: bool,
+
+  /// Exit with a non-zero code if any of the functions isn't byte- or instruction-identical
+  /// to the original anymore.
+  #[arg(long = "fail-on-mismatch", conflicts_with_all = ["watch", "show_source"])]
+  fail_on_mismatch: bool,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// Sets the debug binary file to use.
+  /// The respective .pdb file needs to exist in the same folder as well.
+  starsource_file: String,
+
+  /// Functions to check, selected like for `compare`. Defaults to all functions marked as
+  /// `done = true` or `status = "matching"` in the comparer-config.toml.
+  debug_symbols: Vec<String>,
+
+  /// Read additional symbols, one per line, from the given file.
+  #[arg(long = "from-file")]
+  from_file: Option<String>,
+}
+
+#[derive(Args)]
+struct CfgArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// Sets the debug binary file to use.
+  /// The respective .pdb file needs to exist in the same folder as well.
+  starsource_file: String,
+
+  /// Functions to draw, selected like for `compare`.
+  #[arg(required_unless_present = "from_file")]
+  debug_symbols: Vec<String>,
+
+  /// Read additional symbols, one per line, from the given file.
+  #[arg(long = "from-file")]
+  from_file: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+  Text,
+  Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DiffArg {
+  Unified,
+  SideBySide,
 }
 
 #[derive(Args)]
@@ -117,11 +412,144 @@ struct GenerateFullArgs {
   file: String,
 
   /// Generate the file for the original binary for all functions defined within
-  /// comparer-config.toml, skipping functions without defined sizes.
+  /// comparer-config.toml, inferring missing sizes from the code.
   #[arg(long = "orig-file")]
   orig_file: bool,
 }
 
+#[derive(Args)]
+struct StatusArgs {
+  /// Only list functions with this status. Functions without one count as `todo`.
+  #[arg(long, value_enum)]
+  status: Option<StatusArg>,
+
+  /// Only list functions with this owner.
+  #[arg(long)]
+  owner: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StatusArg {
+  Todo,
+  Wip,
+  Matching,
+  Equivalent,
+  Nonmatching,
+}
+
+#[derive(Args)]
+struct CoverageArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+  #[command(subcommand)]
+  command: ConfigCommands,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+  /// Infers the sizes of all functions without one from the code of the original binary and writes
+  /// them into comparer-config.toml.
+  #[command(arg_required_else_help = true)]
+  InferSizes(InferSizesArgs),
+  /// Checks comparer-config.toml for duplicate or overlapping functions, addresses that don't point to
+  /// code of the original binary and functions missing from the PDB.
+  #[command(arg_required_else_help = true)]
+  Validate(ValidateArgs),
+  /// Adds the functions of a symbol file to comparer-config.toml, filling in missing sizes of existing
+  /// functions and reporting conflicting ones.
+  #[command(arg_required_else_help = true)]
+  Import(ImportArgs),
+  /// Writes the configured functions and the imports of the original binary as an IDA or Ghidra script,
+  /// an x64dbg database or a MAP file.
+  #[command(arg_required_else_help = true)]
+  Export(ExportArgs),
+  /// Adds all functions that the configured ones call, directly or through other new ones, as `sub_<addr>`
+  /// with their inferred size.
+  #[command(arg_required_else_help = true)]
+  Discover(DiscoverArgs),
+}
+
+#[derive(Args)]
+struct DiscoverArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// Only print the discovered functions, leaving the config file unchanged.
+  #[arg(long = "dry-run")]
+  dry_run: bool,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// Format to write. The file is named after the binary, e.g. `Starcraft.idc`, unless --name-template is given.
+  #[arg(long = "type", value_enum)]
+  export_format: ExportFormatArg,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormatArg {
+  /// An IDC script for IDA
+  Idc,
+  /// A Python script for Ghidra's Script Manager
+  Ghidra,
+  /// An x64dbg database (.dd32)
+  X64dbg,
+  /// An MSVC linker MAP file
+  Map,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// An MSVC or IDA .map file, an IDA .idc script, a Ghidra symbol table .csv or a `name,addr,size` .csv.
+  symbol_file: String,
+
+  /// Format of the symbol file, detected from its extension by default.
+  #[arg(long = "type", value_enum)]
+  symbol_format: Option<SymbolFormatArg>,
+
+  /// Only report what would be imported, leaving the config file unchanged.
+  #[arg(long = "dry-run")]
+  dry_run: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SymbolFormatArg {
+  Map,
+  Idc,
+  GhidraCsv,
+  Csv,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// The debug binary to check the function names against.
+  /// The respective .pdb file needs to exist in the same folder as well.
+  starsource_file: Option<String>,
+}
+
+#[derive(Args)]
+struct InferSizesArgs {
+  /// Path to the original Starcraft.exe to use
+  starcraft_file: String,
+
+  /// Only print the inferred sizes, leaving the config file unchanged.
+  #[arg(long = "dry-run")]
+  dry_run: bool,
+}
+
 #[derive(Args)]
 struct GenerateReportArgs {
   /// Path to the original Starcraft.exe to use
@@ -130,6 +558,11 @@ struct GenerateReportArgs {
   /// Sets the debug binary file to use.
   /// The respective .pdb file needs to exist in the same folder as well.
   starsource_file: String,
+
+  /// Draw the basic blocks of both functions below the diff of every function that doesn't match the
+  /// original instruction by instruction. Functions with more than 200 blocks on both sides together are left out.
+  #[arg(long)]
+  graphs: bool,
 }
 
 #[derive(Subcommand)]
@@ -137,22 +570,52 @@ enum Commands {
   /// Generates two disassembly files to compare a function between the original exe and new exe.
   #[command(arg_required_else_help = true)]
   Compare(CompareArgs),
+  /// Checks that functions are still byte- or instruction-identical to the original, exiting with
+  /// a non-zero code otherwise.
+  #[command(arg_required_else_help = true)]
+  Check(CheckArgs),
+  /// Prints the control flow graphs of functions in the original and the new exe as Graphviz DOT, side by
+  /// side, with their basic blocks colored by whether they are matched, changed or missing on the other side.
+  ///
+  /// With --out-dir, one `<function>.dot` file is written per function instead.
+  #[command(arg_required_else_help = true)]
+  Cfg(CfgArgs),
   /// Generates a disassembly file with all functions defined in comparer-config.toml.
   #[command(arg_required_else_help = true)]
   GenerateFull(GenerateFullArgs),
   /// Generates an HTML report showing the state of global equivalence with the original program.
   #[command(arg_required_else_help = true)]
   GenerateReport(GenerateReportArgs),
+  /// Lists the functions of comparer-config.toml with their status, owner and notes.
+  Status(StatusArgs),
+  /// Lists the parts of the original's executable sections that no configured function covers.
+  ///
+  /// They are classified as padding, data or code, with code suggested as new functions. Call targets outside of
+  /// any configured function are listed as well.
+  #[command(arg_required_else_help = true)]
+  Coverage(CoverageArgs),
+  /// Maintains comparer-config.toml.
+  Config(ConfigArgs),
 }
 
-pub fn parse_cmdline() -> Command {
+pub fn parse_cmdline() -> (GlobalOpts, Command) {
   let cli = Cli::parse();
 
-  match &cli.command {
+  let global_opts = GlobalOpts {
+    config_path: cli.config.clone(),
+    verbose: cli.verbose,
+  };
+  let command = match &cli.command {
     Commands::GenerateFull(args) => Command::GenerateFull(cli.parse_generate_full_args(args)),
     Commands::Compare(args) => Command::Compare(cli.parse_compare_args(args)),
+    Commands::Check(args) => Command::Compare(cli.parse_check_args(args)),
+    Commands::Cfg(args) => Command::Compare(cli.parse_cfg_args(args)),
     Commands::GenerateReport(args) => Command::GenerateReport(cli.parse_generate_report_args(args)),
-  }
+    Commands::Status(args) => Command::Status(cli.parse_status_args(args)),
+    Commands::Coverage(args) => Command::Coverage(cli.parse_coverage_args(args)),
+    Commands::Config(args) => Command::Config(cli.parse_config_args(args)),
+  };
+  (global_opts, command)
 }
 
 #[allow(dead_code)]
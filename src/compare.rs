@@ -1,11 +1,13 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use std::time::Duration;
 
+use itertools::Itertools;
 use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use regex::Regex;
 use thiserror::Error;
 
 use goblin::pe::PE;
@@ -13,16 +15,35 @@ use goblin::pe::PE;
 use self::CompareError::*;
 use super::CustomUpperHexFormat;
 use super::comparer_config::*;
+use super::diff::{DiffStyle, write_diff};
 use super::disasm::*;
+use super::flow_graph::{FlowComparison, compare_flow_graphs, write_unmatched_blocks};
+use super::flow_render::write_dot;
+use super::insn_diff::{Change, InstructionDiff, diff_instructions};
+use super::output::{FunctionRecord, OutputOpts};
 use super::pdb::*;
+use super::pe::{PeError, PeImage};
+use super::renaming::{RenameOpts, Renaming, find_renaming};
+use super::sizes::infer_size;
 
 #[derive(Debug)]
 pub struct CompareCommandInfo {
   pub compare_opts: CompareOpts,
   pub disasm_opts: super::DisasmOpts,
-  pub last_offset_size: Option<(u64, usize)>,
   pub enable_watcher: bool,
   pub truncate_to_original: bool,
+  pub rename: RenameOpts,
+  /// Matches the basic blocks of both functions, see [`compare_flow_graphs`].
+  pub match_blocks: bool,
+  /// Lists the unmatched blocks instead of writing the disassembly.
+  pub unmatched_blocks: bool,
+  /// Writes the basic blocks of both functions as a DOT graph instead of the disassembly.
+  pub write_graph: bool,
+  pub diff_style: Option<DiffStyle>,
+  pub output: OutputOpts,
+  /// Fails if any of the functions doesn't match the original anymore.
+  pub fail_on_mismatch: bool,
+  pub write_asm_files: bool,
 }
 
 #[derive(Debug)]
@@ -30,7 +51,8 @@ pub struct CompareOpts {
   pub orig: PathBuf,
   pub compare_file_path: PathBuf,
   pub compare_pdb_file: PathBuf,
-  pub debug_symbol: String,
+  pub debug_symbols: Vec<String>,
+  pub symbols_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Error)]
@@ -38,11 +60,11 @@ pub enum CompareError {
   #[error("PDB file error: {0:#?}")]
   Pdb(#[from] super::pdb::PdbError),
 
-  #[error("Could not find the specified symbol in the config")]
-  ConfigSymbolNotFound,
+  #[error("Could not find the symbol '{0}' in the config")]
+  ConfigSymbolNotFound(String),
 
-  #[error("Could not find the symbol in the PDB, skipping the file")]
-  SymbolNotFound,
+  #[error("{0}")]
+  SymbolLookup(#[from] super::pdb::SymbolLookupError),
 
   #[error("IO error: {0}")]
   Io(#[from] std::io::Error),
@@ -60,13 +82,190 @@ pub enum CompareError {
   RequiredFunctionSizeNotFound(String),
 
   #[error("PE reading failed: {0}")]
-  PE(#[from] goblin::error::Error),
+  PeImage(#[from] super::pe::PeError),
+
+  #[error("The address {1:#X} of '{0}' is outside of any raw section of the original binary")]
+  AddressNotMapped(String, u64),
+
+  #[error("The function offset/size of {0} are outside of the bounds of the input file")]
+  FunctionDefSizeWrong(String),
+
+  #[error("Failed to convert disassembly to UTF-8 string: {0}")]
+  FromUtf8(#[from] std::string::FromUtf8Error),
+
+  #[error("Invalid symbol pattern: {0}")]
+  Pattern(#[from] regex::Error),
+
+  #[error("{} function(s) no longer match the original: {}", .0.len(), .0.join(", "))]
+  Mismatch(Vec<String>),
+}
+
+struct CompareDisassembly {
+  symbol: FunctionSymbol,
+  orig_lines: Vec<DisasmLine>,
+  compare_lines: Vec<DisasmLine>,
+  orig_asm: String,
+  compare_asm: String,
+  bytes_identical: bool,
+  /// Set if the disassemblies only differ in the registers and stack slots that may be renamed.
+  renaming: Option<Renaming>,
+  /// Set if the basic blocks are matched.
+  flow: Option<FlowComparison>,
+}
+
+impl CompareDisassembly {
+  /// The share of equal instructions, the same as the diffs show.
+  fn match_ratio(&self) -> f32 {
+    diff_instructions(&self.orig_lines, &self.compare_lines).ratio
+  }
+
+  /// The basic blocks of both functions as Graphviz DOT, if they were matched.
+  fn dot(&self) -> Result<Option<String>, CompareError> {
+    let Some(flow) = &self.flow else {
+      return Ok(None);
+    };
+    let mut dot = Vec::new();
+    write_dot(&mut dot, &self.symbol.name, flow, &self.orig_lines, &self.compare_lines)?;
+    Ok(Some(String::from_utf8(dot)?))
+  }
+
+  fn match_level(&self, allowed_diff: Option<&AllowedDiff>) -> MatchLevel {
+    if self.bytes_identical {
+      return MatchLevel::Bytes;
+    } else if self.orig_asm == self.compare_asm {
+      return MatchLevel::Instructions;
+    } else if let Some(renaming) = &self.renaming {
+      return if renaming.stack_slots.is_empty() {
+        MatchLevel::Registers
+      } else {
+        MatchLevel::StackSlots
+      };
+    } else if self.flow.as_ref().is_some_and(|flow| flow.summary().equivalent) {
+      return MatchLevel::Reordered;
+    }
+
+    let diff = diff_instructions(&self.orig_lines, &self.compare_lines);
+    let accepted = match allowed_diff {
+      Some(AllowedDiff::Ratio(ratio)) => diff.ratio >= *ratio,
+      Some(AllowedDiff::Differences(lines)) => diff
+        .changes
+        .iter()
+        .flat_map(|change| match change {
+          Change::Equal(..) => Vec::new(),
+          Change::Delete(line) | Change::Insert(line) => vec![line],
+          Change::Replace { orig, compare, .. } => vec![orig, compare],
+        })
+        .all(|changed| lines.iter().any(|line| line.trim() == changed.text.trim())),
+      None => false,
+    };
+    if accepted {
+      MatchLevel::Accepted
+    } else {
+      MatchLevel::Mismatch
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MatchLevel {
+  /// Same bytes as the original.
+  Bytes,
+  /// Same instructions, only differing in addresses that the disassembly replaces by names.
+  Instructions,
+  /// Same instructions apart from a consistent renaming of their registers.
+  Registers,
+  /// Same instructions apart from consistently moved stack slots, and maybe renamed registers.
+  StackSlots,
+  /// Same basic blocks with the same control flow between them, only laid out differently.
+  Reordered,
+  /// Differs only in the ways accepted by the function's `allowed_diff`.
+  Accepted,
+  Mismatch,
+}
+
+impl MatchLevel {
+  /// Whether `check` and `--fail-on-mismatch` let the function pass. Renamed registers, moved stack slots and
+  /// reordered blocks are only reported, differences have to be accepted by an `allowed_diff`.
+  fn passes(self) -> bool {
+    matches!(
+      self,
+      MatchLevel::Bytes | MatchLevel::Instructions | MatchLevel::Accepted
+    )
+  }
+}
+
+/// The original binary together with all functions known for it, loaded once per run.
+struct OrigBinary {
+  bytes: Vec<u8>,
+  image: PeImage,
+  fns: Vec<FunctionDefinition>,
+  fn_map: HashMap<u64, FunctionDefinition>,
+  globals: GlobalMap,
+}
+
+/// The rebuilt binary and its PDB, reloaded after every rebuild.
+struct RebuiltBinary {
+  bytes: Vec<u8>,
+  image: PeImage,
+  /// Kept open to look up source locations.
+  pdb: RefCell<PdbFile>,
+  pdb_funcs: PdbFunctions,
+  fn_map: HashMap<u64, FunctionDefinition>,
+  globals: GlobalMap,
+  /// Stack variables by function address, only loaded to name moved stack slots.
+  locals: HashMap<u64, Vec<StackVariable>>,
+}
+
+struct CompareTarget {
+  orig_fn: FunctionDefinition,
+  orig_offset: u64,
+  /// Finds the function in the PDB, which unlike its address stays the same across rebuilds.
+  query: String,
+  last_offset_size: Option<(u64, usize)>,
+}
+
+struct SummaryRow {
+  name: String,
+  orig_size: Option<usize>,
+  new_size: Option<usize>,
+  match_ratio: Option<f32>,
+  match_level: Option<MatchLevel>,
+  renaming: Option<Renaming>,
+}
+
+enum SymbolSelector {
+  Query(String),
+  Pattern(Regex),
 }
 
-fn get_pe_import_fns(path: &PathBuf) -> Result<Vec<FunctionDefinition>, CompareError> {
-  let bytes = std::fs::read(path)?;
-  let pe = PE::parse(&bytes)?;
-  let base = pe.image_base as u64;
+impl SymbolSelector {
+  /// Parses a regex (`/^CImage::/`), a glob (`Unit*`) or a plain query for a single function.
+  fn parse(selector: &str, cfg: &ComparerConfig) -> Result<Self, CompareError> {
+    if let Some(pattern) = selector.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+      return Ok(SymbolSelector::Pattern(Regex::new(pattern)?));
+    }
+
+    // names like `operator*` are no globs
+    if selector.contains(['*', '?']) && !cfg.func.iter().any(|func| func.name == selector) {
+      let pattern = selector
+        .split_inclusive(['*', '?'])
+        .map(|part| match part.chars().last() {
+          Some('*') => format!("{}.*", regex::escape(&part[..part.len() - 1])),
+          Some('?') => format!("{}.", regex::escape(&part[..part.len() - 1])),
+          _ => regex::escape(part),
+        })
+        .join("");
+      return Ok(SymbolSelector::Pattern(Regex::new(&format!("^{pattern}$"))?));
+    }
+
+    Ok(SymbolSelector::Query(selector.to_string()))
+  }
+}
+
+/// The import address table entries of a PE file, named after the imported functions.
+pub fn get_pe_import_fns(bytes: &[u8]) -> Result<Vec<FunctionDefinition>, PeError> {
+  let pe = PE::parse(bytes)?;
+  let base = pe.image_base;
 
   Ok(
     pe.imports
@@ -75,35 +274,123 @@ fn get_pe_import_fns(path: &PathBuf) -> Result<Vec<FunctionDefinition>, CompareE
         name: import.name.to_string(),
         addr: base + import.offset as u64,
         size: Some(4),
+        ..Default::default()
       })
       .collect(),
   )
 }
 
-pub fn run(mut info: CompareCommandInfo, cfg: &ComparerConfig) -> Result<(), CompareError> {
-  let importmap = get_pe_import_fns(&info.compare_opts.orig)?;
-  let mut orig_fns = cfg.func.clone();
-  orig_fns.extend(importmap);
+impl OrigBinary {
+  fn load(path: &Path, cfg: &ComparerConfig) -> Result<Self, CompareError> {
+    let bytes = std::fs::read(path)?;
+    let image = PeImage::parse(&bytes)?;
+    let importmap = get_pe_import_fns(&bytes)?;
+    let mut fns = cfg.func.clone();
+    fns.extend(importmap);
 
-  let orig_fn_map = orig_fns
-    .iter()
-    .map(|func| (func.addr, func.clone()))
-    .collect::<HashMap<_, _>>();
-  let orig_fn = orig_fns
-    .iter()
-    .find(|s| s.name == info.compare_opts.debug_symbol)
-    .ok_or(ConfigSymbolNotFound)?;
+    let fn_map = fns
+      .iter()
+      .map(|func| (func.addr, func.clone()))
+      .collect::<HashMap<_, _>>();
+
+    Ok(OrigBinary {
+      bytes,
+      image,
+      fns,
+      fn_map,
+      globals: global_map(&cfg.global),
+    })
+  }
+}
 
-  if orig_fn.size.is_none() {
-    if info.truncate_to_original {
-      return Err(RequiredFunctionSizeNotFound(orig_fn.name.clone()));
+impl RebuiltBinary {
+  fn load(opts: &CompareOpts, rename: RenameOpts) -> Result<Self, CompareError> {
+    let bytes = std::fs::read(&opts.compare_file_path)?;
+    let image = PeImage::parse(&bytes)?;
+    let mut pdb = open_pdb(&opts.compare_pdb_file)?;
+    let pdb_funcs = get_pdb_funcs(&mut pdb, &image)?;
+    let fn_map = get_pdb_fn_map(&pdb_funcs);
+    let globals = global_map(&get_pdb_globals(&mut pdb, &image)?);
+    let locals = if rename.stack_slots {
+      get_pdb_locals(&mut pdb, &image)?
     } else {
-      println!("WARN: No size defined for the original function, using the PDB function size instead.");
+      HashMap::new()
+    };
+
+    Ok(RebuiltBinary {
+      bytes,
+      image,
+      pdb: RefCell::new(pdb),
+      pdb_funcs,
+      fn_map,
+      globals,
+      locals,
+    })
+  }
+}
+
+impl CompareOpts {
+  /// Batch mode writes one pair of files per function and prints a summary.
+  fn is_batch(&self, selectors: &[SymbolSelector], output: &OutputOpts) -> bool {
+    self.symbols_file.is_some()
+      || (output.dir.is_some() && !output.is_stdout())
+      || selectors.len() != 1
+      || matches!(selectors[0], SymbolSelector::Pattern(_))
+  }
+
+  fn read_selectors(&self, cfg: &ComparerConfig) -> Result<Vec<SymbolSelector>, CompareError> {
+    let mut selectors = self.debug_symbols.clone();
+    if let Some(path) = &self.symbols_file {
+      let list = std::fs::read_to_string(path)?;
+      selectors.extend(
+        list
+          .lines()
+          .map(str::trim)
+          .filter(|line| !line.is_empty() && !line.starts_with('#'))
+          .map(str::to_string),
+      );
+    }
+
+    selectors
+      .iter()
+      .map(|selector| SymbolSelector::parse(selector, cfg))
+      .collect()
+  }
+}
+
+pub fn run(info: CompareCommandInfo, cfg: &ComparerConfig) -> Result<(), CompareError> {
+  let orig = OrigBinary::load(&info.compare_opts.orig, cfg)?;
+  let build = RebuiltBinary::load(&info.compare_opts, info.rename)?;
+
+  let mut selectors = info.compare_opts.read_selectors(cfg)?;
+  if selectors.is_empty() {
+    // only `check` runs without symbols, checking all finished functions
+    selectors = cfg
+      .func
+      .iter()
+      .filter(|func| func.is_done())
+      .map(|func| SymbolSelector::Query(func.name.clone()))
+      .collect();
+    if selectors.is_empty() {
+      info.output.warn("No function in the config is marked as done.")?;
     }
   }
+  let batch = info.compare_opts.is_batch(&selectors, &info.output);
+
+  let (mut targets, mut mismatches) = if batch {
+    resolve_batch_targets(&info, cfg, &orig, &build, &selectors)?
+  } else {
+    let SymbolSelector::Query(query) = &selectors[0] else {
+      unreachable!("a single pattern is always run in batch mode");
+    };
+    (vec![resolve_target(&info, cfg, &orig, &build, query)?], Vec::new())
+  };
 
   // initial run
-  run_disassemble(&mut info, cfg.address_offset, orig_fn, &orig_fn_map)?;
+  mismatches.extend(run_compare(&info, &orig, &build, &mut targets, batch)?);
+  if info.fail_on_mismatch && !mismatches.is_empty() {
+    return Err(Mismatch(mismatches));
+  }
 
   if !info.enable_watcher {
     return Ok(());
@@ -115,16 +402,19 @@ pub fn run(mut info: CompareCommandInfo, cfg: &ComparerConfig) -> Result<(), Com
 
   watcher.watch(&info.compare_opts.compare_pdb_file, RecursiveMode::NonRecursive)?;
 
-  println!(
+  writeln!(
+    info.output.messages(),
     "Started watching {} for changes. CTRL+C to quit.",
     info.compare_opts.compare_pdb_file.to_string_lossy()
-  );
+  )?;
 
   loop {
     match rx.recv() {
       Ok(Ok(evt)) => match evt.kind {
         EventKind::Create(_) | EventKind::Modify(_) => {
-          if let Err(e) = run_disassemble(&mut info, cfg.address_offset, orig_fn, &orig_fn_map) {
+          let result = RebuiltBinary::load(&info.compare_opts, info.rename)
+            .and_then(|build| run_compare(&info, &orig, &build, &mut targets, batch));
+          if let Err(e) = result {
             eprintln!("{e}");
           }
         }
@@ -142,110 +432,570 @@ pub fn run(mut info: CompareCommandInfo, cfg: &ComparerConfig) -> Result<(), Com
   }
 }
 
+/// Resolves a query to the function in the PDB and its counterpart in the config.
+fn resolve_target(
+  info: &CompareCommandInfo,
+  cfg: &ComparerConfig,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  query: &str,
+) -> Result<CompareTarget, CompareError> {
+  // the config only knows short names and the rebuilt addresses move with every build, so
+  // settle on the short name and a query that keeps finding the same function in the PDB
+  let pdb_fn = build.pdb_funcs.find(query)?;
+  let query = build.pdb_funcs.unique_query(pdb_fn);
+
+  let mut orig_fn = orig
+    .fns
+    .iter()
+    .find(|s| s.name == pdb_fn.name)
+    .ok_or_else(|| ConfigSymbolNotFound(pdb_fn.name.clone()))?
+    .clone();
+
+  if orig_fn.size.is_none() {
+    orig_fn.size = infer_size(&orig.bytes, &orig.image, cfg, orig_fn.addr);
+  }
+  if orig_fn.size.is_none() {
+    if info.truncate_to_original {
+      return Err(RequiredFunctionSizeNotFound(orig_fn.name.clone()));
+    } else {
+      info.output.warn(format_args!(
+        "No size defined for the original function '{}', using the PDB function size instead.",
+        orig_fn.name
+      ))?;
+    }
+  }
+
+  let orig_offset = cfg
+    .orig_file_offset(&orig.image, orig_fn.addr)
+    .ok_or_else(|| AddressNotMapped(orig_fn.name.clone(), orig_fn.addr))?;
+
+  Ok(CompareTarget {
+    orig_fn,
+    orig_offset,
+    query,
+    last_offset_size: None,
+  })
+}
+
+fn resolve_batch_targets(
+  info: &CompareCommandInfo,
+  cfg: &ComparerConfig,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  selectors: &[SymbolSelector],
+) -> Result<(Vec<CompareTarget>, Vec<String>), CompareError> {
+  let mut queries = Vec::new();
+  for selector in selectors {
+    match selector {
+      SymbolSelector::Query(query) => queries.push(query.clone()),
+      SymbolSelector::Pattern(pattern) => {
+        let len = queries.len();
+        queries.extend(
+          cfg
+            .func
+            .iter()
+            .filter(|func| pattern.is_match(&func.name))
+            .map(|func| func.name.clone()),
+        );
+        if queries.len() == len {
+          info.output.warn(format_args!(
+            "Pattern '{}' did not match any function in the config.",
+            pattern
+          ))?;
+        }
+      }
+    }
+  }
+
+  let mut targets: Vec<CompareTarget> = Vec::new();
+  let mut unresolved = Vec::new();
+  for query in queries.iter().unique() {
+    match resolve_target(info, cfg, orig, build, query) {
+      Ok(target) if targets.iter().any(|t| t.query == target.query) => {}
+      Ok(target) => targets.push(target),
+      Err(e) => {
+        info.output.error(query, e)?;
+        unresolved.push(query.clone());
+      }
+    }
+  }
+  Ok((targets, unresolved))
+}
+
+fn run_compare(
+  info: &CompareCommandInfo,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  targets: &mut [CompareTarget],
+  batch: bool,
+) -> Result<Vec<String>, CompareError> {
+  if !batch {
+    let target = &mut targets[0];
+    let match_level = run_disassemble(info, orig, build, target)?;
+    let mismatches = (!match_level.passes()).then(|| target.orig_fn.name.clone());
+    return Ok(mismatches.into_iter().collect());
+  }
+
+  let mut rows = Vec::new();
+  for target in targets.iter_mut() {
+    let row = match compare_batch_target(info, orig, build, target) {
+      Ok(row) => row,
+      Err(e) => {
+        info.output.error(&target.orig_fn.name, e)?;
+        SummaryRow {
+          name: target.orig_fn.name.clone(),
+          orig_size: target.orig_fn.size,
+          new_size: None,
+          match_ratio: None,
+          match_level: None,
+          renaming: None,
+        }
+      }
+    };
+    rows.push(row);
+  }
+
+  if !info.output.is_json() {
+    print_summary(&mut info.output.messages(), &rows)?;
+  }
+  Ok(
+    rows
+      .into_iter()
+      .filter(|row| row.match_level.is_none_or(|level| !level.passes()))
+      .map(|row| row.name)
+      .collect(),
+  )
+}
+
 fn run_disassemble(
-  info: &mut CompareCommandInfo,
-  orig_addr_offset: u64,
-  orig_fn: &FunctionDefinition,
-  orig_fn_map: &HashMap<u64, FunctionDefinition>,
+  info: &CompareCommandInfo,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  target: &mut CompareTarget,
+) -> Result<MatchLevel, CompareError> {
+  let disassembly = disassemble_compare(info, orig, build, target)?;
+  let FunctionSymbol {
+    name,
+    file,
+    offset,
+    size,
+    ..
+  } = &disassembly.symbol;
+
+  if info.output.is_json() {
+    info
+      .output
+      .record_function(function_record(info, target, &disassembly)?)?;
+  } else {
+    let mut messages = info.output.messages();
+    match target.last_offset_size {
+      Some((old_addr, old_size)) => {
+        write!(
+          messages,
+          "Found {} in {} at {:#X} ({:+#X}), size: {:#X} ({:+#X})",
+          name,
+          file,
+          offset,
+          CustomUpperHexFormat((*offset as i64) - (old_addr as i64)),
+          size,
+          CustomUpperHexFormat((*size as i64) - (old_size as i64)),
+        )?;
+      }
+      _ => {
+        write!(
+          messages,
+          "Found {} in {} at {:#X}, size: {:#X}",
+          name, file, offset, size
+        )?;
+      }
+    }
+
+    if let Some(orig_size) = target.orig_fn.size {
+      write!(messages, "; orig size: {:#X}", orig_size)?;
+    }
+    writeln!(messages)?;
+
+    if let Some(renaming) = &disassembly.renaming {
+      writeln!(messages, "Equivalent modulo {}: {renaming}", renaming.kind())?;
+    }
+    // `--unmatched-blocks` prints the blocks itself
+    if let Some(flow) = disassembly.flow.as_ref().filter(|_| !info.unmatched_blocks) {
+      let summary = flow.summary();
+      if !summary.equivalent {
+        writeln!(messages, "Blocks: {summary}")?;
+      } else if disassembly.match_level(None) == MatchLevel::Reordered {
+        writeln!(messages, "Equivalent modulo block layout: {summary}")?;
+      }
+    }
+  }
+
+  write_disassembly(info, &disassembly, "{side}.asm")?;
+
+  target.last_offset_size = Some((*offset, *size));
+  Ok(disassembly.match_level(target.orig_fn.allowed_diff.as_ref()))
+}
+
+fn compare_batch_target(
+  info: &CompareCommandInfo,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  target: &mut CompareTarget,
+) -> Result<SummaryRow, CompareError> {
+  let disassembly = disassemble_compare(info, orig, build, target)?;
+  let name = &target.orig_fn.name;
+
+  if info.output.is_json() {
+    info
+      .output
+      .record_function(function_record(info, target, &disassembly)?)?;
+  }
+  if info.diff_style.is_some() || info.unmatched_blocks {
+    writeln!(info.output.messages(), "{name}:")?;
+  }
+  write_disassembly(info, &disassembly, "{symbol}.{side}.asm")?;
+
+  target.last_offset_size = Some((disassembly.symbol.offset, disassembly.symbol.size));
+
+  Ok(SummaryRow {
+    name: name.clone(),
+    orig_size: target.orig_fn.size,
+    new_size: Some(disassembly.symbol.size),
+    match_ratio: Some(disassembly.match_ratio()),
+    match_level: Some(disassembly.match_level(target.orig_fn.allowed_diff.as_ref())),
+    renaming: disassembly.renaming.clone(),
+  })
+}
+
+/// Writes both disassemblies into their files or prints their diff.
+fn write_disassembly(
+  info: &CompareCommandInfo,
+  disassembly: &CompareDisassembly,
+  default_template: &str,
 ) -> Result<(), CompareError> {
-  let FunctionSymbol { file, offset, size, .. } = write_compare(info, orig_addr_offset, orig_fn, orig_fn_map)?;
-
-  match info.last_offset_size {
-    Some((old_addr, old_size)) => {
-      print!(
-        "Found {} in {} at {:#X} ({:+#X}), size: {:#X} ({:+#X})",
-        &info.compare_opts.debug_symbol,
-        file,
-        offset,
-        CustomUpperHexFormat((offset as i64) - (old_addr as i64)),
-        size,
-        CustomUpperHexFormat((size as i64) - (old_size as i64)),
-      );
+  let CompareDisassembly {
+    symbol,
+    orig_lines,
+    compare_lines,
+    orig_asm,
+    compare_asm,
+    flow,
+    ..
+  } = disassembly;
+
+  if let Some(flow) = flow.as_ref().filter(|_| info.unmatched_blocks) {
+    write_unmatched_blocks(&mut info.output.messages(), flow, orig_lines, compare_lines)?;
+    return Ok(());
+  }
+  if info.write_graph {
+    match disassembly.dot()? {
+      // JSON records contain the graph instead
+      Some(_) if info.output.is_json() && info.output.is_stdout() => {}
+      Some(dot) if info.output.is_stdout() => std::io::stdout().write_all(dot.as_bytes())?,
+      Some(dot) => info.output.write("{symbol}.dot", &symbol.name, "cfg", &dot)?,
+      None => {}
+    }
+    return Ok(());
+  }
+
+  match info.diff_style {
+    Some(style) => {
+      let diff = diff_instructions(orig_lines, compare_lines);
+      print_diff(&diff, style, info.disasm_opts.print_addresses, info.output.is_json())?
     }
-    _ => {
-      print!(
-        "Found {} in {} at {:#X}, size: {:#X}",
-        &info.compare_opts.debug_symbol, file, offset, size,
-      )
+    None if !info.write_asm_files => {}
+    // JSON records contain the disassembly instead
+    None if info.output.is_json() && info.output.is_stdout() => {}
+    None => {
+      info.output.write(default_template, &symbol.name, "orig", orig_asm)?;
+      info
+        .output
+        .write(default_template, &symbol.name, "compare", compare_asm)?;
     }
   }
+  Ok(())
+}
+
+fn function_record(
+  info: &CompareCommandInfo,
+  target: &CompareTarget,
+  disassembly: &CompareDisassembly,
+) -> Result<FunctionRecord, CompareError> {
+  let symbol = &disassembly.symbol;
+  let embed_asm =
+    info.output.is_stdout() && info.diff_style.is_none() && !info.unmatched_blocks && info.write_asm_files;
+  let graph = if info.write_graph && info.output.is_stdout() {
+    disassembly.dot()?
+  } else {
+    None
+  };
 
-  if let Some(orig_size) = orig_fn.size {
-    print!("; orig size: {:#X}", orig_size);
+  Ok(
+    FunctionRecord {
+      symbol: symbol.name.clone(),
+      signature: Some(symbol.signature.clone()),
+      file: Some(symbol.file.clone()),
+      orig_addr: Some(target.orig_fn.addr),
+      orig_size: target.orig_fn.size,
+      addr: Some(symbol.addr),
+      offset: Some(symbol.offset),
+      size: Some(symbol.size),
+      offset_change: target
+        .last_offset_size
+        .map(|(old_offset, _)| symbol.offset as i64 - old_offset as i64),
+      size_change: target
+        .last_offset_size
+        .map(|(_, old_size)| symbol.size as i64 - old_size as i64),
+      match_ratio: Some(disassembly.match_ratio()),
+      byte_identical: Some(disassembly.bytes_identical),
+      status: target.orig_fn.status,
+      owner: target.orig_fn.owner.clone(),
+      orig_asm: embed_asm.then(|| disassembly.orig_asm.clone()),
+      compare_asm: embed_asm.then(|| disassembly.compare_asm.clone()),
+      register_renaming: disassembly.renaming.as_ref().map(|renaming| renaming.registers.clone()),
+      stack_slot_renaming: disassembly
+        .renaming
+        .as_ref()
+        .map(|renaming| renaming.stack_slots.clone()),
+      blocks: disassembly.flow.as_ref().map(FlowComparison::summary),
+      graph,
+      ..Default::default()
+    }
+    .with_size_delta(),
+  )
+}
+
+/// Prints the diff to the terminal, or to stderr if stdout is taken by JSON records.
+fn print_diff(
+  diff: &InstructionDiff,
+  style: DiffStyle,
+  print_addresses: bool,
+  to_stderr: bool,
+) -> Result<(), CompareError> {
+  if to_stderr {
+    let stderr = std::io::stderr();
+    let color = stderr.is_terminal();
+    write_diff(&mut stderr.lock(), diff, style, print_addresses, color)?;
+    return Ok(());
   }
-  println!();
 
-  info.last_offset_size = Some((offset, size));
+  let stdout = std::io::stdout();
+  let color = stdout.is_terminal();
+  let mut stdout_lock = stdout.lock();
+  write_diff(&mut stdout_lock, diff, style, print_addresses, color)?;
+  stdout_lock.flush()?;
   Ok(())
 }
 
-fn write_compare(
-  info: &mut CompareCommandInfo,
-  orig_addr_offset: u64,
-  orig_fn: &FunctionDefinition,
-  orig_fn_map: &HashMap<u64, FunctionDefinition>,
-) -> Result<FunctionSymbol, CompareError> {
-  let pdb_funcs = get_pdb_funcs(&info.compare_opts.compare_pdb_file)?;
-  let fn_sym = pdb_funcs.get(&info.compare_opts.debug_symbol).ok_or(SymbolNotFound)?;
-  let pdb_fn_map = get_pdb_fn_map(&pdb_funcs);
+fn print_summary(writer: &mut impl Write, rows: &[SummaryRow]) -> Result<(), CompareError> {
+  let format_size = |size: Option<usize>| size.map_or_else(|| String::from("-"), |size| format!("{size:#X}"));
+
+  let width = rows.iter().map(|row| row.name.len()).max().unwrap_or(0).max(8);
+  writeln!(
+    writer,
+    "{:<width$}  {:>10}  {:>10}  {:>10}  {:>8}  {:>12}",
+    "Function", "Orig size", "New size", "Delta", "Match", "Identical"
+  )?;
+  for row in rows {
+    let delta = match (row.orig_size, row.new_size) {
+      (Some(orig_size), Some(new_size)) => {
+        format!("{:+#X}", CustomUpperHexFormat(new_size as i64 - orig_size as i64))
+      }
+      _ => String::from("-"),
+    };
+    let match_ratio = row
+      .match_ratio
+      .map_or_else(|| String::from("-"), |ratio| format!("{:.2} %", ratio * 100.0));
+    let identical = match row.match_level {
+      Some(MatchLevel::Bytes) => "bytes",
+      Some(MatchLevel::Instructions) => "instructions",
+      Some(MatchLevel::Registers) => "registers",
+      Some(MatchLevel::StackSlots) => "stack slots",
+      Some(MatchLevel::Reordered) => "layout",
+      Some(MatchLevel::Accepted) => "accepted",
+      Some(MatchLevel::Mismatch) => "no",
+      None => "-",
+    };
+    writeln!(
+      writer,
+      "{:<width$}  {:>10}  {:>10}  {:>10}  {:>8}  {:>12}",
+      row.name,
+      format_size(row.orig_size),
+      format_size(row.new_size),
+      delta,
+      match_ratio,
+      identical
+    )?;
+  }
 
-  let mut orig_function_bytes = if let Some(orig_size) = orig_fn.size {
-    vec![0; orig_size]
+  let renamed = rows
+    .iter()
+    .filter_map(|row| Some((&row.name, row.renaming.as_ref()?)))
+    .collect::<Vec<_>>();
+  let registers = renamed
+    .iter()
+    .filter(|(_, renaming)| !renaming.registers.is_empty())
+    .map(|(name, renaming)| (name, renaming.registers.to_string()))
+    .collect::<Vec<_>>();
+  let stack_slots = renamed
+    .iter()
+    .filter(|(_, renaming)| !renaming.stack_slots.is_empty())
+    .map(|(name, renaming)| (name, renaming.stack_slots.to_string()))
+    .collect::<Vec<_>>();
+  for (title, section) in [
+    ("Equivalent modulo registers:", registers),
+    ("Moved stack slots:", stack_slots),
+  ] {
+    if section.is_empty() {
+      continue;
+    }
+    writeln!(writer)?;
+    writeln!(writer, "{title}")?;
+    for (name, text) in section {
+      writeln!(writer, "{name:<width$}  {text}")?;
+    }
+  }
+  Ok(())
+}
+
+fn disassemble_compare(
+  info: &CompareCommandInfo,
+  orig: &OrigBinary,
+  build: &RebuiltBinary,
+  target: &CompareTarget,
+) -> Result<CompareDisassembly, CompareError> {
+  let orig_fn = &target.orig_fn;
+  let fn_sym = build.pdb_funcs.find(&target.query)?;
+
+  let orig_size = orig_fn.size.unwrap_or(fn_sym.size);
+  let compare_size = if info.truncate_to_original {
+    orig_fn
+      .size
+      .expect("orig size is None even though truncate_to_original is set. Initial check was wrong!")
   } else {
-    vec![0; fn_sym.size]
+    fn_sym.size
   };
 
-  let mut compare_function_bytes = if info.truncate_to_original {
-    vec![
-      0;
-      orig_fn
-        .size
-        .expect("orig size is None even though truncate_to_original is set. Initial check was wrong!")
-    ]
+  let orig_offset = target.orig_offset as usize;
+  let orig_function_bytes = orig
+    .bytes
+    .get(orig_offset..orig_offset + orig_size)
+    .ok_or_else(|| FunctionDefSizeWrong(orig_fn.name.clone()))?;
+
+  let compare_offset = fn_sym.offset as usize;
+  let compare_function_bytes = build
+    .bytes
+    .get(compare_offset..compare_offset + compare_size)
+    .ok_or_else(|| FunctionDefSizeWrong(fn_sym.name.clone()))?;
+
+  let (orig_annotations, compare_annotations) = if info.disasm_opts.show_source {
+    let addrs = instruction_addresses(compare_function_bytes, fn_sym.addr);
+    let locations = get_source_locations(&mut build.pdb.borrow_mut(), &build.image, &addrs)?;
+    let compare_annotations = source_annotations(&locations);
+    (blank_annotations(&compare_annotations), compare_annotations)
   } else {
-    vec![0; fn_sym.size]
+    (Annotations::new(), Annotations::new())
   };
 
-  let orig_offset = orig_fn.addr - orig_addr_offset;
-
-  read_file_into(&mut orig_function_bytes, &info.compare_opts.orig, orig_offset)?;
-  read_file_into(
-    &mut compare_function_bytes,
-    &info.compare_opts.compare_file_path,
-    fn_sym.offset,
+  let mut orig_lines = disasm_lines(
+    orig_function_bytes,
+    &info.disasm_opts,
+    orig_fn.addr,
+    &orig.fn_map,
+    &orig.globals,
+  )?;
+  let mut compare_lines = disasm_lines(
+    compare_function_bytes,
+    &info.disasm_opts,
+    fn_sym.addr,
+    &build.fn_map,
+    &build.globals,
   )?;
+  if info.disasm_opts.pair_refs {
+    pair_references(&mut orig_lines, &mut compare_lines);
+  }
 
-  write_disassembly("orig.asm", &orig_function_bytes, info, orig_fn.addr, orig_fn_map)?;
+  let orig_asm = format_lines(&orig_lines, info, &orig_annotations)?;
+  let compare_asm = format_lines(&compare_lines, info, &compare_annotations)?;
+
+  let renaming = (info.rename.is_enabled() && orig_asm != compare_asm)
+    .then(|| {
+      let locals = build.locals.get(&fn_sym.addr).map_or(&[][..], Vec::as_slice);
+      find_renaming(&orig_lines, &compare_lines, info.rename, locals)
+    })
+    .flatten();
+  let flow = info
+    .match_blocks
+    .then(|| compare_flow_graphs(&orig_lines, &compare_lines));
+
+  Ok(CompareDisassembly {
+    symbol: fn_sym.clone(),
+    orig_lines,
+    compare_lines,
+    orig_asm,
+    compare_asm,
+    bytes_identical: orig_function_bytes == compare_function_bytes,
+    renaming,
+    flow,
+  })
+}
 
-  let addr = fn_sym.offset + PDB_SEGMENT_OFFSET;
-  write_disassembly("compare.asm", &compare_function_bytes, info, addr, &pdb_fn_map)?;
+/// Builds a `; file:line` marker, followed by the source text if the file is on disk,
+/// for every instruction that starts a new source line.
+fn source_annotations(locations: &[Option<SourceLocation>]) -> Annotations {
+  let mut sources: HashMap<&str, Option<Vec<String>>> = HashMap::new();
+  let mut annotations = Annotations::new();
+  let mut last_location = None;
+
+  for (index, location) in locations.iter().enumerate() {
+    let Some(location) = location else {
+      continue;
+    };
+    if last_location == Some(location) {
+      continue;
+    }
+    last_location = Some(location);
+
+    let source = sources.entry(&location.file).or_insert_with(|| {
+      std::fs::read_to_string(&location.file)
+        .ok()
+        .map(|text| text.lines().map(str::to_string).collect())
+    });
+
+    let mut lines = vec![format!("; {}:{}", location.file, location.line)];
+    let source_line = (location.line as usize)
+      .checked_sub(1)
+      .and_then(|line| source.as_ref()?.get(line));
+    if let Some(text) = source_line {
+      lines.push(format!(";   {}", text.trim()));
+    }
+    annotations.insert(index, lines);
+  }
 
-  Ok(fn_sym.clone())
+  annotations
 }
 
-pub fn get_pdb_fn_map(pdb_funcs: &HashMap<String, FunctionSymbol>) -> HashMap<u64, FunctionDefinition> {
-  pdb_funcs
-    .values()
-    .map(|func| func.as_function_definition_pair())
+/// Mirrors annotations with the same number of blank lines, keeping the other side aligned.
+fn blank_annotations(annotations: &Annotations) -> Annotations {
+  annotations
+    .iter()
+    .map(|(&index, lines)| (index, vec![String::new(); lines.len()]))
     .collect()
 }
 
-fn write_disassembly(
-  filename: &str,
-  function_bytes: &[u8],
-  info: &mut CompareCommandInfo,
-  addr: u64,
-  fn_map: &HashMap<u64, FunctionDefinition>,
-) -> Result<(), CompareError> {
-  let path = std::env::current_dir()?.join(filename);
-  let mut writer = File::create(path).map(BufWriter::new)?;
-  write_disasm(&mut writer, function_bytes, &info.disasm_opts, addr, fn_map)?;
-  Ok(())
+pub fn get_pdb_fn_map(pdb_funcs: &PdbFunctions) -> HashMap<u64, FunctionDefinition> {
+  pdb_funcs
+    .iter()
+    .map(|func| func.as_function_definition_pair())
+    .collect()
 }
 
-fn read_file_into(buffer: &mut [u8], path: impl AsRef<Path>, offset: u64) -> Result<(), CompareError> {
-  let mut f = File::open(path)?;
-  f.seek(SeekFrom::Start(offset))?;
-  f.read_exact(buffer)?;
-  Ok(())
+fn format_lines(
+  lines: &[DisasmLine],
+  info: &CompareCommandInfo,
+  annotations: &Annotations,
+) -> Result<String, CompareError> {
+  let mut buf = Vec::new();
+  write_lines(&mut buf, lines, &info.disasm_opts, annotations)?;
+  Ok(String::from_utf8(buf)?)
 }
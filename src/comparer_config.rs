@@ -1,21 +1,90 @@
-use std::path::Path;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::pe::PeImage;
+
 const COMPARER_CONFIG_FILE: &str = "comparer-config.toml";
+const COMPARER_CONFIG_ENV: &str = "STARSOURCE_COMPARER_CONFIG";
 
 #[derive(Debug, Deserialize)]
 pub struct ComparerConfig {
-  pub address_offset: u64,
+  /// Overrides the section table of the original binary, mapping every address by
+  /// subtracting this offset.
+  pub address_offset: Option<u64>,
   pub func: Vec<FunctionDefinition>,
+  /// Global variables of the original binary, named in the disassembly.
+  #[serde(default)]
+  pub global: Vec<GlobalDefinition>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct FunctionDefinition {
   pub name: String,
   pub addr: u64,
   pub size: Option<usize>,
+  /// Finished functions, which `check` makes sure keep matching the original.
+  #[serde(default)]
+  pub done: bool,
+  pub status: Option<FunctionStatus>,
+  /// Who is working on the function.
+  pub owner: Option<String>,
+  pub notes: Option<String>,
+  /// Differences to the original that `check` accepts.
+  pub allowed_diff: Option<AllowedDiff>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GlobalDefinition {
+  pub name: String,
+  pub addr: u64,
+  /// Accesses inside the variable are named relative to it, e.g. `g_units+0x10`. Without a size,
+  /// only its address is named.
+  pub size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum FunctionStatus {
+  Todo,
+  Wip,
+  /// Compiles to the same instructions as the original. `check` fails as soon as it doesn't anymore, even if it's
+  /// still equivalent modulo registers, stack slots or block layout, unless `allowed_diff` accepts the difference.
+  Matching,
+  /// Behaves the same, but compiles to different instructions.
+  Equivalent,
+  NonMatching,
+}
+
+impl Display for FunctionStatus {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    let status = match self {
+      FunctionStatus::Todo => "todo",
+      FunctionStatus::Wip => "wip",
+      FunctionStatus::Matching => "matching",
+      FunctionStatus::Equivalent => "equivalent",
+      FunctionStatus::NonMatching => "nonmatching",
+    };
+    f.write_str(status)
+  }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum AllowedDiff {
+  /// The lowest accepted match ratio, between 0 and 1.
+  Ratio(f32),
+  /// Lines of the disassembly that may differ between both binaries.
+  Differences(Vec<String>),
+}
+
+impl FunctionDefinition {
+  /// Functions which have to keep matching the original.
+  pub fn is_done(&self) -> bool {
+    self.done || self.status == Some(FunctionStatus::Matching)
+  }
 }
 
 #[derive(Debug, Error)]
@@ -25,6 +94,12 @@ pub enum ComparerConfigError {
 
   #[error("Failed to parse config file: {0}")]
   Parse(#[from] toml::de::Error),
+
+  #[error(
+    "Could not find {COMPARER_CONFIG_FILE} in the current directory, its parents or next to the executable. \
+    Use --config or {COMPARER_CONFIG_ENV} to specify it."
+  )]
+  NotFound,
 }
 
 impl ComparerConfig {
@@ -34,8 +109,38 @@ impl ComparerConfig {
     Ok(config)
   }
 
-  pub fn read_default() -> Result<Self, ComparerConfigError> {
-    let path = std::env::current_exe()?.with_file_name(COMPARER_CONFIG_FILE);
-    Self::read_from_file(path)
+  /// Translates an address of the original binary into a file offset.
+  pub fn orig_file_offset(&self, image: &PeImage, addr: u64) -> Option<u64> {
+    match self.address_offset {
+      Some(address_offset) => addr.checked_sub(address_offset),
+      None => image.va_to_file_offset(addr),
+    }
+  }
+
+  /// Reads the config from the given path, falling back to `STARSOURCE_COMPARER_CONFIG`, the first
+  /// `comparer-config.toml` from the current directory upwards and lastly the one next to the executable.
+  /// Returns the path of the loaded file as well.
+  pub fn read(path: Option<&Path>) -> Result<(Self, PathBuf), ComparerConfigError> {
+    let path = match path {
+      Some(path) => path.to_path_buf(),
+      None => Self::find()?,
+    };
+    let config = Self::read_from_file(&path)?;
+    Ok((config, path))
+  }
+
+  fn find() -> Result<PathBuf, ComparerConfigError> {
+    if let Some(path) = std::env::var_os(COMPARER_CONFIG_ENV) {
+      return Ok(PathBuf::from(path));
+    }
+
+    let current_dir = std::env::current_dir()?;
+    let exe_path = std::env::current_exe()?.with_file_name(COMPARER_CONFIG_FILE);
+    current_dir
+      .ancestors()
+      .map(|dir| dir.join(COMPARER_CONFIG_FILE))
+      .chain(std::iter::once(exe_path))
+      .find(|path| path.is_file())
+      .ok_or(ComparerConfigError::NotFound)
   }
 }
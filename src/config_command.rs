@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use thiserror::Error;
+use toml_edit::DocumentMut;
+use zydis::{Decoder, NoOperands};
+
+use super::compare::get_pe_import_fns;
+use super::comparer_config::*;
+use super::output::{FunctionRecord, OutputOpts};
+use super::pdb::{get_pdb_funcs, open_pdb};
+use super::pe::PeImage;
+use super::sizes::{follow_function, infer_function, infer_size};
+use super::symbol_files::{SymbolEntry, SymbolExportFormat, SymbolFileFormat, parse_symbols, write_symbols};
+
+#[derive(Debug)]
+pub enum ConfigCommand {
+  InferSizes(InferSizesInfo),
+  Validate(ValidateInfo),
+  Import(ImportInfo),
+  Export(ExportInfo),
+  Discover(DiscoverInfo),
+}
+
+#[derive(Debug)]
+pub struct InferSizesInfo {
+  pub orig: PathBuf,
+  pub dry_run: bool,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug)]
+pub struct ValidateInfo {
+  pub orig: PathBuf,
+  /// The rebuilt binary, whose PDB has to contain every configured function.
+  pub compare_file_path: Option<PathBuf>,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug)]
+pub struct ImportInfo {
+  pub orig: PathBuf,
+  pub symbol_file: PathBuf,
+  /// Detected from the file extension if missing.
+  pub format: Option<SymbolFileFormat>,
+  pub dry_run: bool,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug)]
+pub struct ExportInfo {
+  pub orig: PathBuf,
+  pub format: SymbolExportFormat,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug)]
+pub struct DiscoverInfo {
+  pub orig: PathBuf,
+  pub dry_run: bool,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigCommandError {
+  #[error("IO error: {0}")]
+  Io(#[from] std::io::Error),
+
+  #[error("PE reading failed: {0}")]
+  PeImage(#[from] super::pe::PeError),
+
+  #[error("Failed to edit config file: {0}")]
+  Toml(#[from] toml_edit::TomlError),
+
+  #[error("Expected the functions of the config file as [[func]] tables")]
+  UnexpectedLayout,
+
+  #[error("PDB file error: {0:#?}")]
+  Pdb(#[from] super::pdb::PdbError),
+
+  #[error("Found {0} problem(s) in the config file")]
+  Invalid(usize),
+
+  #[error("Unknown format of the symbol file {0}, pick one with --type")]
+  UnknownSymbolFormat(PathBuf),
+}
+
+pub fn run(command: ConfigCommand, cfg: &ComparerConfig, config_path: &Path) -> Result<(), ConfigCommandError> {
+  match command {
+    ConfigCommand::InferSizes(info) => infer_sizes(info, cfg, config_path),
+    ConfigCommand::Validate(info) => validate(info, cfg),
+    ConfigCommand::Import(info) => import(info, cfg, config_path),
+    ConfigCommand::Export(info) => export(info, cfg),
+    ConfigCommand::Discover(info) => discover(info, cfg, config_path),
+  }
+}
+
+fn read_config_document(config_path: &Path, cfg: &ComparerConfig) -> Result<DocumentMut, ConfigCommandError> {
+  let doc = std::fs::read_to_string(config_path)?.parse::<DocumentMut>()?;
+  let func_count = doc
+    .get("func")
+    .and_then(|item| item.as_array_of_tables())
+    .map(|tables| tables.len());
+  if func_count.is_some_and(|count| count != cfg.func.len()) || (func_count.is_none() && !cfg.func.is_empty()) {
+    return Err(ConfigCommandError::UnexpectedLayout);
+  }
+  Ok(doc)
+}
+
+fn hex_value(value: u64) -> toml_edit::Item {
+  let value = format!("{value:#X}")
+    .parse::<toml_edit::Value>()
+    .expect("valid hex literal");
+  toml_edit::value(value)
+}
+
+fn function_table(symbol: &SymbolEntry) -> toml_edit::Table {
+  let mut table = toml_edit::Table::new();
+  table.insert("name", toml_edit::value(symbol.name.clone()));
+  table.insert("addr", hex_value(symbol.addr));
+  if let Some(size) = symbol.size {
+    table.insert("size", hex_value(size as u64));
+  }
+  table
+}
+
+/// Merges functions from a symbol file into the config. New functions are appended, existing ones only
+/// get a missing size. Different names or sizes for an existing function are reported instead.
+fn import(info: ImportInfo, cfg: &ComparerConfig, config_path: &Path) -> Result<(), ConfigCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+
+  let contents = std::fs::read_to_string(&info.symbol_file)?;
+  let format = info
+    .format
+    .or_else(|| SymbolFileFormat::detect(&info.symbol_file, &contents))
+    .ok_or_else(|| ConfigCommandError::UnknownSymbolFormat(info.symbol_file.clone()))?;
+  let symbols = parse_symbols(&contents, format, &image);
+
+  let mut doc = read_config_document(config_path, cfg)?;
+  if doc.get("func").is_none() {
+    doc.insert("func", toml_edit::Item::ArrayOfTables(toml_edit::ArrayOfTables::new()));
+  }
+  let tables = doc["func"]
+    .as_array_of_tables_mut()
+    .expect("checked by read_config_document");
+
+  let mut added = Vec::<SymbolEntry>::new();
+  let mut updated = 0;
+  for symbol in symbols.iter().unique_by(|symbol| (&symbol.name, symbol.addr)) {
+    let by_addr = cfg.func.iter().position(|func| func.addr == symbol.addr);
+    let by_name = cfg.func.iter().find(|func| func.name == symbol.name);
+
+    match (by_addr, by_name) {
+      (Some(index), _) => {
+        let func = &cfg.func[index];
+        if func.name != symbol.name {
+          info.output.warn(format_args!(
+            "'{}' at {:#X} is called '{}' in the config, keeping that name.",
+            symbol.name, symbol.addr, func.name
+          ))?;
+        }
+        match (func.size, symbol.size) {
+          (None, Some(size)) => {
+            tables
+              .get_mut(index)
+              .expect("same length as the config")
+              .insert("size", hex_value(size as u64));
+            updated += 1;
+          }
+          (Some(size), Some(new_size)) if size != new_size => {
+            info.output.warn(format_args!(
+              "'{}' has the size {:#X} in the config, but {:#X} in the symbol file.",
+              func.name, size, new_size
+            ))?;
+          }
+          _ => {}
+        }
+      }
+      (None, Some(func)) => {
+        info.output.warn(format_args!(
+          "'{}' is at {:#X} in the config, but at {:#X} in the symbol file.",
+          func.name, func.addr, symbol.addr
+        ))?;
+      }
+      (None, None)
+        if added
+          .iter()
+          .any(|other| other.addr == symbol.addr || other.name == symbol.name) =>
+      {
+        info.output.warn(format_args!(
+          "'{}' at {:#X} is defined more than once in the symbol file.",
+          symbol.name, symbol.addr
+        ))?;
+      }
+      (None, None) => {
+        tables.push(function_table(symbol));
+        added.push(symbol.clone());
+      }
+    }
+  }
+
+  if info.output.is_json() {
+    for symbol in &added {
+      info.output.record_function(FunctionRecord {
+        symbol: symbol.name.clone(),
+        orig_addr: Some(symbol.addr),
+        orig_size: symbol.size,
+        ..Default::default()
+      })?;
+    }
+  }
+
+  if !info.dry_run {
+    std::fs::write(config_path, doc.to_string())?;
+  }
+  if !info.output.is_json() {
+    println!(
+      "{} {} new function(s) and {} size(s) from {}.",
+      if info.dry_run { "Found" } else { "Imported" },
+      added.len(),
+      updated,
+      info.symbol_file.display()
+    );
+  }
+
+  Ok(())
+}
+
+fn validate(info: ValidateInfo, cfg: &ComparerConfig) -> Result<(), ConfigCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+  let decoder = Decoder::new32();
+
+  let mut problems = Vec::new();
+
+  for (name, count) in cfg.func.iter().counts_by(|func| &func.name) {
+    if count > 1 {
+      problems.push((name.clone(), format!("defined {count} times")));
+    }
+  }
+
+  let by_addr = cfg.func.iter().sorted_by_key(|func| func.addr).collect_vec();
+  // the function reaching furthest so far, which any later function starting before its end overlaps with
+  let mut furthest: Option<(u64, &FunctionDefinition)> = None;
+  for (prev, func) in by_addr.iter().tuple_windows() {
+    if let Some(size) = prev.size
+      && furthest.is_none_or(|(end, _)| prev.addr + size as u64 > end)
+    {
+      furthest = Some((prev.addr + size as u64, prev));
+    }
+    if prev.addr == func.addr {
+      problems.push((func.name.clone(), format!("has the same address as '{}'", prev.name)));
+    } else if let Some((end, other)) = furthest
+      && end > func.addr
+    {
+      problems.push((func.name.clone(), format!("overlaps with '{}'", other.name)));
+    }
+  }
+
+  for func in &cfg.func {
+    let Some(offset) = cfg.orig_file_offset(&image, func.addr) else {
+      problems.push((
+        func.name.clone(),
+        format!(
+          "address {:#X} is outside of any section of the original binary",
+          func.addr
+        ),
+      ));
+      continue;
+    };
+
+    let section_end = image.raw_section_end(offset).unwrap_or(bytes.len() as u64);
+    if let Some(size) = func.size
+      && offset + size as u64 > section_end
+    {
+      problems.push((
+        func.name.clone(),
+        format!("size {size:#X} runs past the end of its section"),
+      ));
+    }
+
+    let Some(code) = bytes.get(offset as usize..section_end.min(bytes.len() as u64) as usize) else {
+      problems.push((
+        func.name.clone(),
+        format!("address {:#X} is outside of the original binary", func.addr),
+      ));
+      continue;
+    };
+    if !matches!(decoder.decode_first::<NoOperands>(code), Ok(Some(_))) {
+      problems.push((
+        func.name.clone(),
+        format!("the bytes at {:#X} are no valid x86 instruction", func.addr),
+      ));
+    }
+  }
+
+  if let Some(compare_file_path) = &info.compare_file_path {
+    let compare_bytes = std::fs::read(compare_file_path)?;
+    let compare_image = PeImage::parse(&compare_bytes)?;
+    let pdb_funcs = get_pdb_funcs(&mut open_pdb(compare_file_path.with_extension("pdb"))?, &compare_image)?;
+    for func in &cfg.func {
+      if pdb_funcs.get(&func.name).is_none() {
+        problems.push((func.name.clone(), String::from("not found in the PDB")));
+      }
+    }
+  }
+
+  for (name, problem) in &problems {
+    info.output.error(name, problem)?;
+  }
+
+  if problems.is_empty() {
+    Ok(())
+  } else {
+    Err(ConfigCommandError::Invalid(problems.len()))
+  }
+}
+
+/// Writes inferred sizes into every `[[func]]` without one, keeping the rest of the file as is.
+fn infer_sizes(info: InferSizesInfo, cfg: &ComparerConfig, config_path: &Path) -> Result<(), ConfigCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+
+  let mut doc = read_config_document(config_path, cfg)?;
+  let Some(tables) = doc.get_mut("func").and_then(|item| item.as_array_of_tables_mut()) else {
+    return Ok(());
+  };
+
+  let mut inferred = 0;
+  for (table, func) in tables.iter_mut().zip(&cfg.func) {
+    if func.size.is_some() {
+      continue;
+    }
+
+    let Some(size) = infer_size(&bytes, &image, cfg, func.addr) else {
+      info
+        .output
+        .warn(format_args!("Could not infer the size of '{}'.", func.name))?;
+      continue;
+    };
+
+    table.insert("size", hex_value(size as u64));
+    inferred += 1;
+
+    if info.output.is_json() {
+      info.output.record_function(FunctionRecord {
+        symbol: func.name.clone(),
+        orig_addr: Some(func.addr),
+        orig_size: Some(size),
+        ..Default::default()
+      })?;
+    } else {
+      println!("{}: {:#X}", func.name, size);
+    }
+  }
+
+  if !info.dry_run {
+    std::fs::write(config_path, doc.to_string())?;
+  }
+  if !info.output.is_json() {
+    let verb = if info.dry_run { "Inferred" } else { "Wrote" };
+    println!("{verb} {inferred} size(s) for {}.", config_path.display());
+  }
+
+  Ok(())
+}
+
+/// Writes the configured functions and the imports of the original binary for disassemblers and debuggers.
+fn export(info: ExportInfo, cfg: &ComparerConfig) -> Result<(), ConfigCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+
+  let functions = cfg
+    .func
+    .iter()
+    .map(|func| SymbolEntry {
+      name: func.name.clone(),
+      addr: func.addr,
+      size: func.size.or_else(|| infer_size(&bytes, &image, cfg, func.addr)),
+    })
+    .collect::<Vec<_>>();
+  let imports = get_pe_import_fns(&bytes)?
+    .into_iter()
+    .map(|func| SymbolEntry {
+      name: func.name,
+      addr: func.addr,
+      size: func.size,
+    })
+    .collect::<Vec<_>>();
+
+  let module = info
+    .orig
+    .file_name()
+    .map_or_else(|| "Starcraft.exe".into(), |name| name.to_string_lossy());
+  let stem = info
+    .orig
+    .file_stem()
+    .map_or_else(|| "Starcraft".into(), |stem| stem.to_string_lossy());
+
+  let file_name = info.output.file_name(info.format.file_template(), &stem, "orig");
+  let mut writer: Box<dyn Write> = if info.output.is_stdout() {
+    Box::new(std::io::stdout())
+  } else {
+    info.output.open(info.format.file_template(), &stem, "orig")?
+  };
+  write_symbols(&mut writer, info.format, &functions, &imports, &image, &module)?;
+  writer.flush()?;
+
+  if !info.output.is_stdout() && !info.output.is_json() {
+    println!(
+      "Exported {} function(s) and {} import(s) to {}.",
+      functions.len(),
+      imports.len(),
+      info.output.dir_or("")?.join(file_name).display()
+    );
+  }
+
+  Ok(())
+}
+
+/// Adds every function of the original binary that is reachable through direct calls from the configured
+/// ones, but isn't configured itself, as `sub_<addr>` with its inferred size.
+fn discover(info: DiscoverInfo, cfg: &ComparerConfig, config_path: &Path) -> Result<(), ConfigCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+
+  // configured and discovered functions, so no call into the middle of one becomes a function of its own
+  let mut covered = cfg
+    .func
+    .iter()
+    .filter_map(|func| {
+      Some((
+        func.addr,
+        func.size.or_else(|| infer_size(&bytes, &image, cfg, func.addr))?,
+      ))
+    })
+    .collect::<Vec<_>>();
+  let is_covered = |covered: &[(u64, usize)], addr: u64| {
+    covered
+      .iter()
+      .any(|&(start, size)| addr >= start && addr < start + size as u64)
+  };
+
+  let mut pending = cfg
+    .func
+    .iter()
+    .map(|func| (func.addr, func.size, func.name.clone()))
+    .collect::<Vec<_>>();
+  let mut visited = cfg.func.iter().map(|func| func.addr).collect::<HashSet<_>>();
+  // sorted by address for the config file
+  let mut discovered = BTreeMap::<u64, (usize, String)>::new();
+
+  while let Some((addr, size, name)) = pending.pop() {
+    let flow = match size {
+      Some(size) => cfg
+        .orig_file_offset(&image, addr)
+        .and_then(|offset| bytes.get(offset as usize..offset as usize + size))
+        .and_then(|code| follow_function(code, addr)),
+      None => infer_function(&bytes, &image, cfg, addr),
+    };
+    let Some(flow) = flow else {
+      continue;
+    };
+
+    for target in flow.call_targets {
+      if !image.is_executable(target) || is_covered(&covered, target) || !visited.insert(target) {
+        continue;
+      }
+      let Some(size) = infer_size(&bytes, &image, cfg, target) else {
+        info.output.warn(format_args!(
+          "Could not infer the size of the function at {target:#X} called by '{name}', skipping it."
+        ))?;
+        continue;
+      };
+      covered.push((target, size));
+      discovered.insert(target, (size, name.clone()));
+      pending.push((target, Some(size), format!("sub_{target:X}")));
+    }
+  }
+
+  // functions discovered later may contain ones discovered before them
+  let inside = discovered
+    .keys()
+    .filter_map(|&addr| {
+      covered
+        .iter()
+        .find(|&&(start, size)| start < addr && addr < start + size as u64)
+        .map(|&(start, _)| (addr, start))
+    })
+    .collect::<Vec<_>>();
+  for (addr, start) in inside {
+    info.output.warn(format_args!(
+      "The function at {addr:#X} lies inside of the one at {start:#X}, skipping it."
+    ))?;
+    discovered.remove(&addr);
+  }
+
+  let symbols = discovered
+    .iter()
+    .map(|(&addr, (size, _))| SymbolEntry {
+      name: format!("sub_{addr:X}"),
+      addr,
+      size: Some(*size),
+    })
+    .collect::<Vec<_>>();
+
+  for (symbol, (_, caller)) in symbols.iter().zip(discovered.values()) {
+    if info.output.is_json() {
+      info.output.record_function(FunctionRecord {
+        symbol: symbol.name.clone(),
+        orig_addr: Some(symbol.addr),
+        orig_size: symbol.size,
+        ..Default::default()
+      })?;
+    } else {
+      println!(
+        "{}: {} (called by {})",
+        symbol.name,
+        symbol
+          .size
+          .map_or_else(|| String::from("-"), |size| format!("{size:#X}")),
+        caller
+      );
+    }
+  }
+
+  if !info.dry_run && !symbols.is_empty() {
+    let mut doc = read_config_document(config_path, cfg)?;
+    if doc.get("func").is_none() {
+      doc.insert("func", toml_edit::Item::ArrayOfTables(toml_edit::ArrayOfTables::new()));
+    }
+    let tables = doc["func"]
+      .as_array_of_tables_mut()
+      .expect("checked by read_config_document");
+    for symbol in &symbols {
+      tables.push(function_table(symbol));
+    }
+    std::fs::write(config_path, doc.to_string())?;
+  }
+  if !info.output.is_json() {
+    println!(
+      "{} {} function(s) called by configured ones.",
+      if info.dry_run { "Found" } else { "Added" },
+      symbols.len()
+    );
+  }
+
+  Ok(())
+}
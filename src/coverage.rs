@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use thiserror::Error;
+
+use super::comparer_config::*;
+use super::output::{OutputOpts, Record};
+use super::pe::PeImage;
+use super::sizes::{follow_function, infer_size, is_padding};
+
+#[derive(Debug)]
+pub struct CoverageCommandInfo {
+  pub orig: PathBuf,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug, Error)]
+pub enum CoverageCommandError {
+  #[error("IO error: {0}")]
+  Io(#[from] std::io::Error),
+
+  #[error("PE reading failed: {0}")]
+  PeImage(#[from] super::pe::PeError),
+}
+
+/// What fills the bytes of an executable section that no configured function covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+  Padding,
+  /// Jump tables and anything else that can't be followed as code.
+  Data,
+  /// A function missing from the config, starting at the beginning of the region.
+  Code,
+}
+
+impl Display for RegionKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      RegionKind::Padding => "padding",
+      RegionKind::Data => "data",
+      RegionKind::Code => "code",
+    })
+  }
+}
+
+struct Region {
+  kind: RegionKind,
+  addr: u64,
+  size: usize,
+}
+
+/// A function of the config, with its size inferred if missing.
+struct CoveredRange<'a> {
+  name: &'a str,
+  addr: u64,
+  size: usize,
+}
+
+/// The smallest number of consecutive addresses into executable code that are taken as a jump table.
+const MIN_JUMP_TABLE_ENTRIES: usize = 2;
+
+pub fn run(info: CoverageCommandInfo, cfg: &ComparerConfig) -> Result<(), CoverageCommandError> {
+  let bytes = std::fs::read(&info.orig)?;
+  let image = PeImage::parse(&bytes)?;
+
+  let mut covered = Vec::new();
+  for func in &cfg.func {
+    match func.size.or_else(|| infer_size(&bytes, &image, cfg, func.addr)) {
+      Some(size) => covered.push(CoveredRange {
+        name: &func.name,
+        addr: func.addr,
+        size,
+      }),
+      None => info.output.warn(format_args!(
+        "Could not infer the size of '{}', it doesn't count as covered.",
+        func.name
+      ))?,
+    }
+  }
+  covered.sort_by_key(|range| range.addr);
+
+  let mut regions = Vec::new();
+  let mut section_sizes = Vec::new();
+  for section in image.sections.iter().filter(|section| section.executable) {
+    let start = image.rva_to_va(section.virtual_address);
+    let Some(code) = cfg
+      .orig_file_offset(&image, start)
+      .and_then(|offset| bytes.get(offset as usize..(offset + section.raw_size) as usize))
+    else {
+      info.output.warn(format_args!(
+        "The section {} is outside of the original binary.",
+        section.name
+      ))?;
+      continue;
+    };
+    section_sizes.push((section.name.as_str(), code.len()));
+
+    let mut gap_start = start;
+    let end = start + code.len() as u64;
+    for range in &covered {
+      let range_end = range.addr + range.size as u64;
+      if range_end <= gap_start || range.addr >= end {
+        continue;
+      }
+      if range.addr > gap_start {
+        let gap = &code[(gap_start - start) as usize..(range.addr - start) as usize];
+        regions.extend(classify_gap(gap, gap_start, &image));
+      }
+      gap_start = gap_start.max(range_end);
+    }
+    if gap_start < end {
+      regions.extend(classify_gap(&code[(gap_start - start) as usize..], gap_start, &image));
+    }
+  }
+
+  let uncovered_calls = uncovered_call_targets(&bytes, &image, cfg, &covered, &regions);
+
+  if info.output.is_json() {
+    for region in &regions {
+      info.output.record(&Record::Region {
+        kind: region.kind,
+        addr: region.addr,
+        size: region.size,
+      })?;
+    }
+    for (addr, callers) in uncovered_calls {
+      info.output.record(&Record::CallTarget { addr, callers })?;
+    }
+    return Ok(());
+  }
+
+  let stdout = std::io::stdout();
+  let mut stdout_lock = stdout.lock();
+
+  writeln!(stdout_lock, "{:>10}  {:>8}  Kind", "Address", "Size")?;
+  for region in &regions {
+    let line = format!(
+      "{:>10}  {:>8}  {:<7}  {}",
+      format!("{:#X}", region.addr),
+      format!("{:#X}", region.size),
+      region.kind.to_string(),
+      match region.kind {
+        RegionKind::Code => format!("sub_{:X}", region.addr),
+        _ => String::new(),
+      }
+    );
+    writeln!(stdout_lock, "{}", line.trim_end())?;
+  }
+
+  let total = section_sizes.iter().map(|(_, size)| size).sum::<usize>();
+  let mut sizes = BTreeMap::new();
+  for region in &regions {
+    *sizes.entry(region.kind).or_insert(0) += region.size;
+  }
+  let uncovered = sizes.values().sum::<usize>();
+
+  writeln!(stdout_lock)?;
+  writeln!(
+    stdout_lock,
+    "Executable sections: {}",
+    section_sizes
+      .iter()
+      .map(|(name, size)| format!("{name} ({size:#X} bytes)"))
+      .collect::<Vec<_>>()
+      .join(", ")
+  )?;
+  let percentage = |size: usize| size as f32 * 100.0 / total.max(1) as f32;
+  writeln!(
+    stdout_lock,
+    "{:<9}  {:>8}  {:>6.2} %",
+    "functions",
+    format!("{:#X}", total - uncovered),
+    percentage(total - uncovered)
+  )?;
+  for (kind, size) in sizes {
+    writeln!(
+      stdout_lock,
+      "{:<9}  {:>8}  {:>6.2} %",
+      kind.to_string(),
+      format!("{size:#X}"),
+      percentage(size)
+    )?;
+  }
+
+  if !uncovered_calls.is_empty() {
+    writeln!(stdout_lock)?;
+    writeln!(stdout_lock, "Call targets without a configured function:")?;
+    for (addr, callers) in uncovered_calls {
+      writeln!(
+        stdout_lock,
+        "{:>10}  called by {}",
+        format!("{addr:#X}"),
+        callers.join(", ")
+      )?;
+    }
+  }
+
+  Ok(())
+}
+
+/// Splits the bytes between configured functions into padding, jump tables and other data, and
+/// functions found by following their code.
+fn classify_gap(code: &[u8], addr: u64, image: &PeImage) -> Vec<Region> {
+  let mut regions = Vec::<Region>::new();
+  let mut pos = 0;
+
+  // the compiler aligns jump tables, whose entries may well start with a padding byte
+  let jump_table_at = |pos: usize| {
+    (addr + pos as u64)
+      .is_multiple_of(4)
+      .then(|| jump_table_size(&code[pos..], image))
+      .flatten()
+  };
+
+  while pos < code.len() {
+    let (kind, size) = if let Some(size) = jump_table_at(pos) {
+      (RegionKind::Data, size)
+    } else if is_padding_or_zero(&code[pos]) {
+      let end = (pos + 1..code.len())
+        .find(|&end| !is_padding_or_zero(&code[end]) || jump_table_at(end).is_some())
+        .unwrap_or(code.len());
+      (RegionKind::Padding, end - pos)
+    } else if let Some(function) = follow_function(&code[pos..], addr + pos as u64) {
+      (RegionKind::Code, function.size)
+    } else {
+      let size = code[pos..].iter().take_while(|byte| !is_padding_or_zero(byte)).count();
+      (RegionKind::Data, size)
+    };
+
+    // code regions stay separate, each being a function of its own
+    match regions.last_mut() {
+      Some(last) if last.kind == kind && kind != RegionKind::Code => last.size += size,
+      _ => regions.push(Region {
+        kind,
+        addr: addr + pos as u64,
+        size,
+      }),
+    }
+    pos += size;
+  }
+
+  regions
+}
+
+/// Zero bytes end up between functions as well, e.g. after the last one of a section.
+fn is_padding_or_zero(byte: &u8) -> bool {
+  is_padding(byte) || *byte == 0
+}
+
+/// The size of a table of code addresses at the start of `code`, as generated for `switch` statements.
+/// Sparse cases add a table of byte indices into it right after.
+fn jump_table_size(code: &[u8], image: &PeImage) -> Option<usize> {
+  let entries = code
+    .chunks_exact(4)
+    .take_while(|entry| image.is_executable(u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64))
+    .count();
+  if entries < MIN_JUMP_TABLE_ENTRIES {
+    return None;
+  }
+
+  let indices = code[entries * 4..]
+    .iter()
+    .take_while(|&&index| (index as usize) < entries)
+    .count();
+  Some(entries * 4 + indices)
+}
+
+/// Direct call targets in executable sections that aren't inside any configured function, with the names
+/// of their callers. Calls from code found in the gaps count as well.
+fn uncovered_call_targets(
+  bytes: &[u8],
+  image: &PeImage,
+  cfg: &ComparerConfig,
+  covered: &[CoveredRange],
+  regions: &[Region],
+) -> BTreeMap<u64, Vec<String>> {
+  let suggested = regions
+    .iter()
+    .filter(|region| region.kind == RegionKind::Code)
+    .map(|region| (format!("sub_{:X}", region.addr), region.addr, region.size));
+  let functions = covered
+    .iter()
+    .map(|range| (range.name.to_string(), range.addr, range.size))
+    .chain(suggested);
+
+  let mut targets = BTreeMap::<u64, Vec<String>>::new();
+  for (name, addr, size) in functions {
+    let Some(code) = cfg
+      .orig_file_offset(image, addr)
+      .and_then(|offset| bytes.get(offset as usize..offset as usize + size))
+    else {
+      continue;
+    };
+    let Some(function) = follow_function(code, addr) else {
+      continue;
+    };
+
+    for target in function.call_targets {
+      let is_covered = covered
+        .iter()
+        .any(|range| target >= range.addr && target < range.addr + range.size as u64);
+      if !is_covered && image.is_executable(target) {
+        let callers = targets.entry(target).or_default();
+        if !callers.contains(&name) {
+          callers.push(name.clone());
+        }
+      }
+    }
+  }
+
+  targets
+}
@@ -0,0 +1,762 @@
+//! Undecoration of MSVC symbol names, covering the subset of the name mangling scheme
+//! used by 32-bit Visual C++ builds: `?foo@Bar@@QAEXH@Z`, `_foo@8`, `@foo@4` and `_foo`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DemangledName {
+  /// Fully qualified name without any type information, e.g. `Bar::foo`.
+  pub name: String,
+  /// The full undecorated signature, e.g. `public: void __thiscall Bar::foo(int)`.
+  pub signature: String,
+}
+
+/// Undecorates a mangled symbol name. Returns `None` if the name is not decorated or uses
+/// parts of the mangling scheme that are not supported.
+pub fn demangle(symbol: &str) -> Option<DemangledName> {
+  if symbol.starts_with('?') {
+    return Parser::new(symbol).parse_symbol();
+  }
+
+  // C names: `@foo@4` (__fastcall), `_foo@8` (__stdcall) and `_foo` (__cdecl)
+  let (stripped, has_size) = if let Some(rest) = symbol.strip_prefix('@') {
+    (rest, true)
+  } else if let Some(rest) = symbol.strip_prefix('_') {
+    (rest, rest.contains('@'))
+  } else {
+    return None;
+  };
+
+  let name = if has_size {
+    let (name, size) = stripped.rsplit_once('@')?;
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+    name
+  } else {
+    stripped
+  };
+
+  if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+    return None;
+  }
+
+  Some(DemangledName {
+    name: name.to_string(),
+    signature: name.to_string(),
+  })
+}
+
+/// Strips the argument list and trailing qualifiers from an undecorated function name,
+/// e.g. `Bar::foo(int, char*) const` becomes `Bar::foo`.
+pub fn strip_arguments(name: &str) -> &str {
+  let mut trimmed = name.trim_end();
+  while let Some(rest) = trimmed
+    .strip_suffix("const")
+    .or_else(|| trimmed.strip_suffix("volatile"))
+  {
+    trimmed = rest.trim_end();
+  }
+
+  if !trimmed.ends_with(')') {
+    return name;
+  }
+
+  let mut depth = 0;
+  for (i, c) in trimmed.char_indices().rev() {
+    match c {
+      ')' => depth += 1,
+      '(' => {
+        depth -= 1;
+        if depth == 0 {
+          return &trimmed[..i];
+        }
+      }
+      _ => {}
+    }
+  }
+
+  name
+}
+
+struct Function {
+  access: &'static str,
+  storage: &'static str,
+  is_member: bool,
+  is_thunk: bool,
+}
+
+struct Parser<'a> {
+  input: &'a [u8],
+  pos: usize,
+  names: Vec<String>,
+  types: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+  fn new(input: &'a str) -> Self {
+    Parser {
+      input: input.as_bytes(),
+      pos: 0,
+      names: Vec::new(),
+      types: Vec::new(),
+    }
+  }
+
+  fn peek(&self) -> Option<u8> {
+    self.input.get(self.pos).copied()
+  }
+
+  fn next(&mut self) -> Option<u8> {
+    let c = self.peek()?;
+    self.pos += 1;
+    Some(c)
+  }
+
+  fn consume(&mut self, prefix: &str) -> bool {
+    if self.input[self.pos..].starts_with(prefix.as_bytes()) {
+      self.pos += prefix.len();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, c: u8) -> Option<()> {
+    (self.next()? == c).then_some(())
+  }
+
+  fn parse_symbol(mut self) -> Option<DemangledName> {
+    self.expect(b'?')?;
+
+    let (first, special) = self.parse_first_name()?;
+    let scopes = self.parse_scopes()?;
+    let name = match special {
+      SpecialName::Constructor => qualify(&scopes, scopes.first()?),
+      SpecialName::Destructor => qualify(&scopes, &format!("~{}", scopes.first()?)),
+      SpecialName::Other => qualify(&scopes, &first),
+    };
+
+    let signature = match self.next()? {
+      c @ b'0'..=b'4' => {
+        let access = match c {
+          b'0' => "private: static ",
+          b'1' => "protected: static ",
+          b'2' => "public: static ",
+          _ => "",
+        };
+        let ty = self.parse_type()?;
+        let cv = self.parse_cv()?;
+        format!("{access}{}{cv} {name}", ty)
+      }
+      b'6' | b'7' => format!("const {name}"),
+      b'Y' | b'Z' => self.parse_function(&name, Function::global(), special)?,
+      c @ b'A'..=b'X' => self.parse_function(&name, Function::member(c), special)?,
+      _ => return None,
+    };
+
+    Some(DemangledName { name, signature })
+  }
+
+  fn parse_function(&mut self, name: &str, function: Function, special: SpecialName) -> Option<String> {
+    if function.is_thunk {
+      let adjustment = self.parse_number()?;
+      return Some(format!(
+        "{}{}[thunk]:{name}`adjustor{{{adjustment}}}'",
+        function.access, function.storage
+      ));
+    }
+
+    let this_cv = if function.is_member {
+      self.parse_cv()?
+    } else {
+      String::new()
+    };
+    let calling_convention = self.parse_calling_convention()?;
+
+    let return_type = if self.consume("@") {
+      String::new()
+    } else {
+      let ty = self.parse_return_type()?;
+      format!("{ty} ")
+    };
+    let params = self.parse_params()?;
+
+    let name = match special {
+      SpecialName::Other if name.ends_with("::operator") || name == "operator" => {
+        format!("{name} {}", return_type.trim_end())
+      }
+      _ => name.to_string(),
+    };
+
+    Some(format!(
+      "{}{}{return_type}{calling_convention} {name}({params}){this_cv}",
+      function.access, function.storage
+    ))
+  }
+
+  fn parse_first_name(&mut self) -> Option<(String, SpecialName)> {
+    if self.peek()? == b'?' && self.input.get(self.pos + 1) != Some(&b'$') {
+      self.pos += 1;
+      let special = match self.peek()? {
+        b'0' => SpecialName::Constructor,
+        b'1' => SpecialName::Destructor,
+        _ => SpecialName::Other,
+      };
+      let operator = self.parse_operator_name()?;
+      return Some((operator, special));
+    }
+
+    Some((self.parse_unqualified_name()?, SpecialName::Other))
+  }
+
+  fn parse_operator_name(&mut self) -> Option<String> {
+    let c = self.next()?;
+    let name = if c == b'_' {
+      match self.next()? {
+        b'0' => "operator/=",
+        b'1' => "operator%=",
+        b'2' => "operator>>=",
+        b'3' => "operator<<=",
+        b'4' => "operator&=",
+        b'5' => "operator|=",
+        b'6' => "operator^=",
+        b'7' => "`vftable'",
+        b'8' => "`vbtable'",
+        b'9' => "`vcall'",
+        b'A' => "`typeof'",
+        b'B' => "`local static guard'",
+        b'D' => "`vbase destructor'",
+        b'E' => "`vector deleting destructor'",
+        b'F' => "`default constructor closure'",
+        b'G' => "`scalar deleting destructor'",
+        b'H' => "`vector constructor iterator'",
+        b'I' => "`vector destructor iterator'",
+        b'L' => "`eh vector constructor iterator'",
+        b'M' => "`eh vector destructor iterator'",
+        b'U' => "operator new[]",
+        b'V' => "operator delete[]",
+        _ => return None,
+      }
+    } else {
+      match c {
+        b'0' | b'1' => "",
+        b'2' => "operator new",
+        b'3' => "operator delete",
+        b'4' => "operator=",
+        b'5' => "operator>>",
+        b'6' => "operator<<",
+        b'7' => "operator!",
+        b'8' => "operator==",
+        b'9' => "operator!=",
+        b'A' => "operator[]",
+        b'B' => "operator",
+        b'C' => "operator->",
+        b'D' => "operator*",
+        b'E' => "operator++",
+        b'F' => "operator--",
+        b'G' => "operator-",
+        b'H' => "operator+",
+        b'I' => "operator&",
+        b'J' => "operator->*",
+        b'K' => "operator/",
+        b'L' => "operator%",
+        b'M' => "operator<",
+        b'N' => "operator<=",
+        b'O' => "operator>",
+        b'P' => "operator>=",
+        b'Q' => "operator,",
+        b'R' => "operator()",
+        b'S' => "operator~",
+        b'T' => "operator^",
+        b'U' => "operator|",
+        b'V' => "operator&&",
+        b'W' => "operator||",
+        b'X' => "operator*=",
+        b'Y' => "operator+=",
+        b'Z' => "operator-=",
+        _ => return None,
+      }
+    };
+    Some(name.to_string())
+  }
+
+  /// Parses a single name fragment, either a plain name, a template or a back reference.
+  fn parse_unqualified_name(&mut self) -> Option<String> {
+    let c = self.peek()?;
+    if c.is_ascii_digit() {
+      self.pos += 1;
+      return self.names.get((c - b'0') as usize).cloned();
+    }
+
+    if self.consume("?$") {
+      // template arguments use their own back reference tables
+      let names = std::mem::take(&mut self.names);
+      let types = std::mem::take(&mut self.types);
+      let result = self.parse_template();
+      self.names = names;
+      self.types = types;
+
+      let template = result?;
+      self.remember_name(&template);
+      return Some(template);
+    }
+
+    let name = self.parse_simple_name()?;
+    self.remember_name(&name);
+    Some(name)
+  }
+
+  fn parse_template(&mut self) -> Option<String> {
+    let name = if self.peek()? == b'?' {
+      self.pos += 1;
+      self.parse_operator_name()?
+    } else {
+      let name = self.parse_simple_name()?;
+      self.remember_name(&name);
+      name
+    };
+
+    let mut args = Vec::new();
+    while !self.consume("@") {
+      args.push(self.parse_template_arg()?);
+    }
+
+    let args = args.join(",");
+    let close = if args.ends_with('>') { " >" } else { ">" };
+    Some(format!("{name}<{args}{close}"))
+  }
+
+  fn parse_template_arg(&mut self) -> Option<String> {
+    if self.consume("$0") {
+      return self.parse_number().map(|n| n.to_string());
+    }
+    if self.consume("$1") {
+      let symbol = self.parse_embedded_symbol()?;
+      return Some(format!("&{symbol}"));
+    }
+    if self.consume("$$T") {
+      return Some(String::from("std::nullptr_t"));
+    }
+    if self.consume("$$V") || self.consume("$$Z") {
+      return Some(String::new());
+    }
+    self.parse_param_type()
+  }
+
+  fn parse_embedded_symbol(&mut self) -> Option<String> {
+    self.expect(b'?')?;
+    let (first, _) = self.parse_first_name()?;
+    let scopes = self.parse_scopes()?;
+    let name = qualify(&scopes, &first);
+    // skip the type encoding of the referenced symbol, it isn't part of the displayed name
+    match self.next()? {
+      b'0'..=b'4' => {
+        self.parse_type()?;
+        self.parse_cv()?;
+      }
+      b'Y' | b'Z' => {
+        self.parse_calling_convention()?;
+        if !self.consume("@") {
+          self.parse_return_type()?;
+        }
+        self.parse_params()?;
+      }
+      _ => return None,
+    }
+    Some(name)
+  }
+
+  fn parse_simple_name(&mut self) -> Option<String> {
+    let start = self.pos;
+    while self.peek()? != b'@' {
+      self.pos += 1;
+    }
+    let name = std::str::from_utf8(&self.input[start..self.pos]).ok()?.to_string();
+    self.pos += 1;
+    (!name.is_empty()).then_some(name)
+  }
+
+  fn remember_name(&mut self, name: &str) {
+    if self.names.len() < 10 && !self.names.iter().any(|n| n == name) {
+      self.names.push(name.to_string());
+    }
+  }
+
+  /// Parses the enclosing scopes of a name up to the terminating `@`, innermost first.
+  fn parse_scopes(&mut self) -> Option<Vec<String>> {
+    let mut scopes = Vec::new();
+    while !self.consume("@") {
+      if self.consume("?A") {
+        // anonymous namespace, `?A0x1234abcd@`
+        self.parse_simple_name()?;
+        scopes.push(String::from("`anonymous namespace'"));
+      } else if self.peek()? == b'?' && self.input.get(self.pos + 1) != Some(&b'$') {
+        // nested function scopes (`?1??foo@@...`) are not supported
+        return None;
+      } else {
+        scopes.push(self.parse_unqualified_name()?);
+      }
+    }
+    Some(scopes)
+  }
+
+  fn parse_qualified_name(&mut self) -> Option<String> {
+    let first = self.parse_unqualified_name()?;
+    let scopes = self.parse_scopes()?;
+    Some(qualify(&scopes, &first))
+  }
+
+  fn parse_number(&mut self) -> Option<i64> {
+    let negative = self.consume("?");
+    let c = self.next()?;
+    let value = if c.is_ascii_digit() {
+      (c - b'0') as i64 + 1
+    } else {
+      let mut value = 0i64;
+      let mut c = c;
+      while c != b'@' {
+        if !(b'A'..=b'P').contains(&c) {
+          return None;
+        }
+        value = value.checked_mul(16)?.checked_add((c - b'A') as i64)?;
+        c = self.next()?;
+      }
+      value
+    };
+    Some(if negative { -value } else { value })
+  }
+
+  fn parse_cv(&mut self) -> Option<String> {
+    // skip `__ptr64`
+    self.consume("E");
+    let cv = match self.next()? {
+      b'A' => "",
+      b'B' => " const",
+      b'C' => " volatile",
+      b'D' => " const volatile",
+      _ => return None,
+    };
+    Some(cv.to_string())
+  }
+
+  fn parse_calling_convention(&mut self) -> Option<&'static str> {
+    let convention = match self.next()? {
+      b'A' | b'B' => "__cdecl",
+      b'C' | b'D' => "__pascal",
+      b'E' | b'F' => "__thiscall",
+      b'G' | b'H' => "__stdcall",
+      b'I' | b'J' => "__fastcall",
+      b'Q' => "__vectorcall",
+      _ => return None,
+    };
+    Some(convention)
+  }
+
+  fn parse_return_type(&mut self) -> Option<String> {
+    if self.consume("?") {
+      let cv = self.parse_cv()?;
+      let ty = self.parse_type()?;
+      return Some(format!("{ty}{cv}"));
+    }
+    self.parse_type()
+  }
+
+  fn parse_params(&mut self) -> Option<String> {
+    if self.consume("X") {
+      return Some(String::from("void"));
+    }
+
+    let mut params = Vec::new();
+    loop {
+      if self.consume("@") {
+        break;
+      }
+      if self.consume("Z") {
+        params.push(String::from("..."));
+        break;
+      }
+      params.push(self.parse_param_type()?);
+    }
+    Some(params.join(","))
+  }
+
+  /// Parses a type in a parameter list, which takes part in type back references.
+  fn parse_param_type(&mut self) -> Option<String> {
+    let c = self.peek()?;
+    if c.is_ascii_digit() {
+      self.pos += 1;
+      return self.types.get((c - b'0') as usize).cloned();
+    }
+
+    let start = self.pos;
+    let ty = self.parse_type()?;
+    if self.pos - start > 1 && self.types.len() < 10 {
+      self.types.push(ty.clone());
+    }
+    Some(ty)
+  }
+
+  fn parse_type(&mut self) -> Option<String> {
+    let ty = match self.next()? {
+      b'C' => "signed char",
+      b'D' => "char",
+      b'E' => "unsigned char",
+      b'F' => "short",
+      b'G' => "unsigned short",
+      b'H' => "int",
+      b'I' => "unsigned int",
+      b'J' => "long",
+      b'K' => "unsigned long",
+      b'M' => "float",
+      b'N' => "double",
+      b'O' => "long double",
+      b'X' => "void",
+      b'_' => match self.next()? {
+        b'D' => "__int8",
+        b'E' => "unsigned __int8",
+        b'F' => "__int16",
+        b'G' => "unsigned __int16",
+        b'H' => "__int32",
+        b'I' => "unsigned __int32",
+        b'J' => "__int64",
+        b'K' => "unsigned __int64",
+        b'N' => "bool",
+        b'S' => "char16_t",
+        b'U' => "char32_t",
+        b'W' => "wchar_t",
+        _ => return None,
+      },
+      b'T' => return Some(format!("union {}", self.parse_qualified_name()?)),
+      b'U' => return Some(format!("struct {}", self.parse_qualified_name()?)),
+      b'V' => return Some(format!("class {}", self.parse_qualified_name()?)),
+      b'W' => {
+        // the enum's underlying type, `4` being int
+        self.next()?;
+        return Some(format!("enum {}", self.parse_qualified_name()?));
+      }
+      b'P' => return self.parse_pointer("*", ""),
+      b'Q' => return self.parse_pointer("*", " const"),
+      b'R' => return self.parse_pointer("*", " volatile"),
+      b'S' => return self.parse_pointer("*", " const volatile"),
+      b'A' => return self.parse_pointer("&", ""),
+      b'B' => return self.parse_pointer("&", " volatile"),
+      b'?' => {
+        let cv = self.parse_cv()?;
+        let ty = self.parse_type()?;
+        return Some(format!("{ty}{cv}"));
+      }
+      b'$' => {
+        if self.consume("$Q") {
+          return self.parse_pointer("&&", "");
+        }
+        if self.consume("$C") {
+          let cv = self.parse_cv()?;
+          let ty = self.parse_type()?;
+          return Some(format!("{ty}{cv}"));
+        }
+        if self.consume("$T") {
+          return Some(String::from("std::nullptr_t"));
+        }
+        return None;
+      }
+      b'Y' => {
+        let dimensions = self.parse_number()?;
+        let sizes = (0..dimensions)
+          .map(|_| self.parse_number().map(|n| format!("[{n}]")))
+          .collect::<Option<String>>()?;
+        let ty = self.parse_type()?;
+        return Some(format!("{ty}{sizes}"));
+      }
+      _ => return None,
+    };
+    Some(ty.to_string())
+  }
+
+  fn parse_pointer(&mut self, sigil: &str, pointer_cv: &str) -> Option<String> {
+    self.consume("E");
+
+    if self.consume("6") {
+      let calling_convention = self.parse_calling_convention()?;
+      let return_type = self.parse_return_type()?;
+      let params = self.parse_params()?;
+      self.consume("Z");
+      return Some(format!(
+        "{return_type} ({calling_convention} {sigil}{pointer_cv})({params})"
+      ));
+    }
+
+    let cv = self.parse_cv()?;
+    let pointee = self.parse_type()?;
+    Some(format!("{pointee}{cv} {sigil}{pointer_cv}"))
+  }
+}
+
+#[derive(Clone, Copy)]
+enum SpecialName {
+  Constructor,
+  Destructor,
+  Other,
+}
+
+impl Function {
+  fn global() -> Self {
+    Function {
+      access: "",
+      storage: "",
+      is_member: false,
+      is_thunk: false,
+    }
+  }
+
+  fn member(code: u8) -> Self {
+    let index = code - b'A';
+    let access = match index / 8 {
+      0 => "private: ",
+      1 => "protected: ",
+      _ => "public: ",
+    };
+    let (storage, is_member, is_thunk) = match (index % 8) / 2 {
+      0 => ("", true, false),
+      1 => ("static ", false, false),
+      2 => ("virtual ", true, false),
+      _ => ("virtual ", true, true),
+    };
+    Function {
+      access,
+      storage,
+      is_member,
+      is_thunk,
+    }
+  }
+}
+
+fn qualify(scopes: &[String], name: &str) -> String {
+  scopes
+    .iter()
+    .rev()
+    .map(String::as_str)
+    .chain(std::iter::once(name))
+    .collect::<Vec<_>>()
+    .join("::")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn signature(symbol: &str) -> String {
+    demangle(symbol).expect("failed to demangle").signature
+  }
+
+  fn name(symbol: &str) -> String {
+    demangle(symbol).expect("failed to demangle").name
+  }
+
+  #[test]
+  fn c_names() {
+    assert_eq!(name("_main"), "main");
+    assert_eq!(name("_WinMain@16"), "WinMain");
+    assert_eq!(name("@fastcall_fn@8"), "fastcall_fn");
+    assert_eq!(demangle("main"), None);
+    assert_eq!(demangle("_foo@bar"), None);
+  }
+
+  #[test]
+  fn free_functions() {
+    assert_eq!(signature("?foo@@YAXXZ"), "void __cdecl foo(void)");
+    assert_eq!(signature("?foo@@YGHH@Z"), "int __stdcall foo(int)");
+    assert_eq!(signature("?foo@@YIXPAD@Z"), "void __fastcall foo(char *)");
+    assert_eq!(name("?foo@ns@@YAXXZ"), "ns::foo");
+  }
+
+  #[test]
+  fn thiscall_members() {
+    assert_eq!(signature("?foo@Bar@@QAEXH@Z"), "public: void __thiscall Bar::foo(int)");
+    assert_eq!(
+      signature("?get@Bar@@QBEHXZ"),
+      "public: int __thiscall Bar::get(void) const"
+    );
+    assert_eq!(
+      signature("?update@Unit@@UAEXXZ"),
+      "public: virtual void __thiscall Unit::update(void)"
+    );
+    assert_eq!(
+      signature("?create@Unit@@SAPAV1@H@Z"),
+      "public: static class Unit * __cdecl Unit::create(int)"
+    );
+    assert_eq!(name("?create@Unit@@SAPAV1@H@Z"), "Unit::create");
+  }
+
+  #[test]
+  fn back_references() {
+    assert_eq!(
+      signature("?copy@@YAXPAUPoint@@0@Z"),
+      "void __cdecl copy(struct Point *,struct Point *)"
+    );
+    assert_eq!(
+      signature("?move@Unit@@QAEXABUPoint@@0@Z"),
+      "public: void __thiscall Unit::move(struct Point const &,struct Point const &)"
+    );
+  }
+
+  #[test]
+  fn special_functions() {
+    assert_eq!(signature("??0Bar@@QAE@XZ"), "public: __thiscall Bar::Bar(void)");
+    assert_eq!(
+      signature("??1Bar@@UAE@XZ"),
+      "public: virtual __thiscall Bar::~Bar(void)"
+    );
+    assert_eq!(name("??0Bar@@QAE@XZ"), "Bar::Bar");
+    assert_eq!(name("??1Bar@@UAE@XZ"), "Bar::~Bar");
+  }
+
+  #[test]
+  fn operators() {
+    assert_eq!(
+      signature("??4Bar@@QAEAAV0@ABV0@@Z"),
+      "public: class Bar & __thiscall Bar::operator=(class Bar const &)"
+    );
+    assert_eq!(
+      signature("??8Point@@QBE_NABU0@@Z"),
+      "public: bool __thiscall Point::operator==(struct Point const &) const"
+    );
+    assert_eq!(name("??2@YAPAXI@Z"), "operator new");
+  }
+
+  #[test]
+  fn templates() {
+    assert_eq!(
+      signature("?push@?$Stack@H@@QAEXH@Z"),
+      "public: void __thiscall Stack<int>::push(int)"
+    );
+    assert_eq!(name("?push@?$Stack@H@@QAEXH@Z"), "Stack<int>::push");
+    assert_eq!(
+      name("?size@?$Array@PAUUnit@@$0BA@@@QBEIXZ"),
+      "Array<struct Unit *,16>::size"
+    );
+  }
+
+  #[test]
+  fn data() {
+    assert_eq!(signature("?g_count@@3HA"), "int g_count");
+    assert_eq!(
+      signature("?s_instance@Game@@2PAV1@A"),
+      "public: static class Game * Game::s_instance"
+    );
+    assert_eq!(name("?s_instance@Game@@2PAV1@A"), "Game::s_instance");
+  }
+
+  #[test]
+  fn numbers() {
+    let mut parser = Parser::new("3BA@?0PPPPPPPPPPPPPPPPP@");
+    assert_eq!(parser.parse_number(), Some(4));
+    assert_eq!(parser.parse_number(), Some(16));
+    assert_eq!(parser.parse_number(), Some(-1));
+    assert_eq!(parser.parse_number(), None);
+  }
+
+  #[test]
+  fn strip() {
+    assert_eq!(strip_arguments("Bar::foo(int, char*) const"), "Bar::foo");
+    assert_eq!(strip_arguments("Bar::foo"), "Bar::foo");
+  }
+}
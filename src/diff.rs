@@ -0,0 +1,198 @@
+use std::io::{Result as IoResult, Write};
+
+use super::disasm::DisasmLine;
+use super::insn_diff::{Change, InstructionDiff, Span};
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD_RED: &str = "\x1b[1;31m";
+const BOLD_GREEN: &str = "\x1b[1;32m";
+const RESET: &str = "\x1b[0m";
+
+const MAX_COLUMN_WIDTH: usize = 60;
+const CONTEXT_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStyle {
+  Unified,
+  SideBySide,
+}
+
+/// Writes a terminal diff between the original and the new disassembly, followed by their match ratio
+/// and how the aligned instructions differ. Only the differing tokens of those are highlighted.
+pub fn write_diff(
+  writer: &mut impl Write,
+  diff: &InstructionDiff,
+  style: DiffStyle,
+  print_addresses: bool,
+  color: bool,
+) -> IoResult<()> {
+  let paint = |code: &'static str| if color { code } else { "" };
+  let reset = paint(RESET);
+  let line_text = |line: &DisasmLine| {
+    if print_addresses {
+      format!("{:X}: {}", line.ip, line.text)
+    } else {
+      line.text.clone()
+    }
+  };
+  let line_spans = |line: &DisasmLine, spans: &[Span]| {
+    let mut spans = spans.to_vec();
+    if print_addresses {
+      spans.insert(
+        0,
+        Span {
+          text: format!("{:X}: ", line.ip),
+          changed: false,
+        },
+      );
+    }
+    spans
+  };
+  let paint_spans = |spans: &[Span], base: &'static str, highlight: &'static str, width: Option<usize>| {
+    let mut text = String::new();
+    let mut remaining = width.unwrap_or(usize::MAX);
+    for span in spans {
+      let part = truncate(&span.text, remaining);
+      remaining -= part.chars().count();
+      let code = if span.changed { highlight } else { base };
+      text.push_str(&format!("{}{part}{reset}", paint(code)));
+    }
+    let padding = width.map_or(0, |_| remaining);
+    (text, padding)
+  };
+
+  match style {
+    DiffStyle::Unified => {
+      for hunk in hunks(&diff.changes) {
+        let (old_start, new_start) = line_numbers(&diff.changes[..hunk.start]);
+        let (old_len, new_len) = line_numbers(&diff.changes[hunk.clone()]);
+        writeln!(
+          writer,
+          "{}@@ -{},{old_len} +{},{new_len} @@{reset}",
+          paint(CYAN),
+          old_start + 1,
+          new_start + 1
+        )?;
+
+        for change in &diff.changes[hunk] {
+          match change {
+            Change::Equal(line, _) => writeln!(writer, " {}", line_text(line))?,
+            Change::Delete(line) => writeln!(writer, "{}-{}{reset}", paint(RED), line_text(line))?,
+            Change::Insert(line) => writeln!(writer, "{}+{}{reset}", paint(GREEN), line_text(line))?,
+            Change::Replace {
+              orig,
+              compare,
+              orig_spans,
+              compare_spans,
+              kinds,
+            } => {
+              let (orig_text, _) = paint_spans(&line_spans(orig, orig_spans), RED, BOLD_RED, None);
+              let (compare_text, _) = paint_spans(&line_spans(compare, compare_spans), GREEN, BOLD_GREEN, None);
+              writeln!(writer, "{}-{reset}{orig_text}", paint(RED))?;
+              writeln!(
+                writer,
+                "{}+{reset}{compare_text}  {}; {}{reset}",
+                paint(GREEN),
+                paint(CYAN),
+                kinds.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+              )?;
+            }
+          }
+        }
+      }
+    }
+    DiffStyle::SideBySide => {
+      let width = diff
+        .changes
+        .iter()
+        .filter_map(|change| match change {
+          Change::Equal(line, _) | Change::Delete(line) => Some(line_text(line).chars().count()),
+          Change::Replace { orig, .. } => Some(line_text(orig).chars().count()),
+          Change::Insert(_) => None,
+        })
+        .max()
+        .unwrap_or(0)
+        .min(MAX_COLUMN_WIDTH);
+
+      for change in &diff.changes {
+        let plain = |line: Option<&DisasmLine>| {
+          let text = line.map(&line_text).unwrap_or_default();
+          vec![Span { text, changed: false }]
+        };
+        let (marker, old_text, old_padding, new_text) = match change {
+          Change::Equal(orig, compare) => {
+            let (old_text, padding) = paint_spans(&plain(Some(orig)), "", "", Some(width));
+            (' ', old_text, padding, line_text(compare))
+          }
+          Change::Delete(line) => {
+            let (old_text, padding) = paint_spans(&plain(Some(line)), RED, RED, Some(width));
+            ('<', old_text, padding, String::new())
+          }
+          Change::Insert(line) => {
+            let (old_text, padding) = paint_spans(&plain(None), "", "", Some(width));
+            let (new_text, _) = paint_spans(&plain(Some(line)), GREEN, GREEN, None);
+            ('>', old_text, padding, new_text)
+          }
+          Change::Replace {
+            orig,
+            compare,
+            orig_spans,
+            compare_spans,
+            ..
+          } => {
+            let (old_text, padding) = paint_spans(&line_spans(orig, orig_spans), RED, BOLD_RED, Some(width));
+            let (new_text, _) = paint_spans(&line_spans(compare, compare_spans), GREEN, BOLD_GREEN, None);
+            ('|', old_text, padding, new_text)
+          }
+        };
+        writeln!(writer, "{old_text}{:old_padding$} {marker} {new_text}", "")?;
+      }
+    }
+  }
+
+  writeln!(writer, "Match ratio: {:.2} %", diff.ratio * 100.0)?;
+  let counts = diff.counts();
+  if !counts.is_empty() {
+    let counts = counts
+      .iter()
+      .map(|(kind, count)| format!("{count} {kind}"))
+      .collect::<Vec<_>>();
+    writeln!(writer, "Differences: {}", counts.join(", "))?;
+  }
+  Ok(())
+}
+
+/// The ranges of changes to print, with some equal instructions around the differing ones.
+fn hunks(changes: &[Change]) -> Vec<std::ops::Range<usize>> {
+  let mut hunks = Vec::<std::ops::Range<usize>>::new();
+  for (index, change) in changes.iter().enumerate() {
+    if matches!(change, Change::Equal(..)) {
+      continue;
+    }
+    let start = index.saturating_sub(CONTEXT_LINES);
+    let end = (index + 1 + CONTEXT_LINES).min(changes.len());
+    match hunks.last_mut() {
+      Some(last) if last.end >= start => last.end = end,
+      _ => hunks.push(start..end),
+    }
+  }
+  hunks
+}
+
+/// The number of original and new instructions in the changes.
+fn line_numbers(changes: &[Change]) -> (usize, usize) {
+  changes.iter().fold((0, 0), |(old, new), change| match change {
+    Change::Equal(..) | Change::Replace { .. } => (old + 1, new + 1),
+    Change::Delete(_) => (old + 1, new),
+    Change::Insert(_) => (old, new + 1),
+  })
+}
+
+fn truncate(line: &str, width: usize) -> &str {
+  match line.char_indices().nth(width) {
+    Some((end, _)) => &line[..end],
+    None => line,
+  }
+}
@@ -1,12 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::io::{Error as IoError, Write};
 
+use similar::{Algorithm, DiffOp, capture_diff_slices};
 use thiserror::Error;
-use zydis::ffi::{DecodedOperandKind, FormatterBuffer, FormatterContext, ImmediateInfo};
-use zydis::{Decoder, Formatter, FormatterStyle, OutputBuffer, Result as ZydisResult, Status, VisibleOperands};
+use zydis::ffi::{DecodedOperand, DecodedOperandKind, FormatterBuffer, FormatterContext, ImmediateInfo};
+use zydis::{
+  AllOperands, Decoder, Formatter, FormatterStyle, Instruction, InstructionCategory, MachineMode, NoOperands,
+  OperandAction, OperandVisibility, OutputBuffer, Register, RegisterClass, Result as ZydisResult, Status,
+};
 
-use super::comparer_config::FunctionDefinition;
+use super::comparer_config::{FunctionDefinition, GlobalDefinition};
 use super::hexformat::*;
 
 #[derive(Debug, Clone)]
@@ -14,6 +18,88 @@ pub struct DisasmOpts {
   pub print_addresses: bool,
   pub show_mem_disp: bool,
   pub show_imms: bool,
+  pub show_source: bool,
+  /// Names addresses that both binaries reference at the same places alike, see [`pair_references`].
+  pub pair_refs: bool,
+}
+
+/// Lines to print above instructions, keyed by the index of the instruction.
+pub type Annotations = BTreeMap<usize, Vec<String>>;
+
+/// Global variables keyed by their address, to look up the one containing an address.
+pub type GlobalMap = BTreeMap<u64, GlobalDefinition>;
+
+/// Smaller displacements and immediates are taken as offsets and numbers rather than addresses.
+const MIN_ADDRESS: u64 = 0x10000;
+
+/// An address in the disassembly that isn't named, e.g. of an unknown function or global variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+  pub addr: u64,
+  /// How it's printed in the instruction.
+  pub text: String,
+  /// Index of the visible operand it's printed in.
+  pub operand: usize,
+}
+
+/// A formatted instruction.
+#[derive(Debug, Clone)]
+pub struct DisasmLine {
+  pub ip: u64,
+  pub text: String,
+  /// In the order of their operands.
+  pub refs: Vec<Reference>,
+  /// The text up to the first operand, including prefixes.
+  pub mnemonic: String,
+  /// The visible operands as printed in the text. Empty if they couldn't be told apart in it.
+  pub operands: Vec<Operand>,
+  pub flow: Flow,
+  /// The general purpose registers the instruction uses, including hidden operands like `ecx` of `rep movsd`.
+  pub registers: Vec<RegisterAccess>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterAccess {
+  /// The full 32-bit register, e.g. `ecx` for `cl`.
+  pub register: &'static str,
+  pub read: bool,
+  pub written: bool,
+  /// Determined by the instruction itself rather than the compiler, like `cl` in `shl eax, cl`.
+  pub fixed: bool,
+}
+
+/// Where execution continues after an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+  /// With the next instruction, calls included.
+  Next,
+  Jump(u64),
+  /// Either at the target or with the next instruction.
+  Branch(u64),
+  /// Somewhere only known at runtime, like after `ret` or `jmp [eax*4+table]`.
+  Exit,
+}
+
+#[derive(Debug, Clone)]
+pub struct Operand {
+  pub kind: OperandKind,
+  pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OperandKind {
+  Register,
+  Memory {
+    size: u16,
+    /// Names of the base and index register, if any.
+    registers: Vec<&'static str>,
+  },
+  Immediate,
+  Pointer,
+}
+
+pub fn global_map(globals: &[GlobalDefinition]) -> GlobalMap {
+  globals.iter().map(|global| (global.addr, global.clone())).collect()
 }
 
 #[derive(Debug, Clone)]
@@ -21,7 +107,10 @@ struct DisasmExtra {
   #[allow(dead_code)]
   pub opts: DisasmOpts,
   pub fn_map: HashMap<u64, FunctionDefinition>,
+  pub globals: GlobalMap,
   pub offset: u64,
+  /// References printed by the hooks while formatting the current instruction.
+  pub refs: Vec<Reference>,
 }
 
 #[derive(Debug, Error)]
@@ -39,7 +128,32 @@ pub fn write_disasm(
   disasm_opts: &DisasmOpts,
   offset: u64,
   fn_map: &HashMap<u64, FunctionDefinition>,
+  globals: &GlobalMap,
 ) -> Result<(), DisasmError> {
+  write_disasm_annotated(writer, bytes, disasm_opts, offset, fn_map, globals, &Annotations::new())
+}
+
+pub fn write_disasm_annotated(
+  writer: &mut impl Write,
+  bytes: &[u8],
+  disasm_opts: &DisasmOpts,
+  offset: u64,
+  fn_map: &HashMap<u64, FunctionDefinition>,
+  globals: &GlobalMap,
+  annotations: &Annotations,
+) -> Result<(), DisasmError> {
+  let lines = disasm_lines(bytes, disasm_opts, offset, fn_map, globals)?;
+  write_lines(writer, &lines, disasm_opts, annotations)
+}
+
+/// Disassembles all instructions up to the first one that can't be decoded.
+pub fn disasm_lines(
+  bytes: &[u8],
+  disasm_opts: &DisasmOpts,
+  offset: u64,
+  fn_map: &HashMap<u64, FunctionDefinition>,
+  globals: &GlobalMap,
+) -> Result<Vec<DisasmLine>, DisasmError> {
   let mut buf = [0u8; 255];
   let mut buf = OutputBuffer::new(&mut buf);
 
@@ -48,6 +162,8 @@ pub fn write_disasm(
 
   if !disasm_opts.show_mem_disp {
     formatter.set_print_disp(Box::new(void_format_disp))?;
+  } else if !globals.is_empty() {
+    formatter.set_print_disp(Box::new(format_disp))?;
   }
 
   if !disasm_opts.show_imms {
@@ -59,36 +175,323 @@ pub fn write_disasm(
   let mut disasm_extra = DisasmExtra {
     opts: disasm_opts.clone(),
     fn_map: fn_map.clone(),
+    globals: globals.clone(),
     offset,
+    refs: Vec::new(),
   };
 
-  for insn_info in decoder.decode_all::<VisibleOperands>(bytes, offset) {
-    if insn_info.is_err() {
+  let mut lines = Vec::new();
+  for insn_info in decoder.decode_all::<AllOperands>(bytes, offset) {
+    let Ok((ip, _, insn)) = insn_info else {
       break;
-    }
-
-    let (ip, _, insn) = insn_info.unwrap();
+    };
 
     disasm_extra.offset = ip; // BUG: Formatter is not propagating the instruction pointer
+    disasm_extra.refs.clear();
     formatter.format_ex(Some(ip), &insn, &mut buf, Some(&mut disasm_extra))?;
 
-    let insn_str = buf.as_str().expect("not utf8");
+    let text = buf.as_str().expect("not utf8").to_string();
+
+    let mut refs = Vec::new();
+    let mut operands = Vec::new();
+    for (index, op) in insn.visible_operands().iter().enumerate() {
+      disasm_extra.refs.clear();
+      formatter.format_operand(Some(ip), &insn, &mut buf, index, Some(&mut disasm_extra))?;
+      let op_text = buf.as_str().expect("not utf8").to_string();
+      refs.extend(
+        disasm_extra
+          .refs
+          .drain(..)
+          .chain(printed_value(op, &op_text, disasm_opts))
+          .map(|reference| Reference {
+            operand: index,
+            ..reference
+          }),
+      );
+      let kind = match &op.kind {
+        DecodedOperandKind::Reg(_) => OperandKind::Register,
+        DecodedOperandKind::Mem(mem) => OperandKind::Memory {
+          size: op.size,
+          registers: [mem.base, mem.index]
+            .into_iter()
+            .filter(|&register| register != Register::NONE)
+            .filter_map(Register::static_string)
+            .collect(),
+        },
+        DecodedOperandKind::Imm(_) => OperandKind::Immediate,
+        _ => OperandKind::Pointer,
+      };
+      operands.push(Operand { kind, text: op_text });
+    }
+
+    let flow = control_flow(&insn, ip);
+    let registers = register_accesses(&insn);
+    let mnemonic = split_mnemonic(&text, &operands);
+    if mnemonic.is_none() {
+      operands.clear();
+    }
+    lines.push(DisasmLine {
+      ip,
+      mnemonic: mnemonic.unwrap_or(&text).to_string(),
+      text,
+      refs,
+      operands,
+      flow,
+      registers,
+    });
+  }
+
+  Ok(lines)
+}
+
+pub fn write_lines(
+  writer: &mut impl Write,
+  lines: &[DisasmLine],
+  disasm_opts: &DisasmOpts,
+  annotations: &Annotations,
+) -> Result<(), DisasmError> {
+  for (index, line) in lines.iter().enumerate() {
+    for annotation in annotations.get(&index).into_iter().flatten() {
+      writeln!(writer, "{}", annotation)?;
+    }
 
     if disasm_opts.print_addresses {
-      writeln!(writer, "{:X}: {}", ip, insn_str)?;
+      writeln!(writer, "{:X}: {}", line.ip, line.text)?;
     } else {
-      writeln!(writer, "{}", insn_str)?;
+      writeln!(writer, "{}", line.text)?;
     }
   }
 
   Ok(())
 }
 
+/// The text before the operands, if they are printed one after another as usual.
+fn split_mnemonic<'a>(text: &'a str, operands: &[Operand]) -> Option<&'a str> {
+  let Some(first) = operands.first() else {
+    return Some(text);
+  };
+  let start = text.find(&first.text)?;
+  let operand_text = operands
+    .iter()
+    .map(|op| op.text.as_str())
+    .collect::<Vec<_>>()
+    .join(", ");
+  (text[start..] == operand_text).then(|| text[..start].trim_end())
+}
+
+fn control_flow(insn: &Instruction<AllOperands>, ip: u64) -> Flow {
+  let target = insn
+    .visible_operands()
+    .first()
+    .filter(|op| matches!(op.kind, DecodedOperandKind::Imm(_)))
+    .and_then(|op| insn.calc_absolute_address(ip, op).ok());
+
+  match (insn.meta.category, target) {
+    (InstructionCategory::COND_BR, Some(target)) => Flow::Branch(target),
+    (InstructionCategory::UNCOND_BR, Some(target)) => Flow::Jump(target),
+    (InstructionCategory::UNCOND_BR | InstructionCategory::RET, _) => Flow::Exit,
+    _ => Flow::Next,
+  }
+}
+
+/// Lists the general purpose registers of all operands, the base and index of memory operands being read.
+fn register_accesses(insn: &Instruction<AllOperands>) -> Vec<RegisterAccess> {
+  let mut accesses = Vec::new();
+  for op in insn.operands() {
+    let fixed = op.visibility != OperandVisibility::EXPLICIT;
+    let (registers, read, written) = match &op.kind {
+      DecodedOperandKind::Reg(register) => (
+        vec![*register],
+        op.action.intersects(OperandAction::MASK_READ),
+        op.action.intersects(OperandAction::MASK_WRITE),
+      ),
+      DecodedOperandKind::Mem(mem) => (vec![mem.base, mem.index], true, false),
+      _ => continue,
+    };
+    for register in registers {
+      let full = register.largest_enclosing(MachineMode::LEGACY_32);
+      if full.class() != RegisterClass::GPR32 {
+        continue;
+      }
+      accesses.extend(full.static_string().map(|register| RegisterAccess {
+        register,
+        read,
+        written,
+        fixed,
+      }));
+    }
+  }
+  accesses
+}
+
+/// Finds an immediate or displacement that looks like an address, which Zydis printed itself in the operand.
+fn printed_value(op: &DecodedOperand, op_text: &str, disasm_opts: &DisasmOpts) -> Option<Reference> {
+  let value = match &op.kind {
+    DecodedOperandKind::Imm(imm) if disasm_opts.show_imms && !imm.is_relative => imm.value,
+    // absolute addresses without any register go through `format_addrs`
+    DecodedOperandKind::Mem(mem)
+      if disasm_opts.show_mem_disp
+        && mem.disp.has_displacement
+        && (mem.base != Register::NONE || mem.index != Register::NONE) =>
+    {
+      mem.disp.displacement as u64
+    }
+    _ => return None,
+  };
+  (MIN_ADDRESS..=u32::MAX as u64)
+    .contains(&value)
+    .then(|| Reference {
+      addr: value,
+      text: masm_hex(value),
+      operand: 0,
+    })
+    .filter(|reference| find_token(op_text, &reference.text).is_some())
+}
+
+/// Finds `token` in `text` where it isn't part of a longer number or name, e.g. `10000h` not in `410000h`.
+fn find_token(text: &str, token: &str) -> Option<usize> {
+  let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+  text
+    .match_indices(token)
+    .map(|(start, _)| start)
+    .find(|&start| !text[..start].ends_with(is_word) && !text[start + token.len()..].starts_with(is_word))
+}
+
+fn replace_token(text: &str, token: &str, replacement: &str) -> String {
+  match find_token(text, token) {
+    Some(start) => format!("{}{replacement}{}", &text[..start], &text[start + token.len()..]),
+    None => text.to_string(),
+  }
+}
+
+impl DisasmLine {
+  /// Replaces references by the names given for them, in the operand each one is printed in.
+  fn rename_refs(&mut self, name: impl Fn(&Reference) -> Option<String>) {
+    let operand_text = |operands: &[Operand]| {
+      operands
+        .iter()
+        .map(|op| op.text.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+    };
+    let prefix_len = self.text.len() - operand_text(&self.operands).len();
+    for reference in &self.refs {
+      let Some(new_name) = name(reference) else {
+        continue;
+      };
+      match self.operands.get_mut(reference.operand) {
+        Some(op) => op.text = replace_token(&op.text, &reference.text, &new_name),
+        None => self.text = replace_token(&self.text, &reference.text, &new_name),
+      }
+    }
+    if !self.operands.is_empty() {
+      self.text = format!("{}{}", &self.text[..prefix_len], operand_text(&self.operands));
+    }
+  }
+}
+
+/// Splits a text into words, runs of whitespace and single other characters.
+pub fn tokenize(text: &str) -> Vec<&str> {
+  let class = |c: char| {
+    if c.is_ascii_alphanumeric() || c == '_' {
+      0
+    } else if c.is_whitespace() {
+      1
+    } else {
+      2
+    }
+  };
+
+  let mut tokens = Vec::new();
+  let mut rest = text;
+  while let Some(first) = rest.chars().next() {
+    let end = match class(first) {
+      2 => first.len_utf8(),
+      first_class => rest.find(|c: char| class(c) != first_class).unwrap_or(rest.len()),
+    };
+    tokens.push(&rest[..end]);
+    rest = &rest[end..];
+  }
+  tokens
+}
+
+/// Formats a number like Zydis does in MASM style, e.g. `0A0h`.
+pub fn masm_hex(value: u64) -> String {
+  let hex = format!("{value:X}");
+  let zero = if hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+    "0"
+  } else {
+    ""
+  };
+  format!("{zero}{hex}h")
+}
+
+/// Replaces the addresses that both sides reference at the same instructions by shared placeholder names.
+///
+/// The instructions are aligned like in a diff, ignoring the addresses. Whenever aligned instructions reference
+/// an orig address A and a rebuilt address B, both are printed as the same `ref_<n>` everywhere. The first pairing
+/// wins: if A is referenced alongside a different address later on, that one keeps its own name or address, so the
+/// instructions still differ.
+pub fn pair_references(orig: &mut [DisasmLine], compare: &mut [DisasmLine]) {
+  let masked = |lines: &[DisasmLine]| {
+    lines
+      .iter()
+      .map(|line| {
+        let mut line = line.clone();
+        line.rename_refs(|_| Some(String::from("<ref>")));
+        line.text
+      })
+      .collect::<Vec<_>>()
+  };
+  let orig_masked = masked(orig);
+  let compare_masked = masked(compare);
+
+  let mut orig_names = HashMap::new();
+  let mut compare_names = HashMap::new();
+  for op in capture_diff_slices(Algorithm::Myers, &orig_masked, &compare_masked) {
+    let DiffOp::Equal {
+      old_index,
+      new_index,
+      len,
+    } = op
+    else {
+      continue;
+    };
+
+    let orig_lines = &orig[old_index..old_index + len];
+    let compare_lines = &compare[new_index..new_index + len];
+    for (orig_line, compare_line) in orig_lines.iter().zip(compare_lines) {
+      for (orig_ref, compare_ref) in orig_line.refs.iter().zip(&compare_line.refs) {
+        if orig_names.contains_key(&orig_ref.addr) || compare_names.contains_key(&compare_ref.addr) {
+          continue;
+        }
+        let name = format!("ref_{}", orig_names.len() + 1);
+        orig_names.insert(orig_ref.addr, name.clone());
+        compare_names.insert(compare_ref.addr, name);
+      }
+    }
+  }
+
+  for (lines, names) in [(orig, &orig_names), (compare, &compare_names)] {
+    for line in lines.iter_mut() {
+      line.rename_refs(|reference| names.get(&reference.addr).cloned());
+    }
+  }
+}
+
+/// Returns the address of every instruction that `write_disasm` would print.
+pub fn instruction_addresses(bytes: &[u8], offset: u64) -> Vec<u64> {
+  Decoder::new32()
+    .decode_all::<NoOperands>(bytes, offset)
+    .map_while(|insn_info| insn_info.ok().map(|(ip, _, _)| ip))
+    .collect()
+}
+
 fn cleanup_name(func: &FunctionDefinition) -> String {
   func.name.split('(').next().unwrap_or(&func.name).to_string()
 }
 
-fn process_address(target_addr: u64, imm: &ImmediateInfo) -> String {
+fn process_address(imm: &ImmediateInfo) -> String {
   let prefix = if imm.is_relative { "$" } else { "" };
 
   if imm.is_signed {
@@ -117,8 +520,19 @@ fn format_addrs(
             buf.append_str("<indir_fn>")? // hide function call addresses, 0xFF /3 = CALL m16:32)
           } else {
             let target_addr = mem.disp.displacement as u64;
-            let func = opts.fn_map.get(&target_addr);
-            buf.append_str(&func.map_or_else(|| format!("{:#X}", target_addr), cleanup_name))?
+            let name = match opts.fn_map.get(&target_addr) {
+              Some(func) => cleanup_name(func),
+              None => global_name(&opts.globals, target_addr).unwrap_or_else(|| {
+                let text = format!("{:#X}", target_addr);
+                opts.refs.push(Reference {
+                  addr: target_addr,
+                  text: text.clone(),
+                  operand: 0,
+                });
+                text
+              }),
+            };
+            buf.append_str(&name)?
           }
         } else {
           buf.append_str("<indir_addr>")?
@@ -126,8 +540,19 @@ fn format_addrs(
       }
       DecodedOperandKind::Imm(imm) => {
         let target_addr = insn.calc_absolute_address(opts.offset, op)?;
-        let func = opts.fn_map.get(&target_addr);
-        buf.append_str(&func.map_or_else(|| process_address(target_addr, imm), cleanup_name))?
+        let name = match opts.fn_map.get(&target_addr) {
+          Some(func) => cleanup_name(func),
+          None => {
+            let text = process_address(imm);
+            opts.refs.push(Reference {
+              addr: target_addr,
+              text: text.clone(),
+              operand: 0,
+            });
+            text
+          }
+        };
+        buf.append_str(&name)?
       }
       _ => {}
     }
@@ -136,6 +561,41 @@ fn format_addrs(
   Ok(())
 }
 
+/// Names the global variable containing the given address, relative to its start if needed.
+fn global_name(globals: &GlobalMap, addr: u64) -> Option<String> {
+  let (&start, global) = globals.range(..=addr).next_back()?;
+  match addr - start {
+    0 => Some(global.name.clone()),
+    offset if offset < global.size.unwrap_or(0) as u64 => Some(format!("{}+{:#X}", global.name, offset)),
+    _ => None,
+  }
+}
+
+/// Prints displacements like `[eax*4+g_table]` as global variables, and all others the way Zydis does.
+fn format_disp(
+  _: &Formatter<DisasmExtra>,
+  buf: &mut FormatterBuffer,
+  ctx: &mut FormatterContext,
+  disasm_opts: Option<&mut DisasmExtra>,
+) -> ZydisResult<()> {
+  unsafe {
+    let opts = disasm_opts.unwrap();
+    let op = &*ctx.operand;
+
+    if let DecodedOperandKind::Mem(mem) = &op.kind {
+      let disp = mem.disp.displacement;
+      match global_name(&opts.globals, disp as u64) {
+        Some(name) => buf.append_str(&format!("+{name}"))?,
+        None => {
+          let sign = if disp < 0 { "-" } else { "+" };
+          buf.append_str(&format!("{sign}{}", masm_hex(disp.unsigned_abs())))?
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
 fn void_format_disp(
   _: &Formatter<DisasmExtra>,
   buf: &mut FormatterBuffer,
@@ -181,3 +641,65 @@ impl Compat for FormatterBuffer {
     self.get_string().expect("not utf8").append(s)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const OPTS: DisasmOpts = DisasmOpts {
+    print_addresses: false,
+    show_mem_disp: true,
+    show_imms: true,
+    show_source: false,
+    pair_refs: true,
+  };
+
+  fn lines(bytes: &[u8]) -> Vec<DisasmLine> {
+    disasm_lines(bytes, &OPTS, 0x401000, &HashMap::new(), &GlobalMap::new()).unwrap()
+  }
+
+  fn texts(lines: &[DisasmLine]) -> Vec<&str> {
+    lines.iter().map(|line| line.text.as_str()).collect()
+  }
+
+  #[test]
+  fn pairs_relocated_call_targets() {
+    // call 402000h; ret
+    let mut orig = lines(&[0xE8, 0xFB, 0x0F, 0x00, 0x00, 0xC3]);
+    // call 403000h; ret
+    let mut compare = lines(&[0xE8, 0xFB, 0x1F, 0x00, 0x00, 0xC3]);
+    assert_ne!(texts(&orig), texts(&compare));
+
+    pair_references(&mut orig, &mut compare);
+    assert_eq!(texts(&orig), ["call ref_1", "ret"]);
+    assert_eq!(texts(&compare), ["call ref_1", "ret"]);
+  }
+
+  #[test]
+  fn first_pairing_wins() {
+    // mov eax, [410000h]; mov ecx, [410000h]
+    let mut orig = lines(&[0xA1, 0x00, 0x00, 0x41, 0x00, 0x8B, 0x0D, 0x00, 0x00, 0x41, 0x00]);
+    // mov eax, [420000h]; mov ecx, [430000h]
+    let mut compare = lines(&[0xA1, 0x00, 0x00, 0x42, 0x00, 0x8B, 0x0D, 0x00, 0x00, 0x43, 0x00]);
+
+    pair_references(&mut orig, &mut compare);
+    assert_eq!(
+      texts(&orig),
+      ["mov eax, dword ptr [ref_1]", "mov ecx, dword ptr [ref_1]"]
+    );
+    assert_eq!(
+      texts(&compare),
+      ["mov eax, dword ptr [ref_1]", "mov ecx, dword ptr [0x430000]"]
+    );
+  }
+
+  #[test]
+  fn replaces_whole_numbers_only() {
+    assert_eq!(
+      replace_token("mov eax, 1410000h", "410000h", "ref_1"),
+      "mov eax, 1410000h"
+    );
+    assert_eq!(replace_token("push 410000h", "410000h", "ref_1"), "push ref_1");
+    assert_eq!(find_token("[eax+410000h]", "410000h"), Some(5));
+  }
+}
@@ -0,0 +1,491 @@
+//! Comparing functions by their basic blocks, so that code laid out in a different order still matches.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{Result as IoResult, Write};
+use std::ops::Range;
+
+use serde::Serialize;
+
+use super::disasm::{DisasmLine, Flow};
+
+/// A run of instructions that is only entered at its first one and only left after its last one.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+  pub addr: u64,
+  /// Indices of its instructions.
+  pub lines: Range<usize>,
+  /// The mnemonic of the conditional jump ending the block, and the block it jumps to.
+  pub branch: Option<(String, usize)>,
+  /// The block that execution continues with otherwise, by falling through or jumping.
+  pub next: Option<usize>,
+  /// The instructions compared with the other side: all but a final jump within the function, as its target
+  /// depends on the layout.
+  body: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlowGraph {
+  pub blocks: Vec<BasicBlock>,
+}
+
+impl FlowGraph {
+  /// Splits the instructions into blocks after every jump and at every jump target within the function.
+  pub fn new(lines: &[DisasmLine]) -> Self {
+    let index_of = |addr: u64| lines.binary_search_by_key(&addr, |line| line.ip).ok();
+
+    let mut starts = BTreeSet::from([0]);
+    for (index, line) in lines.iter().enumerate() {
+      match line.flow {
+        Flow::Next => continue,
+        Flow::Jump(target) | Flow::Branch(target) => starts.extend(index_of(target)),
+        Flow::Exit => {}
+      }
+      starts.insert(index + 1);
+    }
+    starts.retain(|&start| start < lines.len());
+    let starts = starts.into_iter().collect::<Vec<_>>();
+    let block_at = |addr: u64| starts.binary_search(&index_of(addr)?).ok();
+
+    let blocks = starts
+      .iter()
+      .enumerate()
+      .map(|(block, &start)| {
+        let end = starts.get(block + 1).copied().unwrap_or(lines.len());
+        let following = (block + 1 < starts.len()).then_some(block + 1);
+        let last = &lines[end - 1];
+
+        let (branch, next, body_end) = match last.flow {
+          Flow::Next => (None, following, end),
+          Flow::Jump(target) => match block_at(target) {
+            Some(target) => (None, Some(target), end - 1),
+            // tail calls
+            None => (None, None, end),
+          },
+          Flow::Branch(target) => match block_at(target) {
+            Some(target) => (Some((last.mnemonic.clone(), target)), following, end - 1),
+            None => (None, following, end),
+          },
+          Flow::Exit => (None, None, end),
+        };
+
+        BasicBlock {
+          addr: lines[start].ip,
+          lines: start..end,
+          branch,
+          next,
+          body: start..body_end,
+        }
+      })
+      .collect();
+
+    FlowGraph { blocks }
+  }
+}
+
+/// How the basic blocks of the original and the rebuilt function correspond to each other.
+#[derive(Debug, Clone)]
+pub struct FlowComparison {
+  pub orig: FlowGraph,
+  pub compare: FlowGraph,
+  /// The rebuilt block matched with each original block.
+  pub orig_matches: Vec<Option<usize>>,
+  /// The original block matched with each rebuilt block.
+  pub compare_matches: Vec<Option<usize>>,
+}
+
+/// How a block compares to the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+  /// Matched, and continuing with matched blocks.
+  Matched,
+  /// Matched but continuing differently, or in place of a block that differs.
+  Changed,
+  /// Without a counterpart.
+  Missing,
+}
+
+/// The outcome of a [`FlowComparison`], with blocks referred to by their addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockSummary {
+  pub orig_blocks: usize,
+  pub compare_blocks: usize,
+  pub matched: usize,
+  /// Set if all blocks are matched and continue with matching blocks.
+  pub equivalent: bool,
+  pub unmatched_orig: Vec<u64>,
+  pub unmatched_compare: Vec<u64>,
+  /// Matched original blocks that continue with different blocks than their counterparts.
+  pub different_successors: Vec<u64>,
+}
+
+/// Matches the basic blocks of both functions by their instructions, following the control flow from the
+/// entry blocks on. Blocks that can't be reached that way, e.g. through jump tables, are matched by their
+/// instructions alone.
+///
+/// The jumps between blocks are left out of the comparison, so blocks may be laid out in any order, and
+/// conditional jumps may be inverted when their targets are swapped, as for swapped if/else blocks.
+pub fn compare_flow_graphs(orig_lines: &[DisasmLine], compare_lines: &[DisasmLine]) -> FlowComparison {
+  let orig = FlowGraph::new(orig_lines);
+  let compare = FlowGraph::new(compare_lines);
+  let bodies = |graph: &FlowGraph, lines: &[DisasmLine]| {
+    graph
+      .blocks
+      .iter()
+      .map(|block| lines[block.body.clone()].iter().map(|line| line.text.clone()).collect())
+      .collect::<Vec<Vec<String>>>()
+  };
+  let orig_bodies = bodies(&orig, orig_lines);
+  let compare_bodies = bodies(&compare, compare_lines);
+
+  let mut orig_matches = vec![None; orig.blocks.len()];
+  let mut compare_matches = vec![None; compare.blocks.len()];
+  let mut queue = VecDeque::new();
+  if !orig.blocks.is_empty() && !compare.blocks.is_empty() {
+    queue.push_back((0, 0));
+  }
+
+  loop {
+    while let Some((orig_block, compare_block)) = queue.pop_front() {
+      if orig_matches[orig_block].is_some()
+        || compare_matches[compare_block].is_some()
+        || orig_bodies[orig_block] != compare_bodies[compare_block]
+      {
+        continue;
+      }
+      orig_matches[orig_block] = Some(compare_block);
+      compare_matches[compare_block] = Some(orig_block);
+
+      let successors = successor_pairs(&orig.blocks[orig_block], &compare.blocks[compare_block]);
+      queue.extend(
+        successors
+          .into_iter()
+          .flatten()
+          .filter_map(|(orig_next, compare_next)| orig_next.zip(compare_next)),
+      );
+    }
+
+    let unmatched = (0..orig.blocks.len())
+      .filter(|&orig_block| orig_matches[orig_block].is_none())
+      .find_map(|orig_block| {
+        (0..compare.blocks.len())
+          .find(|&compare_block| {
+            compare_matches[compare_block].is_none() && orig_bodies[orig_block] == compare_bodies[compare_block]
+          })
+          .map(|compare_block| (orig_block, compare_block))
+      });
+    match unmatched {
+      Some(pair) => queue.push_back(pair),
+      None => break,
+    }
+  }
+
+  FlowComparison {
+    orig,
+    compare,
+    orig_matches,
+    compare_matches,
+  }
+}
+
+/// Pairs up the successors of two blocks, or `None` if they end in different ways.
+fn successor_pairs(orig: &BasicBlock, compare: &BasicBlock) -> Option<Vec<(Option<usize>, Option<usize>)>> {
+  match (&orig.branch, &compare.branch) {
+    (None, None) => Some(vec![(orig.next, compare.next)]),
+    (Some((orig_mnemonic, orig_target)), Some((compare_mnemonic, compare_target))) => {
+      if orig_mnemonic == compare_mnemonic {
+        Some(vec![
+          (Some(*orig_target), Some(*compare_target)),
+          (orig.next, compare.next),
+        ])
+      } else if inverted_branch(orig_mnemonic).as_ref() == Some(compare_mnemonic) {
+        Some(vec![
+          (Some(*orig_target), compare.next),
+          (orig.next, Some(*compare_target)),
+        ])
+      } else {
+        None
+      }
+    }
+    _ => None,
+  }
+}
+
+/// The conditional jump taken in exactly the opposite cases, e.g. `jnz` for `jz`.
+fn inverted_branch(mnemonic: &str) -> Option<String> {
+  let condition = mnemonic.strip_prefix('j')?;
+  match condition.strip_prefix('n') {
+    Some(condition) => Some(format!("j{condition}")),
+    None => Some(format!("jn{condition}")),
+  }
+}
+
+impl FlowComparison {
+  pub fn matched(&self) -> usize {
+    self.orig_matches.iter().flatten().count()
+  }
+
+  /// Whether a matched original block continues with the counterparts of the blocks its match continues with.
+  fn successors_match(&self, orig_block: usize) -> bool {
+    let Some(compare_block) = self.orig_matches[orig_block] else {
+      return false;
+    };
+    let Some(successors) = successor_pairs(&self.orig.blocks[orig_block], &self.compare.blocks[compare_block]) else {
+      return false;
+    };
+    successors.into_iter().all(|pair| match pair {
+      (None, None) => true,
+      (Some(orig_next), Some(compare_next)) => self.orig_matches[orig_next] == Some(compare_next),
+      _ => false,
+    })
+  }
+
+  /// The states of the original and the rebuilt blocks. Unmatched blocks that matched blocks continue with
+  /// on both sides are taken as changed versions of each other.
+  pub fn block_states(&self) -> (Vec<BlockState>, Vec<BlockState>) {
+    let mut orig_states = (0..self.orig.blocks.len())
+      .map(|block| match self.orig_matches[block] {
+        Some(_) if self.successors_match(block) => BlockState::Matched,
+        Some(_) => BlockState::Changed,
+        None => BlockState::Missing,
+      })
+      .collect::<Vec<_>>();
+    let mut compare_states = self
+      .compare_matches
+      .iter()
+      .map(|matched| matched.map_or(BlockState::Missing, |orig_block| orig_states[orig_block]))
+      .collect::<Vec<_>>();
+
+    let mut queue = self
+      .orig_matches
+      .iter()
+      .enumerate()
+      .filter_map(|(orig_block, compare_block)| Some((orig_block, (*compare_block)?)))
+      .collect::<VecDeque<_>>();
+    while let Some((orig_block, compare_block)) = queue.pop_front() {
+      let successors = successor_pairs(&self.orig.blocks[orig_block], &self.compare.blocks[compare_block]);
+      for (orig_next, compare_next) in successors
+        .into_iter()
+        .flatten()
+        .filter_map(|(orig_next, compare_next)| orig_next.zip(compare_next))
+      {
+        if orig_states[orig_next] == BlockState::Missing && compare_states[compare_next] == BlockState::Missing {
+          orig_states[orig_next] = BlockState::Changed;
+          compare_states[compare_next] = BlockState::Changed;
+          queue.push_back((orig_next, compare_next));
+        }
+      }
+    }
+
+    (orig_states, compare_states)
+  }
+
+  pub fn summary(&self) -> BlockSummary {
+    let unmatched = |graph: &FlowGraph, matches: &[Option<usize>]| {
+      graph
+        .blocks
+        .iter()
+        .zip(matches)
+        .filter(|(_, matched)| matched.is_none())
+        .map(|(block, _)| block.addr)
+        .collect::<Vec<_>>()
+    };
+    let unmatched_orig = unmatched(&self.orig, &self.orig_matches);
+    let unmatched_compare = unmatched(&self.compare, &self.compare_matches);
+    let different_successors = (0..self.orig.blocks.len())
+      .filter(|&block| self.orig_matches[block].is_some() && !self.successors_match(block))
+      .map(|block| self.orig.blocks[block].addr)
+      .collect::<Vec<_>>();
+
+    BlockSummary {
+      orig_blocks: self.orig.blocks.len(),
+      compare_blocks: self.compare.blocks.len(),
+      matched: self.matched(),
+      equivalent: unmatched_orig.is_empty() && unmatched_compare.is_empty() && different_successors.is_empty(),
+      unmatched_orig,
+      unmatched_compare,
+      different_successors,
+    }
+  }
+}
+
+impl Display for BlockSummary {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.equivalent {
+      return write!(f, "all {} blocks matched", self.matched);
+    }
+    write!(
+      f,
+      "{} of {} original and {} rebuilt blocks matched",
+      self.matched, self.orig_blocks, self.compare_blocks
+    )?;
+    if !self.different_successors.is_empty() {
+      write!(f, ", {} with different successors", self.different_successors.len())?;
+    }
+    Ok(())
+  }
+}
+
+/// Lists the blocks of both functions that have no counterpart, and the matched ones that continue differently.
+pub fn write_unmatched_blocks(
+  writer: &mut impl Write,
+  comparison: &FlowComparison,
+  orig_lines: &[DisasmLine],
+  compare_lines: &[DisasmLine],
+) -> IoResult<()> {
+  let summary = comparison.summary();
+  writeln!(writer, "Blocks: {summary}")?;
+
+  for (title, graph, matches, lines) in [
+    ("original", &comparison.orig, &comparison.orig_matches, orig_lines),
+    (
+      "rebuilt",
+      &comparison.compare,
+      &comparison.compare_matches,
+      compare_lines,
+    ),
+  ] {
+    let unmatched = graph
+      .blocks
+      .iter()
+      .zip(matches)
+      .filter(|(_, matched)| matched.is_none())
+      .map(|(block, _)| block)
+      .collect::<Vec<_>>();
+    if unmatched.is_empty() {
+      continue;
+    }
+
+    writeln!(writer)?;
+    writeln!(writer, "Unmatched {title} blocks:")?;
+    for block in unmatched {
+      writeln!(writer, "{:X}:", block.addr)?;
+      for line in &lines[block.lines.clone()] {
+        writeln!(writer, "  {}", line.text)?;
+      }
+    }
+  }
+
+  if !summary.different_successors.is_empty() {
+    writeln!(writer)?;
+    writeln!(writer, "Matched blocks with different successors:")?;
+    let different = comparison
+      .orig_matches
+      .iter()
+      .enumerate()
+      .filter_map(|(orig_block, compare_block)| Some((orig_block, (*compare_block)?)))
+      .filter(|&(orig_block, _)| !comparison.successors_match(orig_block));
+    for (orig_block, compare_block) in different {
+      writeln!(
+        writer,
+        "{:X} / {:X}",
+        comparison.orig.blocks[orig_block].addr, comparison.compare.blocks[compare_block].addr
+      )?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::disasm::{DisasmOpts, GlobalMap, disasm_lines};
+
+  fn lines(bytes: &[u8]) -> Vec<DisasmLine> {
+    let opts = DisasmOpts {
+      print_addresses: false,
+      show_mem_disp: true,
+      show_imms: true,
+      show_source: false,
+      pair_refs: false,
+    };
+    disasm_lines(bytes, &opts, 0x401000, &HashMap::new(), &GlobalMap::new()).unwrap()
+  }
+
+  /// An if/else setting `ecx` to `then` or `otherwise` depending on `eax`, with the blocks in the given order.
+  fn if_else(branch: u8, first: u8, second: u8) -> Vec<DisasmLine> {
+    lines(&[
+      0x85, 0xC0, // test eax, eax
+      branch, 0x07, // jz/jnz 40100Bh
+      0xB9, first, 0x00, 0x00, 0x00, // mov ecx, first
+      0xEB, 0x05, // jmp 401010h
+      0xB9, second, 0x00, 0x00, 0x00, // mov ecx, second
+      0x89, 0xC8, // mov eax, ecx
+      0xC3, // ret
+    ])
+  }
+
+  const JZ: u8 = 0x74;
+  const JNZ: u8 = 0x75;
+
+  #[test]
+  fn splits_blocks() {
+    let graph = FlowGraph::new(&if_else(JZ, 1, 2));
+    let blocks = graph
+      .blocks
+      .iter()
+      .map(|block| (block.addr, block.lines.clone(), block.branch.clone(), block.next))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      blocks,
+      [
+        (0x401000, 0..2, Some(("jz".to_string(), 2)), Some(1)),
+        (0x401004, 2..4, None, Some(3)),
+        (0x40100B, 4..5, None, Some(3)),
+        (0x401010, 5..7, None, None),
+      ]
+    );
+  }
+
+  #[test]
+  fn swapped_blocks_with_inverted_branch() {
+    let comparison = compare_flow_graphs(&if_else(JZ, 1, 2), &if_else(JNZ, 2, 1));
+    assert_eq!(comparison.orig_matches, [Some(0), Some(2), Some(1), Some(3)]);
+    let summary = comparison.summary();
+    assert!(summary.equivalent);
+    assert_eq!(summary.matched, 4);
+
+    let (orig_states, compare_states) = comparison.block_states();
+    assert!(orig_states.iter().all(|&state| state == BlockState::Matched));
+    assert!(compare_states.iter().all(|&state| state == BlockState::Matched));
+  }
+
+  #[test]
+  fn swapped_blocks_with_the_same_branch() {
+    let comparison = compare_flow_graphs(&if_else(JZ, 1, 2), &if_else(JZ, 2, 1));
+    // matched by their instructions alone
+    assert_eq!(comparison.orig_matches, [Some(0), Some(2), Some(1), Some(3)]);
+    let summary = comparison.summary();
+    assert!(!summary.equivalent);
+    assert_eq!(summary.different_successors, [0x401000]);
+    assert!(summary.unmatched_orig.is_empty());
+
+    let (orig_states, _) = comparison.block_states();
+    assert_eq!(
+      orig_states,
+      [
+        BlockState::Changed,
+        BlockState::Matched,
+        BlockState::Matched,
+        BlockState::Matched
+      ]
+    );
+  }
+
+  #[test]
+  fn changed_block() {
+    let comparison = compare_flow_graphs(&if_else(JZ, 1, 2), &if_else(JNZ, 3, 1));
+    let summary = comparison.summary();
+    assert!(!summary.equivalent);
+    assert_eq!(summary.matched, 3);
+    assert_eq!(summary.unmatched_orig, [0x40100B]);
+    assert_eq!(summary.unmatched_compare, [0x401004]);
+    assert_eq!(summary.different_successors, [0x401000]);
+
+    let (orig_states, compare_states) = comparison.block_states();
+    assert_eq!(orig_states[2], BlockState::Changed);
+    assert_eq!(compare_states[1], BlockState::Changed);
+  }
+}
@@ -0,0 +1,252 @@
+//! Drawing the basic blocks of both functions side by side, as Graphviz DOT and as SVG for the report.
+
+use std::io::{Result as IoResult, Write};
+
+use handlebars::html_escape;
+
+use super::disasm::DisasmLine;
+use super::flow_graph::{BasicBlock, BlockState, FlowComparison, FlowGraph};
+
+const CHAR_WIDTH: f64 = 7.2;
+const LINE_HEIGHT: f64 = 15.0;
+const PADDING: f64 = 6.0;
+const BLOCK_GAP: f64 = 24.0;
+const LANE_WIDTH: f64 = 8.0;
+const COLUMN_GAP: f64 = 48.0;
+const TITLE_HEIGHT: f64 = 24.0;
+
+/// How control gets from a block to another one.
+#[derive(Debug, Clone, Copy)]
+enum EdgeKind {
+  Taken,
+  NotTaken,
+  /// Falling through or jumping unconditionally.
+  Next,
+}
+
+impl EdgeKind {
+  fn dot_color(self) -> &'static str {
+    match self {
+      EdgeKind::Taken => "#2e7d32",
+      EdgeKind::NotTaken => "#c62828",
+      EdgeKind::Next => "#1565c0",
+    }
+  }
+
+  fn class(self) -> &'static str {
+    match self {
+      EdgeKind::Taken => "taken",
+      EdgeKind::NotTaken => "not-taken",
+      EdgeKind::Next => "next",
+    }
+  }
+}
+
+impl BlockState {
+  fn dot_color(self) -> &'static str {
+    match self {
+      BlockState::Matched => "#c8e6c9",
+      BlockState::Changed => "#fff3b0",
+      BlockState::Missing => "#ffcdd2",
+    }
+  }
+
+  fn class(self) -> &'static str {
+    match self {
+      BlockState::Matched => "matched",
+      BlockState::Changed => "changed",
+      BlockState::Missing => "missing",
+    }
+  }
+}
+
+/// One of the functions to draw.
+struct Side<'a> {
+  id: &'static str,
+  title: &'static str,
+  graph: &'a FlowGraph,
+  states: Vec<BlockState>,
+  lines: &'a [DisasmLine],
+}
+
+fn sides<'a>(
+  comparison: &'a FlowComparison,
+  orig_lines: &'a [DisasmLine],
+  compare_lines: &'a [DisasmLine],
+) -> [Side<'a>; 2] {
+  let (orig_states, compare_states) = comparison.block_states();
+  [
+    Side {
+      id: "orig",
+      title: "Original",
+      graph: &comparison.orig,
+      states: orig_states,
+      lines: orig_lines,
+    },
+    Side {
+      id: "compare",
+      title: "New",
+      graph: &comparison.compare,
+      states: compare_states,
+      lines: compare_lines,
+    },
+  ]
+}
+
+fn edges(block: &BasicBlock) -> Vec<(usize, EdgeKind)> {
+  match &block.branch {
+    Some((_, target)) => [(Some(*target), EdgeKind::Taken), (block.next, EdgeKind::NotTaken)]
+      .into_iter()
+      .filter_map(|(target, kind)| Some((target?, kind)))
+      .collect(),
+    None => block.next.map(|next| (next, EdgeKind::Next)).into_iter().collect(),
+  }
+}
+
+/// The text of a block: its address, then its instructions.
+fn block_text(block: &BasicBlock, lines: &[DisasmLine]) -> Vec<String> {
+  std::iter::once(format!("{:X}:", block.addr))
+    .chain(lines[block.lines.clone()].iter().map(|line| format!("  {}", line.text)))
+    .collect()
+}
+
+/// Writes a Graphviz graph with a cluster of basic blocks for each function, colored by whether they are
+/// matched, changed or missing on the other side. Render it with e.g. `dot -Tsvg`.
+pub fn write_dot(
+  writer: &mut impl Write,
+  name: &str,
+  comparison: &FlowComparison,
+  orig_lines: &[DisasmLine],
+  compare_lines: &[DisasmLine],
+) -> IoResult<()> {
+  writeln!(writer, "digraph \"{}\" {{", dot_escape(name))?;
+  writeln!(
+    writer,
+    "  node [shape=box, style=filled, fontname=\"Courier New\", fontsize=10];"
+  )?;
+
+  for side in sides(comparison, orig_lines, compare_lines) {
+    writeln!(writer, "  subgraph cluster_{} {{", side.id)?;
+    writeln!(writer, "    label=\"{}\";", side.title)?;
+    for (index, block) in side.graph.blocks.iter().enumerate() {
+      let label = block_text(block, side.lines)
+        .iter()
+        .map(|line| format!("{}\\l", dot_escape(line)))
+        .collect::<String>();
+      writeln!(
+        writer,
+        "    {}_{index} [label=\"{label}\", fillcolor=\"{}\"];",
+        side.id,
+        side.states[index].dot_color()
+      )?;
+    }
+    for (index, block) in side.graph.blocks.iter().enumerate() {
+      for (target, kind) in edges(block) {
+        writeln!(
+          writer,
+          "    {id}_{index} -> {id}_{target} [color=\"{}\"];",
+          kind.dot_color(),
+          id = side.id
+        )?;
+      }
+    }
+    writeln!(writer, "  }}")?;
+  }
+
+  writeln!(writer, "}}")
+}
+
+fn dot_escape(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Draws the basic blocks of both functions as two columns of boxes in address order, like the disassembly.
+/// Jumps to the following block go straight down, all others are routed along the right of the column.
+pub fn render_svg(comparison: &FlowComparison, orig_lines: &[DisasmLine], compare_lines: &[DisasmLine]) -> String {
+  let mut body = String::new();
+  let mut x = 0.0;
+  let mut height: f64 = 0.0;
+
+  for side in sides(comparison, orig_lines, compare_lines) {
+    let texts = side
+      .graph
+      .blocks
+      .iter()
+      .map(|block| block_text(block, side.lines))
+      .collect::<Vec<_>>();
+    let chars = texts
+      .iter()
+      .flatten()
+      .map(|line| line.chars().count())
+      .max()
+      .unwrap_or(0);
+    let width = chars as f64 * CHAR_WIDTH + 2.0 * PADDING;
+
+    body.push_str(&format!(
+      r#"<text class="cfg-title" x="{x}" y="{}">{}</text>"#,
+      LINE_HEIGHT, side.title
+    ));
+
+    let mut tops = Vec::new();
+    let mut y = TITLE_HEIGHT;
+    for (index, text) in texts.iter().enumerate() {
+      let block_height = text.len() as f64 * LINE_HEIGHT + 2.0 * PADDING;
+      let state = side.states[index];
+      body.push_str(&format!(
+        r#"<rect class="cfg-block cfg-{}" x="{x}" y="{y}" width="{width}" height="{block_height}"><title>{}</title></rect>"#,
+        state.class(),
+        state.class()
+      ));
+      for (line_index, line) in text.iter().enumerate() {
+        body.push_str(&format!(
+          r#"<text class="cfg-text" x="{}" y="{}" xml:space="preserve">{}</text>"#,
+          x + PADDING,
+          y + PADDING + (line_index as f64 + 0.8) * LINE_HEIGHT,
+          html_escape(line)
+        ));
+      }
+      tops.push((y, y + block_height));
+      y += block_height + BLOCK_GAP;
+    }
+    height = height.max(y);
+
+    let mut lanes = 0;
+    for (index, block) in side.graph.blocks.iter().enumerate() {
+      for (target, kind) in edges(block) {
+        let (_, bottom) = tops[index];
+        let (top, _) = tops[target];
+        let path = if target == index + 1 {
+          let center = x + width / 2.0;
+          format!("M{center},{bottom} V{top}")
+        } else {
+          lanes += 1;
+          let right = x + width;
+          let lane = right + lanes as f64 * LANE_WIDTH;
+          format!("M{right},{} H{lane} V{} H{right}", bottom - PADDING, top + PADDING)
+        };
+        body.push_str(&format!(
+          r#"<path class="cfg-edge cfg-{}" d="{path}" marker-end="url(#cfg-arrow-{})"/>"#,
+          kind.class(),
+          kind.class()
+        ));
+      }
+    }
+
+    x += width + lanes as f64 * LANE_WIDTH + COLUMN_GAP;
+  }
+  let width = x - COLUMN_GAP;
+
+  let markers = [EdgeKind::Taken, EdgeKind::NotTaken, EdgeKind::Next]
+    .iter()
+    .map(|kind| {
+      format!(
+        r#"<marker id="cfg-arrow-{0}" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="6" markerHeight="6" orient="auto-start-reverse"><path class="cfg-arrow cfg-{0}" d="M0,0 L10,5 L0,10 z"/></marker>"#,
+        kind.class()
+      )
+    })
+    .collect::<String>();
+
+  format!(
+    r#"<svg xmlns="http://www.w3.org/2000/svg" class="cfg" width="{width}" height="{height}" viewBox="0 0 {width} {height}"><defs>{markers}</defs>{body}</svg>"#
+  )
+}
@@ -1,6 +1,5 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::path::PathBuf;
 
 use thiserror::Error;
@@ -8,7 +7,10 @@ use thiserror::Error;
 use self::GenerateFullCommandError::*;
 use super::comparer_config::*;
 use super::disasm::*;
+use super::output::{FunctionRecord, OutputOpts};
 use super::pdb::*;
+use super::pe::PeImage;
+use super::sizes::infer_size;
 
 #[derive(Debug)]
 pub struct GenerateFullCommandInfo {
@@ -16,6 +18,7 @@ pub struct GenerateFullCommandInfo {
   pub orig_file: bool,
   pub disasm_opts: super::DisasmOpts,
   pub truncate_to_original: bool,
+  pub output: OutputOpts,
 }
 
 #[derive(Debug, Error)]
@@ -29,8 +32,17 @@ pub enum GenerateFullCommandError {
   #[error("Zydis disassembly error: {0:#?}")]
   Disasm(#[from] super::disasm::DisasmError),
 
+  #[error("PE reading failed: {0}")]
+  PeImage(#[from] super::pe::PeError),
+
   #[error("Error: The function offset/size of {0} are outside of the bounds of the input file.")]
   FunctionDefSizeWrong(String),
+
+  #[error("Error: The address {1:#X} of '{0}' is outside of any raw section of the original binary.")]
+  AddressNotMapped(String, u64),
+
+  #[error("Failed to convert disassembly to UTF-8 string: {0}")]
+  FromUtf8(#[from] std::string::FromUtf8Error),
 }
 
 pub fn run(info: GenerateFullCommandInfo, cfg: &ComparerConfig) -> Result<(), GenerateFullCommandError> {
@@ -42,45 +54,68 @@ pub fn run(info: GenerateFullCommandInfo, cfg: &ComparerConfig) -> Result<(), Ge
 }
 
 fn generate_full_orig(info: GenerateFullCommandInfo, cfg: &ComparerConfig) -> Result<(), GenerateFullCommandError> {
-  let mut path = std::env::current_dir()?;
-  path.push("orig_full.asm");
-
   let bytes = std::fs::read(&info.file_path)?;
+  let image = PeImage::parse(&bytes)?;
 
-  let stdout = std::io::stdout();
-  let mut stdout_lock = stdout.lock();
-
+  let orig_globals = global_map(&cfg.global);
   let orig_fn_map = cfg
     .func
     .iter()
     .map(|func| (func.addr, func.clone()))
     .collect::<HashMap<_, _>>();
 
-  let mut writer = File::create(path).map(BufWriter::new)?;
+  let embed_asm = info.output.is_json() && info.output.is_stdout();
+  let mut writer = open_writer(&info.output, "orig", embed_asm)?;
 
   for func in &cfg.func {
-    let size = match func.size {
+    let size = match func.size.or_else(|| infer_size(&bytes, &image, cfg, func.addr)) {
       None => {
-        writeln!(
-          stdout_lock,
-          "Note: Skipping '{}' because no size was defined.",
+        info.output.note(format_args!(
+          "Skipping '{}' because no size was defined and none could be inferred.",
           func.name
-        )?;
+        ))?;
         continue;
       }
       Some(size) => size,
     };
 
-    write_function_head(&mut writer, size, func.name.as_ref())?;
-
-    let offset = (func.addr - cfg.address_offset) as usize;
+    let offset = cfg
+      .orig_file_offset(&image, func.addr)
+      .ok_or_else(|| AddressNotMapped(func.name.clone(), func.addr))? as usize;
     let offset_end = offset + size;
 
     let func_bytes = bytes
       .get(offset..offset_end)
       .ok_or_else(|| FunctionDefSizeWrong(func.name.clone()))?;
 
-    write_disasm(&mut writer, func_bytes, &info.disasm_opts, func.addr, &orig_fn_map)?;
+    let mut asm = Vec::new();
+    write_disasm(
+      &mut asm,
+      func_bytes,
+      &info.disasm_opts,
+      func.addr,
+      &orig_fn_map,
+      &orig_globals,
+    )?;
+    if let Some(writer) = &mut writer {
+      write_function_head(writer, size, func)?;
+      writer.write_all(&asm)?;
+    }
+
+    if info.output.is_json() {
+      info.output.record_function(FunctionRecord {
+        symbol: func.name.clone(),
+        orig_addr: Some(func.addr),
+        orig_size: Some(size),
+        status: func.status,
+        owner: func.owner.clone(),
+        orig_asm: embed_asm.then(|| String::from_utf8(asm)).transpose()?,
+        ..Default::default()
+      })?;
+    }
+  }
+  if let Some(writer) = &mut writer {
+    writer.flush()?;
   }
 
   Ok(())
@@ -90,37 +125,39 @@ fn generate_full_pdb(info: GenerateFullCommandInfo, cfg: &ComparerConfig) -> Res
   let mut pdb_path = info.file_path.clone();
   pdb_path.set_extension("pdb");
 
-  let mut pdb_funcs: HashMap<String, FunctionSymbol> = get_pdb_funcs(pdb_path)?;
+  let bytes = std::fs::read(&info.file_path)?;
+  let image = PeImage::parse(&bytes)?;
+
+  let mut pdb = open_pdb(&pdb_path)?;
+  let pdb_funcs = get_pdb_funcs(&mut pdb, &image)?;
+  let pdb_globals = global_map(&get_pdb_globals(&mut pdb, &image)?);
   let pdb_fn_map = pdb_funcs
-    .values()
+    .iter()
     .map(|func| func.as_function_definition_pair())
     .collect::<HashMap<_, _>>();
 
-  let mut path = std::env::current_dir()?;
-  path.push("compare_full.asm");
-
-  // println!("{}", path.to_str().unwrap());
+  let embed_asm = info.output.is_json() && info.output.is_stdout();
+  let mut writer = open_writer(&info.output, "compare", embed_asm)?;
 
-  let bytes = std::fs::read(&info.file_path)?;
-
-  let stdout = std::io::stdout();
-  let mut stdout_lock = stdout.lock();
+  for collision in pdb_funcs.collisions() {
+    info.output.warn(format_args!("{collision}, using the first one."))?;
+  }
 
-  let mut writer = File::create(path).map(BufWriter::new)?;
+  let mut unused_names = pdb_funcs.names().collect::<HashSet<_>>();
 
   for func in &cfg.func {
-    if let Some(pdb_func) = pdb_funcs.remove::<str>(&func.name) {
-      write_function_head(&mut writer, pdb_func.size, &func.name)?;
+    if let Some(pdb_func) = pdb_funcs.get(&func.name) {
+      unused_names.remove(&func.name);
 
       let offset = pdb_func.offset as usize;
       let size = if info.truncate_to_original {
         if let Some(size) = func.size {
           size
         } else {
-          println!(
-            "WARN: No size defined for the original function '{}', using the PDB function size instead.",
+          info.output.warn(format_args!(
+            "No size defined for the original function '{}', using the PDB function size instead.",
             func.name
-          );
+          ))?;
           pdb_func.size
         }
       } else {
@@ -132,36 +169,91 @@ fn generate_full_pdb(info: GenerateFullCommandInfo, cfg: &ComparerConfig) -> Res
         .get(offset..offset_end)
         .ok_or_else(|| FunctionDefSizeWrong(func.name.clone()))?;
 
+      let mut asm = Vec::new();
       write_disasm(
-        &mut writer,
+        &mut asm,
         func_bytes,
         &info.disasm_opts,
-        pdb_func.offset + PDB_SEGMENT_OFFSET,
+        pdb_func.addr,
         &pdb_fn_map,
+        &pdb_globals,
       )?;
+      if let Some(writer) = &mut writer {
+        write_function_head(writer, pdb_func.size, func)?;
+        writer.write_all(&asm)?;
+      }
+
+      if info.output.is_json() {
+        info.output.record_function(
+          FunctionRecord {
+            symbol: func.name.clone(),
+            signature: Some(pdb_func.signature.clone()),
+            file: Some(pdb_func.file.clone()),
+            orig_addr: Some(func.addr),
+            orig_size: func.size,
+            addr: Some(pdb_func.addr),
+            offset: Some(pdb_func.offset),
+            size: Some(pdb_func.size),
+            status: func.status,
+            owner: func.owner.clone(),
+            compare_asm: embed_asm.then(|| String::from_utf8(asm)).transpose()?,
+            ..Default::default()
+          }
+          .with_size_delta(),
+        )?;
+      }
     } else {
-      writeln!(stdout_lock, "WARN: Function '{}' was not found in the PDB.", func.name)?;
+      info
+        .output
+        .warn(format_args!("Function '{}' was not found in the PDB.", func.name))?;
     }
   }
-  for func in pdb_funcs {
-    writeln!(
-      stdout_lock,
-      "WARN: Function '{}' was not found in the config.",
-      func.1.name
-    )?;
+  for name in unused_names {
+    info
+      .output
+      .warn(format_args!("Function '{}' was not found in the config.", name))?;
+  }
+  if let Some(writer) = &mut writer {
+    writer.flush()?;
   }
-  writer.flush()?;
 
   Ok(())
 }
 
-fn write_function_head(writer: &mut impl Write, size: usize, name: &str) -> Result<(), GenerateFullCommandError> {
+/// Opens the output file, unless JSON records on stdout contain the disassembly instead.
+/// The listing counts as the symbol `full` for `--name-template`.
+fn open_writer(output: &OutputOpts, side: &str, embed_asm: bool) -> std::io::Result<Option<Box<dyn Write>>> {
+  if embed_asm {
+    return Ok(None);
+  }
+  output.open("{side}_full.asm", "full", side).map(Some)
+}
+
+fn write_function_head(
+  writer: &mut impl Write,
+  size: usize,
+  func: &FunctionDefinition,
+) -> Result<(), GenerateFullCommandError> {
   // (blank line)
   // ;
   // ; <function>
   // ; size: 0xDEADBEEF
+  // ; status: <status>, owner: <owner>
+  // ; notes: <notes>
   // ;
   // (blank line)
-  writeln!(writer, "\n;\n; {}\n; size: {:#X}\n;\n", name, size)?;
+  write!(writer, "\n;\n; {}\n; size: {:#X}\n", func.name, size)?;
+  match (func.status, &func.owner) {
+    (Some(status), Some(owner)) => writeln!(writer, "; status: {status}, owner: {owner}")?,
+    (Some(status), None) => writeln!(writer, "; status: {status}")?,
+    (None, Some(owner)) => writeln!(writer, "; owner: {owner}")?,
+    (None, None) => {}
+  }
+  if let Some(notes) = &func.notes {
+    for line in notes.lines() {
+      writeln!(writer, "; notes: {line}")?;
+    }
+  }
+  writeln!(writer, ";\n")?;
   Ok(())
 }
@@ -1,27 +1,43 @@
 use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 use std::{collections::HashMap, path::PathBuf};
 
 use chrono::Utc;
 use handlebars::Handlebars;
+use handlebars::html_escape;
 use itertools::Itertools;
 use serde::Serialize;
-use similar::Change;
-use similar::TextDiff;
 use thiserror::Error;
 
 use super::compare::get_pdb_fn_map;
+use super::output::{FunctionRecord, OutputOpts, sanitize_file_name};
 
 use self::GenerateReportError::*;
 use super::assets::*;
 use super::comparer_config::*;
 use super::disasm::*;
+use super::flow_graph::{BlockSummary, FlowComparison, compare_flow_graphs};
+use super::flow_render::render_svg;
+use super::insn_diff::{Change, Span, diff_instructions};
 use super::pdb::*;
+use super::pe::PeImage;
+use super::renaming::{RenameOpts, Renaming, find_renaming};
+use super::sizes::infer_size;
+
+/// Graphs of larger functions get too big to be of use on the page.
+const MAX_GRAPH_BLOCKS: usize = 200;
 
 #[derive(Debug)]
 pub struct GenerateReportCommandInfo {
   pub report_opts: GenerateReportOpts,
   pub disasm_opts: super::DisasmOpts,
+  pub output: OutputOpts,
   pub truncate_to_original: bool,
+  pub rename: RenameOpts,
+  pub match_blocks: bool,
+  /// Draw the basic blocks of functions that differ.
+  pub graphs: bool,
 }
 
 #[derive(Debug)]
@@ -42,9 +58,18 @@ pub enum GenerateReportError {
   #[error("Zydis disassembly engine error: {0:#?}")]
   Disasm(#[from] super::disasm::DisasmError),
 
+  #[error("PE reading failed: {0}")]
+  PeImage(#[from] super::pe::PeError),
+
   #[error("No size defined for the original function '{0}', but truncate_to_original was specified")]
   RequiredFunctionSizeNotFound(String),
 
+  #[error("The address {1:#X} of '{0}' is outside of any raw section of the original binary")]
+  AddressNotMapped(String, u64),
+
+  #[error("The function offset/size of {0} are outside of the bounds of the input file")]
+  FunctionDefSizeWrong(String),
+
   #[error("Failed to load web template: {0}")]
   Template(#[from] handlebars::TemplateError),
 
@@ -59,9 +84,12 @@ pub enum GenerateReportError {
 struct CompareResult {
   pub orig_asm: String,
   pub new_asm: String,
-  pub unified_diff: String,
   pub match_ratio: f32,
   pub diff_html: String,
+  pub renaming: Option<Renaming>,
+  pub blocks: Option<BlockSummary>,
+  /// The basic blocks of both functions as SVG, empty if one of them is missing.
+  pub graph_svg: String,
 }
 
 #[derive(Debug, Clone)]
@@ -69,9 +97,13 @@ struct DualFunctionReport {
   pub fn_name: String,
   pub file: PathBuf,
   pub new_addr: Option<u64>,
+  pub new_offset: Option<u64>,
   pub new_size: Option<usize>,
   pub orig_addr: Option<u64>,
   pub orig_size: Option<usize>,
+  pub status: Option<FunctionStatus>,
+  pub owner: Option<String>,
+  pub notes: Option<String>,
   pub compare_result: Option<CompareResult>,
 }
 
@@ -85,6 +117,8 @@ struct ReportListItem {
   match_percent: f32,
   matching: i32,
   total: i32,
+  status: String,
+  renaming: String,
 }
 
 #[derive(Serialize)]
@@ -111,6 +145,11 @@ struct ReportOverview {
   pub page_content_partial: String,
   pub index_items: Vec<ReportListItem>,
   pub diff_html: String,
+  pub status: String,
+  pub owner: String,
+  pub notes: String,
+  pub renaming: String,
+  pub graph_svg: String,
 }
 
 struct PathReport {
@@ -122,20 +161,23 @@ struct PathReport {
 }
 
 enum ReportNode {
-  Function(DualFunctionReport),
+  Function(Box<DualFunctionReport>),
   Path(PathReport),
 }
 
 struct OrigData {
   functions: HashMap<String, FunctionDefinition>,
   fn_map: HashMap<u64, FunctionDefinition>,
+  globals: GlobalMap,
   file: Vec<u8>,
-  base_address: u64,
+  image: PeImage,
 }
 
 struct PdbData {
-  functions: HashMap<String, FunctionSymbol>,
+  functions: PdbFunctions,
   fn_map: HashMap<u64, FunctionDefinition>,
+  globals: GlobalMap,
+  locals: HashMap<u64, Vec<StackVariable>>,
   file: Vec<u8>,
 }
 
@@ -154,16 +196,59 @@ pub fn run(info: &GenerateReportCommandInfo, cfg: &ComparerConfig) -> Result<(),
   register_template("webpage", &mut handlebars)?;
 
   let report_data = create_report_data(info, cfg)?;
+  if info.output.is_json() {
+    return print_report_records(info, &report_data);
+  }
+
   let report_node = structure_report_data(&report_data);
 
   // TODO
 
-  create_all_pages(&handlebars, &report_node)
+  if info.output.is_stdout() {
+    // only the overview of all functions makes sense in a pipeline
+    return render_page(&handlebars, &report_node, std::io::stdout().lock());
+  }
+
+  create_all_pages(&handlebars, &report_node, &info.output.dir_or("report")?)
+}
+
+fn print_report_records(
+  info: &GenerateReportCommandInfo,
+  report_data: &[DualFunctionReport],
+) -> Result<(), GenerateReportError> {
+  for report in report_data.iter().sorted_by(|a, b| a.fn_name.cmp(&b.fn_name)) {
+    info.output.record_function(
+      FunctionRecord {
+        symbol: report.fn_name.clone(),
+        file: report.new_addr.map(|_| report.file.to_string_lossy().into_owned()),
+        addr: report.new_addr,
+        orig_addr: report.orig_addr,
+        orig_size: report.orig_size,
+        offset: report.new_offset,
+        size: report.new_size,
+        match_ratio: report.compare_result.as_ref().map(|cmp| cmp.match_ratio),
+        register_renaming: report
+          .compare_result
+          .as_ref()
+          .and_then(|cmp| Some(cmp.renaming.as_ref()?.registers.clone())),
+        stack_slot_renaming: report
+          .compare_result
+          .as_ref()
+          .and_then(|cmp| Some(cmp.renaming.as_ref()?.stack_slots.clone())),
+        blocks: report.compare_result.as_ref().and_then(|cmp| cmp.blocks.clone()),
+        status: report.status,
+        owner: report.owner.clone(),
+        ..Default::default()
+      }
+      .with_size_delta(),
+    )?;
+  }
+  Ok(())
 }
 
-fn create_all_pages(handlebars: &Handlebars, root: &ReportNode) -> Result<(), GenerateReportError> {
-  std::fs::create_dir("report").ok();
-  let file = File::create("report/index.html")?;
+fn create_all_pages(handlebars: &Handlebars, root: &ReportNode, report_dir: &Path) -> Result<(), GenerateReportError> {
+  std::fs::create_dir_all(report_dir)?;
+  let file = File::create(report_dir.join("index.html"))?;
 
   // create the root page
   let report = ReportOverview {
@@ -186,23 +271,24 @@ fn create_all_pages(handlebars: &Handlebars, root: &ReportNode) -> Result<(), Ge
     page_content_partial: String::from("index_partial"),
     index_items: Vec::new(),
     diff_html: String::new(),
+    status: String::new(),
+    owner: String::new(),
+    notes: String::new(),
+    renaming: String::new(),
+    graph_svg: String::new(),
   };
 
   handlebars.render_to_write("webpage", &report, file)?;
 
-  create_pages(handlebars, root)
+  create_pages(handlebars, root, report_dir)
 }
 
 fn get_pathname(path: &str) -> String {
-  path
-    .chars()
-    .map(|c| if "<>:\"/\\|?*".find(c).is_some() { '_' } else { c })
-    .collect::<String>()
-    + ".html"
+  sanitize_file_name(path) + ".html"
 }
 
-fn get_report_pathname(path: &str) -> String {
-  "report/".to_string() + get_pathname(path).as_str()
+fn get_report_pathname(report_dir: &Path, path: &str) -> PathBuf {
+  report_dir.join(get_pathname(path))
 }
 
 fn create_index_list_item(node: &ReportNode) -> ReportListItem {
@@ -216,6 +302,13 @@ fn create_index_list_item(node: &ReportNode) -> ReportListItem {
       order_numdiff: 0,
       matching: function.compare_result.as_ref().map_or(0, |f| f.match_ratio as i32),
       total: 1,
+      status: match (function.status, &function.owner) {
+        (Some(status), Some(owner)) => format!("{status} ({owner})"),
+        (Some(status), None) => status.to_string(),
+        (None, Some(owner)) => format!("({owner})"),
+        (None, None) => String::new(),
+      },
+      renaming: renaming_kind(function),
     },
     ReportNode::Path(branch) => ReportListItem {
       htmlpath: get_pathname(&branch.path),
@@ -226,15 +319,30 @@ fn create_index_list_item(node: &ReportNode) -> ReportListItem {
       order_numdiff: 0,
       matching: branch.num_matching_fns,
       total: branch.total_fns,
+      status: String::new(),
+      renaming: String::new(),
     },
   }
 }
 
-fn create_pages(handlebars: &Handlebars, node: &ReportNode) -> Result<(), GenerateReportError> {
+fn create_pages(handlebars: &Handlebars, node: &ReportNode, report_dir: &Path) -> Result<(), GenerateReportError> {
+  let name = match node {
+    ReportNode::Function(function) => &function.fn_name,
+    ReportNode::Path(branch) => {
+      for node in branch.nodes.iter() {
+        create_pages(handlebars, node, report_dir)?;
+      }
+      &branch.path
+    }
+  };
+
+  let file = File::create(get_report_pathname(report_dir, name))?;
+  render_page(handlebars, node, file)
+}
+
+fn render_page(handlebars: &Handlebars, node: &ReportNode, writer: impl Write) -> Result<(), GenerateReportError> {
   match node {
     ReportNode::Function(function) => {
-      let file = File::create(get_report_pathname(&function.fn_name))?;
-
       // create function comparison page
       let report = ReportOverview {
         common: ReportCommonInfo {
@@ -262,16 +370,19 @@ fn create_pages(handlebars: &Handlebars, node: &ReportNode) -> Result<(), Genera
           .compare_result
           .as_ref()
           .map_or(String::new(), |cmp| cmp.diff_html.clone()),
+        status: function.status.map_or(String::new(), |status| status.to_string()),
+        owner: function.owner.clone().unwrap_or_default(),
+        notes: function.notes.clone().unwrap_or_default(),
+        renaming: renaming_text(function),
+        graph_svg: function
+          .compare_result
+          .as_ref()
+          .map_or(String::new(), |cmp| cmp.graph_svg.clone()),
       };
 
-      handlebars.render_to_write("webpage", &report, file)?;
+      handlebars.render_to_write("webpage", &report, writer)?;
     }
     ReportNode::Path(branch) => {
-      for node in branch.nodes.iter() {
-        create_pages(handlebars, node)?;
-      }
-
-      let file = File::create(get_report_pathname(&branch.path))?;
       let items = branch
         .nodes
         .iter()
@@ -300,15 +411,48 @@ fn create_pages(handlebars: &Handlebars, node: &ReportNode) -> Result<(), Genera
         page_content_partial: String::from("index_partial"),
         index_items: items,
         diff_html: String::new(),
+        status: String::new(),
+        owner: String::new(),
+        notes: String::new(),
+        renaming: String::new(),
+        graph_svg: String::new(),
       };
 
-      handlebars.render_to_write("webpage", &report, file)?;
+      handlebars.render_to_write("webpage", &report, writer)?;
     }
   }
 
   Ok(())
 }
 
+/// What a function is equivalent modulo, for the index.
+fn renaming_kind(function: &DualFunctionReport) -> String {
+  let Some(cmp) = &function.compare_result else {
+    return String::new();
+  };
+  match (&cmp.renaming, &cmp.blocks) {
+    (Some(renaming), _) => format!("equivalent modulo {}", renaming.kind()),
+    (None, Some(blocks)) if blocks.equivalent && cmp.orig_asm != cmp.new_asm => {
+      String::from("equivalent modulo block layout")
+    }
+    _ => String::new(),
+  }
+}
+
+/// What a function is equivalent modulo, with the renamed registers and stack slots, or how its blocks match up.
+fn renaming_text(function: &DualFunctionReport) -> String {
+  let Some(cmp) = &function.compare_result else {
+    return String::new();
+  };
+  match (&cmp.renaming, &cmp.blocks) {
+    (Some(renaming), _) => format!("Equivalent modulo {}: {renaming}", renaming.kind()),
+    (None, Some(_)) if cmp.orig_asm == cmp.new_asm => String::new(),
+    (None, Some(blocks)) if blocks.equivalent => format!("Equivalent modulo block layout: {blocks}"),
+    (None, Some(blocks)) => format!("Blocks: {blocks}"),
+    (None, None) => String::new(),
+  }
+}
+
 //fn get_path_grouping(f: &DualFunctionReport, prefix: &PathBuf) -> PathBuf {
 //  f.file.strip_prefix(prefix).unwrap_or(f.file.as_path()).to_path_buf()
 //}
@@ -331,7 +475,10 @@ fn structure_report_data(fns: &[DualFunctionReport]) -> ReportNode {
       .map(|f| f.compare_result.as_ref().map_or(0f32, |cmp| cmp.match_ratio))
       .sum::<f32>()
       / fns.len() as f32,
-    nodes: fns.iter().map(|f| ReportNode::Function(f.clone())).collect_vec(),
+    nodes: fns
+      .iter()
+      .map(|f| ReportNode::Function(Box::new(f.clone())))
+      .collect_vec(),
     total_fns: fns.len() as i32,
     num_matching_fns: fns
       .iter()
@@ -354,43 +501,67 @@ fn create_report_data(
     .map(|f| (f.addr, f.clone()))
     .collect::<HashMap<_, _>>();
 
-  let pdb_functions = get_pdb_funcs(&info.report_opts.compare_pdb_file)?;
+  let pdb_file = std::fs::read(&info.report_opts.compare_file_path)?;
+  let pdb_image = PeImage::parse(&pdb_file)?;
+  let mut pdb = open_pdb(&info.report_opts.compare_pdb_file)?;
+  let pdb_functions = get_pdb_funcs(&mut pdb, &pdb_image)?;
   let pdb_fn_map = get_pdb_fn_map(&pdb_functions);
+  let pdb_globals = global_map(&get_pdb_globals(&mut pdb, &pdb_image)?);
+  let pdb_locals = if info.rename.stack_slots {
+    get_pdb_locals(&mut pdb, &pdb_image)?
+  } else {
+    HashMap::new()
+  };
+
+  for collision in pdb_functions.collisions() {
+    info.output.warn(format_args!("{collision}, using the first one."))?;
+  }
 
+  let orig_file = std::fs::read(&info.report_opts.orig)?;
+  let orig_image = PeImage::parse(&orig_file)?;
   let orig = OrigData {
     functions: orig_functions,
     fn_map: orig_fn_map,
-    file: std::fs::read(&info.report_opts.orig)?,
-    base_address: cfg.address_offset,
+    globals: global_map(&cfg.global),
+    file: orig_file,
+    image: orig_image,
   };
 
   let pdb = PdbData {
     functions: pdb_functions,
     fn_map: pdb_fn_map,
-    file: std::fs::read(&info.report_opts.compare_file_path)?,
+    globals: pdb_globals,
+    locals: pdb_locals,
+    file: pdb_file,
   };
 
   Ok(
     orig
       .functions
       .keys()
-      .chain(pdb.functions.keys())
+      .chain(pdb.functions.names())
       .unique()
       .map(|fn_name| {
         let orig_fn = orig.functions.get(fn_name);
         let pdb_fn = pdb.functions.get(fn_name);
 
-        let compare_result = create_comparison_data(fn_name, &orig, &pdb, info)
-          .inspect_err(|e| eprintln!("{e}"))
+        let compare_result = create_comparison_data(fn_name, &orig, &pdb, info, cfg)
+          .inspect_err(|e| {
+            info.output.error(fn_name, e).ok();
+          })
           .ok();
 
         DualFunctionReport {
           fn_name: fn_name.clone(),
           file: pdb_fn.map_or(PathBuf::new(), |f| PathBuf::from(&f.file)),
-          new_addr: pdb_fn.map(|f| f.offset),
+          new_addr: pdb_fn.map(|f| f.addr),
+          new_offset: pdb_fn.map(|f| f.offset),
           new_size: pdb_fn.map(|f| f.size),
           orig_addr: orig_fn.map(|f| f.addr),
           orig_size: orig_fn.and_then(|f| f.size),
+          status: orig_fn.and_then(|f| f.status),
+          owner: orig_fn.and_then(|f| f.owner.clone()),
+          notes: orig_fn.and_then(|f| f.notes.clone()),
           compare_result,
         }
       })
@@ -398,71 +569,137 @@ fn create_report_data(
   )
 }
 
-fn create_change_line_html(change: Change<&str>) -> String {
-  match change.tag() {
-    similar::ChangeTag::Equal => format!(r#"<tr><td>{change}</td><td>{change}</td></tr>"#),
-    similar::ChangeTag::Delete => format!(r#"<tr><td class="code-delete">{change}</td><td></td></tr>"#),
-    similar::ChangeTag::Insert => format!(r#"<tr><td></td><td class="code-insert">{change}</td></tr>"#),
+fn create_change_line_html(change: &Change) -> String {
+  match change {
+    Change::Equal(orig, compare) => format!(
+      r#"<tr><td>{}</td><td>{}</td></tr>"#,
+      html_escape(&orig.text),
+      html_escape(&compare.text)
+    ),
+    Change::Delete(line) => format!(
+      r#"<tr><td class="code-delete">{}</td><td></td></tr>"#,
+      html_escape(&line.text)
+    ),
+    Change::Insert(line) => format!(
+      r#"<tr><td></td><td class="code-insert">{}</td></tr>"#,
+      html_escape(&line.text)
+    ),
+    Change::Replace {
+      orig_spans,
+      compare_spans,
+      kinds,
+      ..
+    } => format!(
+      r#"<tr title="{}"><td class="code-delete">{}</td><td class="code-insert">{}</td></tr>"#,
+      kinds.iter().join(", "),
+      create_spans_html(orig_spans),
+      create_spans_html(compare_spans)
+    ),
   }
 }
 
+/// Marks the differing tokens of an instruction.
+fn create_spans_html(spans: &[Span]) -> String {
+  spans
+    .iter()
+    .map(|span| match span.changed {
+      true => format!(r#"<span class="code-change">{}</span>"#, html_escape(&span.text)),
+      false => html_escape(&span.text),
+    })
+    .join("")
+}
+
 fn create_comparison_data(
   fn_name: &String,
   orig: &OrigData,
   pdb: &PdbData,
   info: &GenerateReportCommandInfo,
+  cfg: &ComparerConfig,
 ) -> Result<CompareResult, GenerateReportError> {
   let orig_fn = orig.functions.get(fn_name);
   let pdb_fn = pdb.functions.get(fn_name);
 
-  let orig_fn_asm = match orig_fn {
+  let mut orig_lines = match orig_fn {
     Some(f) => {
-      let offset = (f.addr - orig.base_address) as usize;
+      let offset = cfg
+        .orig_file_offset(&orig.image, f.addr)
+        .ok_or_else(|| AddressNotMapped(f.name.clone(), f.addr))? as usize;
       let virt_addr = f.addr;
 
       let orig_fn_size = f
         .size
+        .or_else(|| infer_size(&orig.file, &orig.image, cfg, f.addr))
         .or(pdb_fn.map(|f| f.size))
         .ok_or(RequiredFunctionSizeNotFound(String::from("No function size provided")))?;
 
-      let mut buf = Vec::new();
-      write_disasm(
-        &mut buf,
-        &orig.file[offset..offset + orig_fn_size],
-        &info.disasm_opts,
-        virt_addr,
-        &orig.fn_map,
-      )?;
-      String::from_utf8(buf)?
+      let bytes = orig
+        .file
+        .get(offset..offset + orig_fn_size)
+        .ok_or_else(|| FunctionDefSizeWrong(f.name.clone()))?;
+
+      disasm_lines(bytes, &info.disasm_opts, virt_addr, &orig.fn_map, &orig.globals)?
     }
-    None => String::from(""),
+    None => Vec::new(),
   };
 
-  let pdb_fn_asm = match pdb_fn {
+  let mut pdb_lines = match pdb_fn {
     Some(f) => {
       let offset = f.offset as usize;
-      let virt_addr = f.offset + PDB_SEGMENT_OFFSET;
-
-      let mut buf = Vec::new();
-      write_disasm(
-        &mut buf,
-        &pdb.file[offset..offset + f.size],
-        &info.disasm_opts,
-        virt_addr,
-        &pdb.fn_map,
-      )?;
-      String::from_utf8(buf)?
+      let virt_addr = f.addr;
+
+      let bytes = pdb
+        .file
+        .get(offset..offset + f.size)
+        .ok_or_else(|| FunctionDefSizeWrong(f.name.clone()))?;
+
+      disasm_lines(bytes, &info.disasm_opts, virt_addr, &pdb.fn_map, &pdb.globals)?
     }
-    None => String::from(""),
+    None => Vec::new(),
   };
 
-  let patch = TextDiff::from_lines(&orig_fn_asm, &pdb_fn_asm);
+  if info.disasm_opts.pair_refs {
+    pair_references(&mut orig_lines, &mut pdb_lines);
+  }
+
+  let mut buf = Vec::new();
+  write_lines(&mut buf, &orig_lines, &info.disasm_opts, &Annotations::new())?;
+  let orig_fn_asm = String::from_utf8(buf)?;
+
+  let mut buf = Vec::new();
+  write_lines(&mut buf, &pdb_lines, &info.disasm_opts, &Annotations::new())?;
+  let pdb_fn_asm = String::from_utf8(buf)?;
+
+  let diff = diff_instructions(&orig_lines, &pdb_lines);
+  let renaming = (info.rename.is_enabled() && orig_fn_asm != pdb_fn_asm)
+    .then(|| {
+      let locals = pdb_fn
+        .and_then(|f| pdb.locals.get(&f.addr))
+        .map_or(&[][..], Vec::as_slice);
+      find_renaming(&orig_lines, &pdb_lines, info.rename, locals)
+    })
+    .flatten();
+  let flow = (info.match_blocks || info.graphs).then(|| compare_flow_graphs(&orig_lines, &pdb_lines));
+  let blocks = flow.as_ref().filter(|_| info.match_blocks).map(FlowComparison::summary);
+  let graph_svg = match &flow {
+    Some(flow)
+      if info.graphs
+        && orig_fn_asm != pdb_fn_asm
+        && !orig_lines.is_empty()
+        && !pdb_lines.is_empty()
+        && flow.orig.blocks.len() + flow.compare.blocks.len() <= MAX_GRAPH_BLOCKS =>
+    {
+      render_svg(flow, &orig_lines, &pdb_lines)
+    }
+    _ => String::new(),
+  };
 
   Ok(CompareResult {
     orig_asm: orig_fn_asm.clone(),
     new_asm: pdb_fn_asm.clone(),
-    unified_diff: patch.unified_diff().to_string(),
-    match_ratio: patch.ratio(),
-    diff_html: patch.iter_all_changes().map(create_change_line_html).join("\n"),
+    match_ratio: diff.ratio,
+    diff_html: diff.changes.iter().map(create_change_line_html).join("\n"),
+    renaming,
+    blocks,
+    graph_svg,
   })
 }
@@ -0,0 +1,413 @@
+//! Diffing disassemblies instruction by instruction, down to the operand tokens that differ.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+use similar::{Algorithm, DiffOp, capture_diff_slices, get_diff_ratio};
+
+use super::disasm::{DisasmLine, Operand, OperandKind, tokenize};
+
+/// How an instruction differs from the one it's aligned with on the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DifferenceKind {
+  /// A different instruction altogether.
+  Opcode,
+  /// Operands of a different kind or size, e.g. an immediate instead of a register.
+  Operand,
+  Register,
+  Immediate,
+  Displacement,
+}
+
+impl Display for DifferenceKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      DifferenceKind::Opcode => "opcode",
+      DifferenceKind::Operand => "operand",
+      DifferenceKind::Register => "register",
+      DifferenceKind::Immediate => "immediate",
+      DifferenceKind::Displacement => "displacement",
+    })
+  }
+}
+
+/// A piece of an instruction's text, `changed` if it differs from the other side.
+#[derive(Debug, Clone)]
+pub struct Span {
+  pub text: String,
+  pub changed: bool,
+}
+
+pub enum Change<'a> {
+  Equal(&'a DisasmLine, &'a DisasmLine),
+  Delete(&'a DisasmLine),
+  Insert(&'a DisasmLine),
+  /// Instructions aligned with each other that differ.
+  Replace {
+    orig: &'a DisasmLine,
+    compare: &'a DisasmLine,
+    orig_spans: Vec<Span>,
+    compare_spans: Vec<Span>,
+    kinds: Vec<DifferenceKind>,
+  },
+}
+
+pub struct InstructionDiff<'a> {
+  pub changes: Vec<Change<'a>>,
+  /// The share of equal instructions, like [`similar::TextDiff::ratio`] for lines.
+  pub ratio: f32,
+}
+
+impl InstructionDiff<'_> {
+  /// How many aligned instructions differ in each way.
+  pub fn counts(&self) -> BTreeMap<DifferenceKind, usize> {
+    let mut counts = BTreeMap::new();
+    for change in &self.changes {
+      if let Change::Replace { kinds, .. } = change {
+        for &kind in kinds {
+          *counts.entry(kind).or_insert(0) += 1;
+        }
+      }
+    }
+    counts
+  }
+}
+
+/// Aligns the instructions of both sides like a line diff, then pairs up the replaced ones that share their
+/// mnemonic to classify how they differ.
+pub fn diff_instructions<'a>(orig: &'a [DisasmLine], compare: &'a [DisasmLine]) -> InstructionDiff<'a> {
+  let texts = |lines: &'a [DisasmLine]| lines.iter().map(|line| line.text.as_str()).collect::<Vec<_>>();
+  let ops = capture_diff_slices(Algorithm::Myers, &texts(orig), &texts(compare));
+  let ratio = get_diff_ratio(&ops, orig.len(), compare.len());
+
+  let mut changes = Vec::new();
+  for op in ops {
+    match op {
+      DiffOp::Equal {
+        old_index,
+        new_index,
+        len,
+      } => changes.extend(
+        orig[old_index..old_index + len]
+          .iter()
+          .zip(&compare[new_index..new_index + len])
+          .map(|(orig, compare)| Change::Equal(orig, compare)),
+      ),
+      DiffOp::Delete { old_index, old_len, .. } => {
+        changes.extend(orig[old_index..old_index + old_len].iter().map(Change::Delete))
+      }
+      DiffOp::Insert { new_index, new_len, .. } => {
+        changes.extend(compare[new_index..new_index + new_len].iter().map(Change::Insert))
+      }
+      DiffOp::Replace {
+        old_index,
+        old_len,
+        new_index,
+        new_len,
+      } => changes.extend(align(
+        &orig[old_index..old_index + old_len],
+        &compare[new_index..new_index + new_len],
+      )),
+    }
+  }
+
+  InstructionDiff { changes, ratio }
+}
+
+/// Pairs up a block of replaced instructions by their mnemonics, so that changed operands line up. What's
+/// left over is paired up in order as well, as differing opcodes.
+fn align<'a>(orig: &'a [DisasmLine], compare: &'a [DisasmLine]) -> Vec<Change<'a>> {
+  let mnemonics = |lines: &'a [DisasmLine]| lines.iter().map(|line| line.mnemonic.as_str()).collect::<Vec<_>>();
+
+  let mut changes = Vec::new();
+  for op in capture_diff_slices(Algorithm::Myers, &mnemonics(orig), &mnemonics(compare)) {
+    let (_, old_range, new_range) = op.as_tag_tuple();
+    let (orig_lines, compare_lines) = (&orig[old_range], &compare[new_range]);
+    let paired = orig_lines.len().min(compare_lines.len());
+
+    changes.extend(
+      orig_lines
+        .iter()
+        .zip(compare_lines)
+        .map(|(orig, compare)| compare_instructions(orig, compare)),
+    );
+    changes.extend(orig_lines[paired..].iter().map(Change::Delete));
+    changes.extend(compare_lines[paired..].iter().map(Change::Insert));
+  }
+  changes
+}
+
+fn compare_instructions<'a>(orig: &'a DisasmLine, compare: &'a DisasmLine) -> Change<'a> {
+  if orig.text == compare.text {
+    return Change::Equal(orig, compare);
+  }
+
+  let comparable = orig.mnemonic == compare.mnemonic
+    && orig.operands.len() == compare.operands.len()
+    && !orig.operands.is_empty()
+    && !compare.operands.is_empty();
+  if !comparable {
+    let (orig_spans, compare_spans, ..) = diff_tokens(&orig.text, &compare.text);
+    let kind = if orig.mnemonic == compare.mnemonic {
+      DifferenceKind::Operand
+    } else {
+      DifferenceKind::Opcode
+    };
+    return Change::Replace {
+      orig,
+      compare,
+      orig_spans,
+      compare_spans,
+      kinds: vec![kind],
+    };
+  }
+
+  let mut orig_spans = vec![unchanged(mnemonic_prefix(orig))];
+  let mut compare_spans = vec![unchanged(mnemonic_prefix(compare))];
+  let mut kinds = Vec::new();
+  for (index, (orig_op, compare_op)) in orig.operands.iter().zip(&compare.operands).enumerate() {
+    if index > 0 {
+      orig_spans.push(unchanged(", "));
+      compare_spans.push(unchanged(", "));
+    }
+
+    let (orig_op_spans, compare_op_spans, changed_tokens) = diff_tokens(&orig_op.text, &compare_op.text);
+    kinds.extend(classify(orig_op, compare_op, &changed_tokens));
+    orig_spans.extend(orig_op_spans);
+    compare_spans.extend(compare_op_spans);
+  }
+  kinds.sort();
+  kinds.dedup();
+
+  Change::Replace {
+    orig,
+    compare,
+    orig_spans,
+    compare_spans,
+    kinds,
+  }
+}
+
+/// The mnemonic and the whitespace up to the first operand.
+fn mnemonic_prefix(line: &DisasmLine) -> &str {
+  let operands_len = line.operands.iter().map(|op| op.text.len()).sum::<usize>() + (line.operands.len() - 1) * 2;
+  &line.text[..line.text.len() - operands_len]
+}
+
+fn classify(orig: &Operand, compare: &Operand, changed_tokens: &[&str]) -> Vec<DifferenceKind> {
+  if changed_tokens.is_empty() {
+    return Vec::new();
+  }
+
+  match (&orig.kind, &compare.kind) {
+    (OperandKind::Register, OperandKind::Register) => vec![DifferenceKind::Register],
+    (OperandKind::Immediate, OperandKind::Immediate) => vec![DifferenceKind::Immediate],
+    (
+      OperandKind::Memory {
+        size: orig_size,
+        registers: orig_registers,
+      },
+      OperandKind::Memory {
+        size: compare_size,
+        registers: compare_registers,
+      },
+    ) if orig_size == compare_size => {
+      let (registers, others): (Vec<&str>, Vec<&str>) = changed_tokens
+        .iter()
+        .partition(|token| orig_registers.contains(token) || compare_registers.contains(token));
+      let mut kinds = Vec::new();
+      if !registers.is_empty() {
+        kinds.push(DifferenceKind::Register);
+      }
+      if !others.is_empty() {
+        kinds.push(DifferenceKind::Displacement);
+      }
+      kinds
+    }
+    _ => vec![DifferenceKind::Operand],
+  }
+}
+
+fn unchanged(text: &str) -> Span {
+  Span {
+    text: text.to_string(),
+    changed: false,
+  }
+}
+
+/// Diffs two texts token by token, returning the spans of both sides and the tokens that differ.
+fn diff_tokens<'a>(orig: &'a str, compare: &'a str) -> (Vec<Span>, Vec<Span>, Vec<&'a str>) {
+  let orig_tokens = tokenize(orig);
+  let compare_tokens = tokenize(compare);
+
+  let mut orig_spans = Vec::new();
+  let mut compare_spans = Vec::new();
+  let mut changed_tokens = Vec::<&str>::new();
+  for op in capture_diff_slices(Algorithm::Myers, &orig_tokens, &compare_tokens) {
+    let (_, old_range, new_range) = op.as_tag_tuple();
+    let changed = !matches!(op, DiffOp::Equal { .. });
+    if changed {
+      changed_tokens.extend(
+        orig_tokens[old_range.clone()]
+          .iter()
+          .chain(&compare_tokens[new_range.clone()]),
+      );
+    }
+    push_span(&mut orig_spans, &orig_tokens[old_range].concat(), changed);
+    push_span(&mut compare_spans, &compare_tokens[new_range].concat(), changed);
+  }
+  changed_tokens.retain(|token| !token.trim().is_empty());
+
+  (orig_spans, compare_spans, changed_tokens)
+}
+
+fn push_span(spans: &mut Vec<Span>, text: &str, changed: bool) {
+  if text.is_empty() {
+    return;
+  }
+  match spans.last_mut() {
+    Some(last) if last.changed == changed => last.text.push_str(text),
+    _ => spans.push(Span {
+      text: text.to_string(),
+      changed,
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use itertools::Itertools;
+
+  use super::*;
+  use crate::disasm::{DisasmOpts, GlobalMap, disasm_lines};
+
+  fn lines(bytes: &[u8]) -> Vec<DisasmLine> {
+    let opts = DisasmOpts {
+      print_addresses: false,
+      show_mem_disp: true,
+      show_imms: true,
+      show_source: false,
+      pair_refs: false,
+    };
+    disasm_lines(bytes, &opts, 0x401000, &HashMap::new(), &GlobalMap::new()).unwrap()
+  }
+
+  /// The kinds of each replaced instruction, or what else each change is.
+  fn describe(diff: &InstructionDiff) -> Vec<String> {
+    diff
+      .changes
+      .iter()
+      .map(|change| match change {
+        Change::Equal(..) => "equal".to_string(),
+        Change::Delete(line) => format!("delete {}", line.text),
+        Change::Insert(line) => format!("insert {}", line.text),
+        Change::Replace { kinds, .. } => kinds.iter().join(", "),
+      })
+      .collect()
+  }
+
+  fn changed_text(spans: &[Span]) -> Vec<&str> {
+    spans
+      .iter()
+      .filter(|span| span.changed)
+      .map(|span| span.text.as_str())
+      .collect()
+  }
+
+  #[test]
+  fn classifies_operands() {
+    let orig = lines(&[
+      0x89, 0xD8, // mov eax, ebx
+      0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+      0x8B, 0x45, 0xF8, // mov eax, [ebp-8]
+      0x8B, 0x46, 0x04, // mov eax, [esi+4]
+      0x89, 0xD8, // mov eax, ebx
+      0x8A, 0x45, 0xF8, // mov al, [ebp-8]
+    ]);
+    let compare = lines(&[
+      0x89, 0xD9, // mov ecx, ebx
+      0xB8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+      0x8B, 0x45, 0xF4, // mov eax, [ebp-0Ch]
+      0x8B, 0x47, 0x04, // mov eax, [edi+4]
+      0xB8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+      0x8B, 0x45, 0xF0, // mov eax, [ebp-10h]
+    ]);
+    let diff = diff_instructions(&orig, &compare);
+    assert_eq!(
+      describe(&diff),
+      [
+        "register",
+        "immediate",
+        "displacement",
+        "register",
+        "operand",
+        "operand, register"
+      ]
+    );
+    assert_eq!(diff.ratio, 0.0);
+    assert_eq!(
+      diff.counts(),
+      BTreeMap::from([
+        (DifferenceKind::Operand, 2),
+        (DifferenceKind::Register, 3),
+        (DifferenceKind::Immediate, 1),
+        (DifferenceKind::Displacement, 1),
+      ])
+    );
+
+    let Change::Replace {
+      orig_spans,
+      compare_spans,
+      ..
+    } = &diff.changes[2]
+    else {
+      panic!("not replaced");
+    };
+    assert_eq!(changed_text(orig_spans), ["8h"]);
+    assert_eq!(changed_text(compare_spans), ["0Ch"]);
+  }
+
+  /// Instructions left over after pairing up the same mnemonics are paired up in order.
+  #[test]
+  fn aligns_by_mnemonics() {
+    let orig = lines(&[
+      0x50, // push eax
+      0x83, 0xC0, 0x01, // add eax, 1
+      0x89, 0xD8, // mov eax, ebx
+      0xC3, // ret
+    ]);
+    let compare = lines(&[
+      0x50, // push eax
+      0x90, // nop
+      0x83, 0xE8, 0x01, // sub eax, 1
+      0x89, 0xD9, // mov ecx, ebx
+      0x59, // pop ecx
+      0xC3, // ret
+    ]);
+    let diff = diff_instructions(&orig, &compare);
+    assert_eq!(
+      describe(&diff),
+      [
+        "equal",
+        "opcode",
+        "insert sub eax, 1h",
+        "register",
+        "insert pop ecx",
+        "equal"
+      ]
+    );
+  }
+
+  #[test]
+  fn equal_instructions_have_no_differences() {
+    let code = lines(&[0x89, 0xD8, 0xC3]);
+    let diff = diff_instructions(&code, &code);
+    assert_eq!(describe(&diff), ["equal", "equal"]);
+    assert_eq!(diff.ratio, 1.0);
+    assert!(diff.counts().is_empty());
+  }
+}
@@ -2,31 +2,64 @@ mod assets;
 mod cmdline;
 mod compare;
 mod comparer_config;
+mod config_command;
+mod coverage;
+mod demangle;
+mod diff;
 mod disasm;
+mod flow_graph;
+mod flow_render;
 mod generate_full;
 mod generate_report;
 mod hexformat;
+mod insn_diff;
+mod output;
 mod pdb;
+mod pe;
+mod registers;
+mod renaming;
+mod sizes;
+mod status;
+mod symbol_files;
+
+use std::path::PathBuf;
 
 pub use self::compare::{CompareCommandInfo, CompareOpts};
 use self::comparer_config::ComparerConfig;
+pub use self::config_command::ConfigCommand;
+pub use self::coverage::CoverageCommandInfo;
 pub use self::disasm::{DisasmError, DisasmOpts};
 pub use self::generate_full::GenerateFullCommandInfo;
 pub use self::generate_report::GenerateReportCommandInfo;
 pub use self::hexformat::CustomUpperHexFormat;
+pub use self::status::StatusCommandInfo;
+
+/// Options shared by all commands, needed before running them.
+pub struct GlobalOpts {
+  pub config_path: Option<PathBuf>,
+  pub verbose: bool,
+}
 
 pub enum Command {
   Compare(CompareCommandInfo),
   GenerateFull(GenerateFullCommandInfo),
   GenerateReport(GenerateReportCommandInfo),
+  Status(StatusCommandInfo),
+  Coverage(CoverageCommandInfo),
+  Config(ConfigCommand),
 }
 
 fn main() {
-  let command = cmdline::parse_cmdline();
-  let comparer_config = match ComparerConfig::read_default() {
-    Ok(cfg) => cfg,
+  let (global_opts, command) = cmdline::parse_cmdline();
+  let (comparer_config, config_path) = match ComparerConfig::read(global_opts.config_path.as_deref()) {
+    Ok((cfg, path)) => {
+      if global_opts.verbose {
+        eprintln!("Using config file {}", path.display());
+      }
+      (cfg, path)
+    }
     Err(e) => {
-      println!("Error reading the config file: {:#?}", e);
+      eprintln!("Error reading the config file: {e}");
       std::process::exit(1);
     }
   };
@@ -50,5 +83,23 @@ fn main() {
         std::process::exit(1);
       }
     }
+    Command::Status(info) => {
+      if let Err(e) = status::run(info, &comparer_config) {
+        eprintln!("{e}");
+        std::process::exit(1);
+      }
+    }
+    Command::Coverage(info) => {
+      if let Err(e) = coverage::run(info, &comparer_config) {
+        eprintln!("{e}");
+        std::process::exit(1);
+      }
+    }
+    Command::Config(command) => {
+      if let Err(e) = config_command::run(command, &comparer_config, &config_path) {
+        eprintln!("{e}");
+        std::process::exit(1);
+      }
+    }
   }
 }
@@ -0,0 +1,234 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::comparer_config::FunctionStatus;
+use crate::coverage::RegionKind;
+use crate::flow_graph::BlockSummary;
+use crate::registers::RegisterRenaming;
+use crate::renaming::StackSlotRenaming;
+
+/// Where a command writes its output files, and how it names them.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOpts {
+  /// The directory to write into, `None` being the command's default.
+  pub dir: Option<OutputDir>,
+  /// File name template, `{symbol}` and `{side}` (orig/compare) are replaced.
+  pub name_template: Option<String>,
+  pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+  #[default]
+  Text,
+  /// One JSON record per line on stdout.
+  Json,
+}
+
+/// A line of `--format json` output.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+  Function(Box<FunctionRecord>),
+  Warning {
+    message: String,
+  },
+  Note {
+    message: String,
+  },
+  Error {
+    symbol: String,
+    message: String,
+  },
+  /// Bytes of the original binary outside of the configured functions.
+  Region {
+    kind: RegionKind,
+    addr: u64,
+    size: usize,
+  },
+  /// A called address of the original binary that no configured function covers.
+  CallTarget {
+    addr: u64,
+    callers: Vec<String>,
+  },
+}
+
+/// A function of the original and/or the rebuilt binary. Addresses are virtual addresses,
+/// offsets are file offsets into the rebuilt binary.
+#[derive(Debug, Default, Serialize)]
+pub struct FunctionRecord {
+  pub symbol: String,
+  pub signature: Option<String>,
+  pub file: Option<String>,
+  pub orig_addr: Option<u64>,
+  pub orig_size: Option<usize>,
+  pub addr: Option<u64>,
+  pub offset: Option<u64>,
+  pub size: Option<usize>,
+  /// `size - orig_size`
+  pub size_delta: Option<i64>,
+  /// Changes since the previous build while watching.
+  pub offset_change: Option<i64>,
+  pub size_change: Option<i64>,
+  pub match_ratio: Option<f32>,
+  pub byte_identical: Option<bool>,
+  /// Set if the function only differs in a consistent renaming of its registers and/or stack slots.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub register_renaming: Option<RegisterRenaming>,
+  /// Set along with `register_renaming`, listing the stack variables that moved.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub stack_slot_renaming: Option<StackSlotRenaming>,
+  /// How the basic blocks of both functions match up, if they were matched.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub blocks: Option<BlockSummary>,
+  /// The basic blocks as Graphviz DOT, when writing the graph to stdout.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub graph: Option<String>,
+  pub status: Option<FunctionStatus>,
+  pub owner: Option<String>,
+  pub notes: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub orig_asm: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub compare_asm: Option<String>,
+}
+
+impl FunctionRecord {
+  pub fn with_size_delta(self) -> Self {
+    let size_delta = self
+      .orig_size
+      .zip(self.size)
+      .map(|(orig_size, size)| size as i64 - orig_size as i64);
+    Self { size_delta, ..self }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum OutputDir {
+  Path(PathBuf),
+  /// `-`, writing everything to stdout instead.
+  Stdout,
+}
+
+impl OutputDir {
+  pub fn parse(dir: &str) -> Self {
+    if dir == "-" {
+      OutputDir::Stdout
+    } else {
+      OutputDir::Path(PathBuf::from(dir))
+    }
+  }
+}
+
+impl OutputOpts {
+  pub fn is_stdout(&self) -> bool {
+    matches!(self.dir, Some(OutputDir::Stdout))
+  }
+
+  pub fn is_json(&self) -> bool {
+    self.format == OutputFormat::Json
+  }
+
+  /// The output directory, or `default_dir` relative to the current directory.
+  pub fn dir_or(&self, default_dir: &str) -> IoResult<PathBuf> {
+    match &self.dir {
+      Some(OutputDir::Path(dir)) => Ok(dir.clone()),
+      _ => Ok(std::env::current_dir()?.join(default_dir)),
+    }
+  }
+
+  pub fn file_name(&self, default_template: &str, symbol: &str, side: &str) -> String {
+    self
+      .name_template
+      .as_deref()
+      .unwrap_or(default_template)
+      .replace("{symbol}", &sanitize_file_name(symbol))
+      .replace("{side}", side)
+  }
+
+  /// Opens the output for a single file. On stdout, a header line with the file name
+  /// separates it from the previous one.
+  pub fn open(&self, default_template: &str, symbol: &str, side: &str) -> IoResult<Box<dyn Write>> {
+    let file_name = self.file_name(default_template, symbol, side);
+    if self.is_stdout() {
+      let mut stdout = std::io::stdout();
+      writeln!(stdout, "; ---- {} ----", file_name)?;
+      return Ok(Box::new(stdout));
+    }
+
+    let dir = self.dir_or("")?;
+    std::fs::create_dir_all(&dir)?;
+    let file = File::create(dir.join(file_name))?;
+    Ok(Box::new(BufWriter::new(file)))
+  }
+
+  /// Where progress and warnings go, keeping stdout free for the output itself when it's used for that.
+  pub fn messages(&self) -> Box<dyn Write> {
+    if self.is_stdout() || self.is_json() {
+      Box::new(std::io::stderr())
+    } else {
+      Box::new(std::io::stdout())
+    }
+  }
+
+  /// Prints a record as a single line of JSON.
+  pub fn record(&self, record: &Record) -> IoResult<()> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer(&mut stdout, record)?;
+    writeln!(stdout)
+  }
+
+  pub fn record_function(&self, record: FunctionRecord) -> IoResult<()> {
+    self.record(&Record::Function(Box::new(record)))
+  }
+
+  pub fn warn(&self, message: impl Display) -> IoResult<()> {
+    if self.is_json() {
+      self.record(&Record::Warning {
+        message: message.to_string(),
+      })
+    } else {
+      writeln!(self.messages(), "WARN: {message}")
+    }
+  }
+
+  pub fn note(&self, message: impl Display) -> IoResult<()> {
+    if self.is_json() {
+      self.record(&Record::Note {
+        message: message.to_string(),
+      })
+    } else {
+      writeln!(self.messages(), "Note: {message}")
+    }
+  }
+
+  /// Reports an error that only affects a single function, without stopping the command.
+  pub fn error(&self, symbol: &str, error: impl Display) -> IoResult<()> {
+    if self.is_json() {
+      self.record(&Record::Error {
+        symbol: symbol.to_string(),
+        message: error.to_string(),
+      })
+    } else {
+      writeln!(std::io::stderr(), "{symbol}: {error}")
+    }
+  }
+
+  pub fn write(&self, default_template: &str, symbol: &str, side: &str, contents: &str) -> IoResult<()> {
+    let mut writer = self.open(default_template, symbol, side)?;
+    writer.write_all(contents.as_bytes())?;
+    writer.flush()
+  }
+}
+
+/// Replaces characters that are not allowed in file names.
+pub fn sanitize_file_name(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| if "<>:\"/\\|?*".find(c).is_some() { '_' } else { c })
+    .collect()
+}
@@ -1,15 +1,15 @@
 use itertools::Itertools;
 use pdb::FallibleIterator;
 use pdb_addr2line::pdb;
-use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::fs::File;
 use std::path::Path;
 use thiserror::Error;
 
-use crate::comparer_config::FunctionDefinition;
-
-pub const PDB_SEGMENT_OFFSET: u64 = 0x0040_0C00;
+use crate::comparer_config::{FunctionDefinition, GlobalDefinition};
+use crate::demangle::{demangle, strip_arguments};
+use crate::pe::PeImage;
 
 #[derive(Error, Debug)]
 pub enum PdbError {
@@ -21,15 +21,58 @@ pub enum PdbError {
 
   #[error("failed to parse PDB data")]
   Pdb(#[from] pdb_addr2line::pdb::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum SymbolLookupError {
+  #[error("Could not find the symbol '{0}' in the PDB")]
+  NotFound(String),
 
-  #[error("regex error")]
-  Regex(#[from] regex::Error),
+  #[error("{0}, pick one with name@file.cpp or by its address")]
+  Ambiguous(SymbolCollision),
+}
+
+/// Several functions in the PDB sharing the same name, e.g. static functions
+/// in different translation units or overloads.
+#[derive(Clone, Debug)]
+pub struct SymbolCollision {
+  pub name: String,
+  pub symbols: Vec<FunctionSymbol>,
+}
+
+impl Display for SymbolCollision {
+  fn fmt(&self, f: &mut Formatter) -> FmtResult {
+    write!(f, "Symbol '{}' is defined {} times: ", self.name, self.symbols.len())?;
+    for (i, symbol) in self.symbols.iter().enumerate() {
+      if i > 0 {
+        write!(f, ", ")?;
+      }
+      write!(f, "'{}' in {} at {:#X}", symbol.signature, symbol.file, symbol.addr)?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+  pub file: String,
+  pub line: u32,
+}
+
+/// All functions of a PDB, keeping every function that shares its name with another one.
+#[derive(Clone, Debug, Default)]
+pub struct PdbFunctions {
+  by_name: HashMap<String, Vec<FunctionSymbol>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct FunctionSymbol {
+  /// Short lookup name, e.g. `Bar::foo`.
   pub name: String,
+  /// The full undecorated signature, e.g. `Bar::foo(int)`.
+  pub signature: String,
   pub file: String,
+  pub addr: u64,
   pub offset: u64,
   pub size: usize,
 }
@@ -37,9 +80,10 @@ pub struct FunctionSymbol {
 impl FunctionSymbol {
   pub fn as_function_definition(&self) -> FunctionDefinition {
     FunctionDefinition {
-      addr: self.offset + PDB_SEGMENT_OFFSET,
+      addr: self.addr,
       name: self.name.clone(),
       size: Some(self.size),
+      ..Default::default()
     }
   }
 
@@ -51,8 +95,14 @@ impl FunctionSymbol {
 
 fn to_function_symbol(
   context: &pdb_addr2line::Context,
+  image: &PeImage,
   data: pdb_addr2line::Function,
-) -> Result<FunctionSymbol, PdbError> {
+) -> Result<Option<FunctionSymbol>, PdbError> {
+  let rva = data.start_rva as u64;
+  let Some(offset) = image.rva_to_file_offset(rva) else {
+    return Ok(None);
+  };
+
   let filemap = context
     .find_frames(data.start_rva)?
     .map(|procedure_frames| {
@@ -64,60 +114,406 @@ fn to_function_symbol(
     })
     .unwrap_or_default();
 
-  Ok(FunctionSymbol {
-    name: data.name.unwrap(),
+  let (name, signature) = undecorate_function_name(&data.name.unwrap());
+
+  Ok(Some(FunctionSymbol {
+    name,
+    signature,
     file: filemap.first().map_or("UNKNOWN".to_string(), |s| s.to_string()),
-    offset: (data.start_rva - 0xC00) as u64,
+    addr: image.rva_to_va(rva),
+    offset,
     size: (data.end_rva.unwrap_or(data.start_rva) - data.start_rva) as usize,
-  })
+  }))
 }
 
-fn demangle_function_name(name: String) -> String {
-  Regex::new(r"[^@(]+")
-    .iter()
-    .flat_map(|re| re.find(&name))
-    .map(|found| found.as_str())
-    .collect()
+/// Splits a function name into its short lookup name and its full signature.
+/// Names of functions based on public symbols are still decorated, all others have
+/// already been undecorated while reading the PDB.
+fn undecorate_function_name(raw_name: &str) -> (String, String) {
+  match raw_name.starts_with('?').then(|| demangle(raw_name)).flatten() {
+    Some(demangled) => (demangled.name, demangled.signature),
+    None => (strip_arguments(raw_name).to_string(), raw_name.to_string()),
+  }
 }
 
-pub fn get_pdb_funcs(file: impl AsRef<Path>) -> Result<HashMap<String, FunctionSymbol>, PdbError> {
-  let file = File::open(file)?;
-  let mut pdb = pdb::PDB::open(file)?;
+/// A PDB opened once and read by all of the functions below.
+pub type PdbFile = pdb::PDB<'static, File>;
+
+pub fn open_pdb(file: impl AsRef<Path>) -> Result<PdbFile, PdbError> {
+  Ok(pdb::PDB::open(File::open(file)?)?)
+}
 
+pub fn get_pdb_funcs(pdb: &mut PdbFile, image: &PeImage) -> Result<PdbFunctions, PdbError> {
   let address_map = pdb.address_map()?;
-  let context_data = pdb_addr2line::ContextPdbData::try_from_pdb_ref(&mut pdb)?;
+  let context_data = pdb_addr2line::ContextPdbData::try_from_pdb_ref(pdb)?;
   let context = context_data.make_context()?;
 
-  let mut ret = HashMap::new();
+  let mut ret = PdbFunctions::default();
   for function in context.functions() {
-    let fun = to_function_symbol(&context, function)?;
-    let name = demangle_function_name(fun.name.clone());
-    ret.insert(name, fun);
+    let Some(fun) = to_function_symbol(&context, image, function)? else {
+      continue;
+    };
+    ret.insert(fun);
   }
 
   let symbol_table = pdb.global_symbols()?;
   let mut symbols = symbol_table.iter();
-  let re = regex::Regex::new(r"__imp__?@?\??([^@]+)@?.*")?;
   while let Some(symbol) = symbols.next()? {
     if let Ok(pdb::SymbolData::Public(data)) = symbol.parse() {
       let raw_name: String = data.name.to_string().into();
-      if raw_name.starts_with("__imp_") {
-        let address = data.offset.to_rva(&address_map).unwrap_or_default().0 as u64;
-        let offset = address - 0xC00;
-        let name = re.replace(&raw_name, "$1").to_string();
-
-        ret.insert(
-          name.clone(),
-          FunctionSymbol {
-            name,
-            offset,
-            size: 0,
-            file: String::new(),
-          },
+      if let Some(import_name) = raw_name.strip_prefix("__imp_") {
+        let rva = data.offset.to_rva(&address_map).unwrap_or_default().0 as u64;
+        let Some(offset) = image.rva_to_file_offset(rva) else {
+          continue;
+        };
+        let (name, signature) = demangle(import_name).map_or_else(
+          || (import_name.to_string(), import_name.to_string()),
+          |d| (d.name, d.signature),
         );
+
+        ret.insert(FunctionSymbol {
+          name,
+          signature,
+          addr: image.rva_to_va(rva),
+          offset,
+          size: 0,
+          file: String::new(),
+        });
       }
     }
   }
 
   Ok(ret)
 }
+
+/// Reads all global and static variables (`S_GDATA32`/`S_LDATA32`) of a PDB, sized by their type.
+pub fn get_pdb_globals(pdb: &mut PdbFile, image: &PeImage) -> Result<Vec<GlobalDefinition>, PdbError> {
+  let address_map = pdb.address_map()?;
+  let type_information = pdb.type_information()?;
+  let types = TypeSizes::read(&type_information)?;
+
+  let mut data_symbols = Vec::new();
+  let symbol_table = pdb.global_symbols()?;
+  let mut symbols = symbol_table.iter();
+  while let Some(symbol) = symbols.next()? {
+    if let Ok(pdb::SymbolData::Data(data)) = symbol.parse() {
+      data_symbols.push((data.name.to_string().into_owned(), data.offset, data.type_index));
+    }
+  }
+  // static variables are only part of their module
+  let debug_information = pdb.debug_information()?;
+  let mut modules = debug_information.modules()?;
+  while let Some(module) = modules.next()? {
+    let Some(module_info) = pdb.module_info(&module)? else {
+      continue;
+    };
+    let mut symbols = module_info.symbols()?;
+    while let Some(symbol) = symbols.next()? {
+      if let Ok(pdb::SymbolData::Data(data)) = symbol.parse() {
+        data_symbols.push((data.name.to_string().into_owned(), data.offset, data.type_index));
+      }
+    }
+  }
+
+  let mut globals = Vec::<GlobalDefinition>::new();
+  let mut addrs = HashSet::new();
+  for (raw_name, offset, type_index) in data_symbols {
+    let Some(rva) = offset.to_rva(&address_map) else {
+      continue;
+    };
+    let addr = image.rva_to_va(rva.0 as u64);
+    if !addrs.insert(addr) {
+      continue;
+    }
+
+    globals.push(GlobalDefinition {
+      name: undecorate_function_name(&raw_name).0,
+      addr,
+      size: types.size(type_index).map(|size| size as usize),
+    });
+  }
+
+  Ok(globals)
+}
+
+/// A local variable or argument of a function, addressed relative to `ebp` or `esp`.
+#[derive(Clone, Debug)]
+pub struct StackVariable {
+  pub name: String,
+  pub register: &'static str,
+  pub offset: i64,
+  pub size: Option<u64>,
+}
+
+/// CodeView numbers of the registers stack variables are relative to.
+const CV_REG_ESP: u16 = 21;
+const CV_REG_EBP: u16 = 22;
+
+/// `ebp`-relative variables, which the pdb crate doesn't parse.
+const S_BPREL32: u16 = 0x110B;
+
+/// Reads the stack variables (`S_BPREL32`/`S_REGREL32`) of all functions of a PDB, keyed by the function address.
+pub fn get_pdb_locals(pdb: &mut PdbFile, image: &PeImage) -> Result<HashMap<u64, Vec<StackVariable>>, PdbError> {
+  let address_map = pdb.address_map()?;
+  let type_information = pdb.type_information()?;
+  let types = TypeSizes::read(&type_information)?;
+  let debug_information = pdb.debug_information()?;
+  let mut locals = HashMap::<u64, Vec<StackVariable>>::new();
+  let mut modules = debug_information.modules()?;
+  while let Some(module) = modules.next()? {
+    let Some(module_info) = pdb.module_info(&module)? else {
+      continue;
+    };
+
+    let mut procedure = None;
+    let mut symbols = module_info.symbols()?;
+    while let Some(symbol) = symbols.next()? {
+      if let Ok(pdb::SymbolData::Procedure(data)) = symbol.parse() {
+        procedure = data
+          .offset
+          .to_rva(&address_map)
+          .map(|rva| (image.rva_to_va(rva.0 as u64), data.end));
+        continue;
+      }
+      let Some((addr, end)) = procedure else {
+        continue;
+      };
+      if symbol.index() >= end {
+        procedure = None;
+        continue;
+      }
+
+      let variable = match symbol.parse() {
+        Ok(pdb::SymbolData::RegisterRelative(data)) => {
+          let register = match data.register.0 {
+            CV_REG_EBP => "ebp",
+            CV_REG_ESP => "esp",
+            _ => continue,
+          };
+          StackVariable {
+            name: data.name.to_string().into_owned(),
+            register,
+            offset: data.offset as i64,
+            size: types.size(data.type_index),
+          }
+        }
+        _ if symbol.raw_kind() == S_BPREL32 => {
+          // kind, offset, type index, then the name
+          let bytes = symbol.raw_bytes();
+          let (Some(offset), Some(type_index)) = (bytes.get(2..6), bytes.get(6..10)) else {
+            continue;
+          };
+          let type_index = u32::from_le_bytes([type_index[0], type_index[1], type_index[2], type_index[3]]);
+          let name = bytes.get(10..).unwrap_or_default();
+          let name = name.split(|&byte| byte == 0).next().unwrap_or_default();
+          StackVariable {
+            name: String::from_utf8_lossy(name).into_owned(),
+            register: "ebp",
+            offset: i32::from_le_bytes([offset[0], offset[1], offset[2], offset[3]]) as i64,
+            size: types.size(pdb::TypeIndex(type_index)),
+          }
+        }
+        _ => continue,
+      };
+      locals.entry(addr).or_default().push(variable);
+    }
+  }
+
+  Ok(locals)
+}
+
+/// Looks up the size of types, e.g. of variables.
+struct TypeSizes<'t> {
+  finder: pdb::TypeFinder<'t>,
+  /// Forward references only name their class, the definition comes with the size.
+  class_sizes: HashMap<String, u64>,
+}
+
+impl<'t> TypeSizes<'t> {
+  fn read(type_information: &'t pdb::TypeInformation) -> Result<Self, PdbError> {
+    let mut finder = type_information.finder();
+    let mut class_sizes = HashMap::new();
+    let mut types = type_information.iter();
+    while let Some(item) = types.next()? {
+      finder.update(&types);
+      match item.parse() {
+        Ok(pdb::TypeData::Class(class)) if !class.properties.forward_reference() => {
+          class_sizes.insert(
+            class.unique_name.unwrap_or(class.name).to_string().into_owned(),
+            class.size,
+          );
+        }
+        Ok(pdb::TypeData::Union(union)) if !union.properties.forward_reference() => {
+          class_sizes.insert(
+            union.unique_name.unwrap_or(union.name).to_string().into_owned(),
+            union.size,
+          );
+        }
+        _ => {}
+      }
+    }
+    Ok(Self { finder, class_sizes })
+  }
+
+  fn size(&self, index: pdb::TypeIndex) -> Option<u64> {
+    type_size(&self.finder, &self.class_sizes, index)
+  }
+}
+
+fn type_size(finder: &pdb::TypeFinder, class_sizes: &HashMap<String, u64>, index: pdb::TypeIndex) -> Option<u64> {
+  match finder.find(index).ok()?.parse().ok()? {
+    pdb::TypeData::Primitive(primitive) if primitive.indirection.is_some() => Some(4),
+    pdb::TypeData::Primitive(primitive) => primitive_size(primitive.kind),
+    pdb::TypeData::Pointer(_) => Some(4),
+    pdb::TypeData::Modifier(modifier) => type_size(finder, class_sizes, modifier.underlying_type),
+    pdb::TypeData::Enumeration(enumeration) => type_size(finder, class_sizes, enumeration.underlying_type),
+    // the outermost dimension contains all inner ones
+    pdb::TypeData::Array(array) => array.dimensions.last().map(|&size| size as u64),
+    pdb::TypeData::Class(class) if class.properties.forward_reference() => class_sizes
+      .get(class.unique_name.unwrap_or(class.name).to_string().as_ref())
+      .copied(),
+    pdb::TypeData::Class(class) => Some(class.size),
+    pdb::TypeData::Union(union) if union.properties.forward_reference() => class_sizes
+      .get(union.unique_name.unwrap_or(union.name).to_string().as_ref())
+      .copied(),
+    pdb::TypeData::Union(union) => Some(union.size),
+    _ => None,
+  }
+}
+
+fn primitive_size(kind: pdb::PrimitiveKind) -> Option<u64> {
+  use pdb::PrimitiveKind::*;
+
+  match kind {
+    Char | UChar | RChar | Char8 | I8 | U8 | Bool8 => Some(1),
+    WChar | RChar16 | Short | UShort | I16 | U16 | F16 | Bool16 => Some(2),
+    RChar32 | Long | ULong | I32 | U32 | F32 | F32PP | Bool32 | HRESULT => Some(4),
+    Quad | UQuad | I64 | U64 | F64 | Bool64 | Complex32 => Some(8),
+    F80 => Some(10),
+    Octa | UOcta | I128 | U128 | F128 | Complex64 => Some(16),
+    _ => None,
+  }
+}
+
+impl PdbFunctions {
+  fn insert(&mut self, func: FunctionSymbol) {
+    let symbols = self.by_name.entry(func.name.clone()).or_default();
+    if !symbols.iter().any(|s| s.addr == func.addr) {
+      symbols.push(func);
+    }
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &String> {
+    self.by_name.keys()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &FunctionSymbol> {
+    self.by_name.values().flatten()
+  }
+
+  /// Returns the first function with the given short name.
+  pub fn get(&self, name: &str) -> Option<&FunctionSymbol> {
+    self.by_name.get(name).and_then(|symbols| symbols.first())
+  }
+
+  pub fn collisions(&self) -> Vec<SymbolCollision> {
+    self
+      .by_name
+      .iter()
+      .filter(|(_, symbols)| symbols.len() > 1)
+      .map(|(name, symbols)| SymbolCollision {
+        name: name.clone(),
+        symbols: symbols.clone(),
+      })
+      .sorted_by(|a, b| a.name.cmp(&b.name))
+      .collect()
+  }
+
+  /// Looks up a single function by its short name or full signature (`Bar::foo`),
+  /// by name and source file (`foo@file.cpp`) or by its address (`0x4A1230`).
+  pub fn find(&self, query: &str) -> Result<&FunctionSymbol, SymbolLookupError> {
+    let candidates = if let Some(addr) = parse_address(query) {
+      self.iter().filter(|func| func.addr == addr).collect_vec()
+    } else if let Some((name, file)) = query.rsplit_once('@') {
+      self
+        .find_by_name(name)
+        .into_iter()
+        .filter(|func| file_matches(&func.file, file))
+        .collect_vec()
+    } else {
+      self.find_by_name(query)
+    };
+
+    match candidates.as_slice() {
+      [] => Err(SymbolLookupError::NotFound(query.to_string())),
+      [func] => Ok(func),
+      _ => Err(SymbolLookupError::Ambiguous(SymbolCollision {
+        name: query.to_string(),
+        symbols: candidates.into_iter().cloned().collect(),
+      })),
+    }
+  }
+
+  /// Builds a query that keeps resolving to the given function after a rebuild,
+  /// unlike its address.
+  pub fn unique_query(&self, func: &FunctionSymbol) -> String {
+    [
+      func.name.clone(),
+      func.signature.clone(),
+      format!("{}@{}", func.name, func.file),
+      format!("{}@{}", func.signature, func.file),
+    ]
+    .into_iter()
+    .find(|query| self.find(query).is_ok())
+    .unwrap_or_else(|| format!("{:#X}", func.addr))
+  }
+
+  fn find_by_name(&self, name: &str) -> Vec<&FunctionSymbol> {
+    match self.by_name.get(name) {
+      Some(symbols) => symbols.iter().collect(),
+      None => self.iter().filter(|func| func.signature == name).collect(),
+    }
+  }
+}
+
+fn parse_address(query: &str) -> Option<u64> {
+  let hex = query.strip_prefix("0x").or_else(|| query.strip_prefix("0X"))?;
+  u64::from_str_radix(hex, 16).ok()
+}
+
+/// Matches a source file from the PDB against a file name given by the user,
+/// ignoring the directory it's in as well as case and slash direction.
+fn file_matches(pdb_file: &str, file: &str) -> bool {
+  let normalize = |path: &str| path.replace('\\', "/").to_lowercase();
+  let pdb_file = normalize(pdb_file);
+  let file = normalize(file);
+  pdb_file == file || pdb_file.ends_with(&format!("/{file}"))
+}
+
+/// Looks up the source location of each of the given addresses, using the innermost inlined frame.
+pub fn get_source_locations(
+  pdb: &mut PdbFile,
+  image: &PeImage,
+  addrs: &[u64],
+) -> Result<Vec<Option<SourceLocation>>, PdbError> {
+  let context_data = pdb_addr2line::ContextPdbData::try_from_pdb_ref(pdb)?;
+  let context = context_data.make_context()?;
+
+  addrs
+    .iter()
+    .map(|&addr| {
+      let Some(rva) = image.va_to_rva(addr) else {
+        return Ok(None);
+      };
+      let location = context.find_frames(rva as u32)?.and_then(|procedure_frames| {
+        procedure_frames.frames.into_iter().find_map(|frame| {
+          Some(SourceLocation {
+            file: frame.file?.into_owned(),
+            line: frame.line?,
+          })
+        })
+      });
+      Ok(location)
+    })
+    .collect()
+}
@@ -0,0 +1,102 @@
+use goblin::pe::PE;
+use goblin::pe::section_table::{IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PeError {
+  #[error("failed to parse PE file: {0}")]
+  Goblin(#[from] goblin::error::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+  pub name: String,
+  pub virtual_address: u64,
+  pub raw_offset: u64,
+  pub raw_size: u64,
+  pub executable: bool,
+}
+
+impl Section {
+  /// Only the part of the section backed by raw data can be mapped to the file.
+  fn contains_raw_rva(&self, rva: u64) -> bool {
+    rva >= self.virtual_address && rva < self.virtual_address + self.raw_size
+  }
+}
+
+/// Address translation between RVAs, virtual addresses and file offsets of a PE image,
+/// based on its section table.
+#[derive(Debug, Clone)]
+pub struct PeImage {
+  pub image_base: u64,
+  pub sections: Vec<Section>,
+}
+
+impl PeImage {
+  pub fn parse(bytes: &[u8]) -> Result<Self, PeError> {
+    let pe = PE::parse(bytes)?;
+
+    let sections = pe
+      .sections
+      .iter()
+      .map(|section| Section {
+        name: section.name().unwrap_or_default().to_string(),
+        virtual_address: section.virtual_address as u64,
+        raw_offset: section.pointer_to_raw_data as u64,
+        raw_size: section.size_of_raw_data as u64,
+        executable: section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0,
+      })
+      .collect();
+
+    Ok(PeImage {
+      image_base: pe.image_base,
+      sections,
+    })
+  }
+
+  pub fn rva_to_va(&self, rva: u64) -> u64 {
+    self.image_base + rva
+  }
+
+  pub fn va_to_rva(&self, va: u64) -> Option<u64> {
+    va.checked_sub(self.image_base)
+  }
+
+  pub fn rva_to_file_offset(&self, rva: u64) -> Option<u64> {
+    self
+      .sections
+      .iter()
+      .find(|s| s.contains_raw_rva(rva))
+      .map(|s| rva - s.virtual_address + s.raw_offset)
+  }
+
+  /// The file offset just past the raw data of the section containing the given file offset.
+  pub fn raw_section_end(&self, file_offset: u64) -> Option<u64> {
+    self
+      .sections
+      .iter()
+      .map(|s| (s.raw_offset, s.raw_offset + s.raw_size))
+      .find(|&(start, end)| file_offset >= start && file_offset < end)
+      .map(|(_, end)| end)
+  }
+
+  /// The 1-based index of the section containing the given address and the offset into it, as used in MAP files.
+  pub fn section_offset(&self, va: u64) -> Option<(usize, u64)> {
+    let rva = self.va_to_rva(va)?;
+    self
+      .sections
+      .iter()
+      .position(|s| s.contains_raw_rva(rva))
+      .map(|index| (index + 1, rva - self.sections[index].virtual_address))
+  }
+
+  pub fn is_executable(&self, va: u64) -> bool {
+    self
+      .va_to_rva(va)
+      .is_some_and(|rva| self.sections.iter().any(|s| s.executable && s.contains_raw_rva(rva)))
+  }
+
+  pub fn va_to_file_offset(&self, va: u64) -> Option<u64> {
+    self.rva_to_file_offset(self.va_to_rva(va)?)
+  }
+}
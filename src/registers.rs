@@ -0,0 +1,179 @@
+//! Comparing disassemblies that only differ in which registers the compiler allocated.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::hash::Hash;
+
+use serde::Serialize;
+
+use super::disasm::DisasmLine;
+
+/// General purpose registers as `(name, family, width)`, the family being the name of the full 32-bit
+/// register. High byte registers count as a width of their own.
+/// `esp` isn't one of them, every function uses it the same way.
+const REGISTERS: [(&str, &str, u8); 22] = [
+  ("eax", "eax", 32),
+  ("ax", "eax", 16),
+  ("al", "eax", 8),
+  ("ah", "eax", 9),
+  ("ebx", "ebx", 32),
+  ("bx", "ebx", 16),
+  ("bl", "ebx", 8),
+  ("bh", "ebx", 9),
+  ("ecx", "ecx", 32),
+  ("cx", "ecx", 16),
+  ("cl", "ecx", 8),
+  ("ch", "ecx", 9),
+  ("edx", "edx", 32),
+  ("dx", "edx", 16),
+  ("dl", "edx", 8),
+  ("dh", "edx", 9),
+  ("esi", "esi", 32),
+  ("si", "esi", 16),
+  ("edi", "edi", 32),
+  ("di", "edi", 16),
+  ("ebp", "ebp", 32),
+  ("bp", "ebp", 16),
+];
+
+/// Registers that `__thiscall` and `__fastcall` pass arguments in.
+const ARGUMENT_REGISTERS: [&str; 2] = ["ecx", "edx"];
+
+/// The register functions return their result in.
+const RETURN_REGISTER: &str = "eax";
+
+/// The registers of the original function mapped to the ones used in their place, by their 32-bit names.
+/// Registers used the same way on both sides are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct RegisterRenaming(pub BTreeMap<String, String>);
+
+impl Display for RegisterRenaming {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let pairs = self
+      .0
+      .iter()
+      .map(|(orig, compare)| format!("{orig} -> {compare}"))
+      .collect::<Vec<_>>();
+    f.write_str(&pairs.join(", "))
+  }
+}
+
+impl RegisterRenaming {
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+/// A one-to-one mapping built up while walking both disassemblies.
+pub struct Bijection<T> {
+  forward: HashMap<T, T>,
+  backward: HashMap<T, T>,
+}
+
+impl<T: Copy + Eq + Hash> Bijection<T> {
+  pub fn new() -> Self {
+    Self {
+      forward: HashMap::new(),
+      backward: HashMap::new(),
+    }
+  }
+
+  /// Adds the pair, returning whether it's consistent with all previous ones.
+  pub fn insert(&mut self, orig: T, compare: T) -> bool {
+    *self.forward.entry(orig).or_insert(compare) == compare && *self.backward.entry(compare).or_insert(orig) == orig
+  }
+
+  pub fn pairs(&self) -> impl Iterator<Item = (&T, &T)> {
+    self.forward.iter()
+  }
+
+  pub fn renamed(self) -> impl Iterator<Item = (T, T)> {
+    self.forward.into_iter().filter(|(orig, compare)| orig != compare)
+  }
+}
+
+/// Builds up a consistent renaming from the registers found at the same places of both disassemblies.
+///
+/// Each register of the original has to be replaced by the same register everywhere, and no two by the same
+/// one, while sub-registers like `al` and `ax` follow their full register.
+pub struct RegisterMatcher {
+  registers: HashMap<&'static str, (&'static str, u8)>,
+  map: Bijection<&'static str>,
+}
+
+impl RegisterMatcher {
+  pub fn new() -> Self {
+    Self {
+      registers: REGISTERS
+        .iter()
+        .map(|&(name, family, width)| (name, (family, width)))
+        .collect(),
+      map: Bijection::new(),
+    }
+  }
+
+  /// Keeps the registers that a function can't do without from being renamed, see [`pinned_registers`].
+  pub fn pin(&mut self, lines: &[DisasmLine]) -> bool {
+    pinned_registers(lines)
+      .into_iter()
+      .all(|register| self.map.insert(register, register))
+  }
+
+  pub fn is_register(&self, token: &str) -> bool {
+    self.registers.contains_key(token)
+  }
+
+  /// Adds a pair of registers, returning whether it's consistent with all previous ones.
+  pub fn insert(&mut self, orig: &str, compare: &str) -> bool {
+    match (self.registers.get(orig), self.registers.get(compare)) {
+      (Some(&(orig_family, orig_width)), Some(&(compare_family, compare_width))) => {
+        orig_width == compare_width && self.map.insert(orig_family, compare_family)
+      }
+      _ => false,
+    }
+  }
+
+  pub fn renaming(self) -> RegisterRenaming {
+    RegisterRenaming(
+      self
+        .map
+        .renamed()
+        .map(|(orig, compare)| (orig.to_string(), compare.to_string()))
+        .collect(),
+    )
+  }
+}
+
+/// The registers that renaming would change the behavior of a function for, even if the disassembly still looked
+/// consistent: those the instructions use implicitly, like `eax` and `edx` of `cdq` or `esi`, `edi` and `ecx` of
+/// `rep movsd`, the return value in `eax`, the argument registers the function reads before writing them and those
+/// it sets up before a call.
+fn pinned_registers(lines: &[DisasmLine]) -> HashSet<&'static str> {
+  let mut pinned = HashSet::new();
+  let mut written = HashSet::new();
+  // argument registers written since the last call, which may pass arguments to the next one
+  let mut outgoing = HashSet::new();
+  for line in lines {
+    for access in &line.registers {
+      let argument = access.read && ARGUMENT_REGISTERS.contains(&access.register) && !written.contains(access.register);
+      if access.fixed || argument {
+        pinned.insert(access.register);
+      }
+    }
+    for access in line.registers.iter().filter(|access| access.written) {
+      written.insert(access.register);
+      if ARGUMENT_REGISTERS.contains(&access.register) {
+        outgoing.insert(access.register);
+      }
+    }
+    match line.mnemonic.as_str() {
+      "ret" => {
+        pinned.insert(RETURN_REGISTER);
+      }
+      "call" => pinned.extend(outgoing.drain()),
+      _ => {}
+    }
+  }
+  pinned
+}
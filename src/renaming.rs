@@ -0,0 +1,592 @@
+//! Comparing disassemblies that only differ in which stack slots the compiler allocated, on top of the
+//! registers of [`super::registers`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use super::disasm::{DisasmLine, Flow, OperandKind, masm_hex, tokenize};
+use super::pdb::StackVariable;
+use super::registers::{Bijection, RegisterMatcher, RegisterRenaming};
+
+/// The register local variables and arguments are addressed relative to in functions with a frame pointer.
+const FRAME_REGISTER: &str = "ebp";
+
+/// The register they are addressed relative to otherwise, with displacements that change with every `push`.
+const STACK_REGISTER: &str = "esp";
+
+/// Which differences between the disassemblies are allowed, as long as they are consistent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenameOpts {
+  pub registers: bool,
+  pub stack_slots: bool,
+}
+
+impl RenameOpts {
+  pub fn is_enabled(&self) -> bool {
+    self.registers || self.stack_slots
+  }
+}
+
+/// How the rebuilt function differs from the original one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Renaming {
+  pub registers: RegisterRenaming,
+  pub stack_slots: StackSlotRenaming,
+}
+
+/// Stack variables that ended up in a different slot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct StackSlotRenaming(pub Vec<SlotMove>);
+
+/// A stack variable as addressed relative to `ebp`, or to `esp` as it is after the prologue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct StackSlot {
+  pub register: &'static str,
+  pub offset: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SlotMove {
+  pub orig: StackSlot,
+  pub compare: StackSlot,
+  /// The name of the local variable in the rebuilt function, if the PDB has it.
+  pub name: Option<String>,
+}
+
+impl Renaming {
+  /// What the functions are equivalent modulo.
+  pub fn kind(&self) -> &'static str {
+    match (self.registers.is_empty(), self.stack_slots.is_empty()) {
+      (false, false) => "registers and stack slots",
+      (true, false) => "stack slots",
+      _ => "registers",
+    }
+  }
+}
+
+impl Display for Renaming {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let parts = [self.registers.to_string(), self.stack_slots.to_string()];
+    f.write_str(&parts.iter().filter(|part| !part.is_empty()).join(", "))
+  }
+}
+
+impl StackSlotRenaming {
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+}
+
+impl Display for StackSlotRenaming {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let moves = self.0.iter().map(|slot| match &slot.name {
+      Some(name) => format!("[{}] -> [{}] ({name})", slot.orig, slot.compare),
+      None => format!("[{}] -> [{}]", slot.orig, slot.compare),
+    });
+    f.write_str(&moves.collect::<Vec<_>>().join(", "))
+  }
+}
+
+impl Display for StackSlot {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let sign = if self.offset < 0 { "-" } else { "+" };
+    write!(f, "{}{sign}{}", self.register, masm_hex(self.offset.unsigned_abs()))
+  }
+}
+
+/// Finds a consistent renaming of registers and/or stack slots that turns the original disassembly into the
+/// rebuilt one.
+///
+/// Both have to consist of the same instructions line by line, apart from what may be renamed. Each register
+/// of the original has to be replaced by the same register everywhere, and no two by the same one, while
+/// sub-registers like `al` and `ax` follow their full register. Registers that the instructions or the calling
+/// convention determine have to stay the same, see [`RegisterMatcher::pin`]. Likewise, each stack slot has to be
+/// replaced by the same one everywhere, see [`StackFrame`] for telling them apart. Arguments have to stay where
+/// they are. The `compare_locals` of the rebuilt function name the moved stack slots and keep variables in one
+/// piece, see [`slots_move_together`].
+///
+/// Returns `None` if there is no such renaming.
+pub fn find_renaming(
+  orig_lines: &[DisasmLine],
+  compare_lines: &[DisasmLine],
+  opts: RenameOpts,
+  compare_locals: &[StackVariable],
+) -> Option<Renaming> {
+  if orig_lines.len() != compare_lines.len() {
+    return None;
+  }
+
+  let mut register_map = RegisterMatcher::new();
+  if opts.registers && !(register_map.pin(orig_lines) && register_map.pin(compare_lines)) {
+    return None;
+  }
+  let mut slot_map = Bijection::new();
+  let mut address_taken = HashSet::new();
+  let orig_frame = StackFrame::new(orig_lines);
+  let compare_frame = StackFrame::new(compare_lines);
+  // `esp`-relative accesses where the depth of the stack isn't known, which have to match as they are
+  let mut untracked_esp = false;
+
+  for (line, (orig_line, compare_line)) in orig_lines.iter().zip(compare_lines).enumerate() {
+    let orig_tokens = significant_tokens(&orig_line.text);
+    let compare_tokens = significant_tokens(&compare_line.text);
+    if orig_tokens.len() != compare_tokens.len() {
+      return None;
+    }
+
+    for index in 0..orig_tokens.len() {
+      let (orig_token, compare_token) = (orig_tokens[index], compare_tokens[index]);
+
+      if opts.stack_slots {
+        let orig_displacement = frame_displacement(&orig_tokens, index);
+        let compare_displacement = frame_displacement(&compare_tokens, index);
+        untracked_esp |= [(orig_displacement, &orig_frame), (compare_displacement, &compare_frame)]
+          .iter()
+          .any(|(displacement, frame)| {
+            displacement.is_some_and(|(register, _)| register == STACK_REGISTER) && !frame.tracks(line)
+          });
+        match (
+          orig_frame.slot(line, orig_displacement),
+          compare_frame.slot(line, compare_displacement),
+        ) {
+          (Some(orig_slot), Some(compare_slot)) => {
+            if !slot_map.insert(orig_slot, compare_slot) {
+              return None;
+            }
+            if orig_line.mnemonic == "lea" {
+              address_taken.insert(orig_slot);
+            }
+            continue;
+          }
+          (None, None) => {}
+          _ => return None,
+        }
+      }
+
+      let registers = register_map.is_register(orig_token) && register_map.is_register(compare_token);
+      if registers && opts.registers {
+        if !register_map.insert(orig_token, compare_token) {
+          return None;
+        }
+      } else if orig_token != compare_token {
+        return None;
+      }
+    }
+  }
+
+  if !slots_move_together(&slot_map, &address_taken, compare_locals) {
+    return None;
+  }
+  // the untracked accesses may be to one of the moved slots
+  if untracked_esp
+    && slot_map
+      .pairs()
+      .any(|(orig, compare)| orig.register == STACK_REGISTER && orig != compare)
+  {
+    return None;
+  }
+
+  let mut stack_slots = slot_map
+    .renamed()
+    .map(|(orig, compare)| SlotMove {
+      orig,
+      compare,
+      name: containing_local(compare_locals, compare).map(|local| match compare.offset - local.offset {
+        0 => local.name.clone(),
+        offset => format!("{}+{}", local.name, masm_hex(offset as u64)),
+      }),
+    })
+    .collect::<Vec<_>>();
+  stack_slots.sort_by_key(|slot| slot.orig);
+
+  Some(Renaming {
+    registers: register_map.renaming(),
+    stack_slots: StackSlotRenaming(stack_slots),
+  })
+}
+
+/// Checks that stack slots only move in ways that keep the variables intact: all slots within one local variable of
+/// the rebuilt function have to move by the same distance, and a slot whose address is taken with `lea` may only
+/// move along with a variable of known size, as the code may reach its neighbours through the pointer.
+fn slots_move_together(
+  slot_map: &Bijection<StackSlot>,
+  address_taken: &HashSet<StackSlot>,
+  compare_locals: &[StackVariable],
+) -> bool {
+  let mut local_shifts = HashMap::new();
+  for (orig, compare) in slot_map.pairs() {
+    let shift = compare.offset - orig.offset;
+    let local = containing_local(compare_locals, *compare);
+    if shift != 0 && address_taken.contains(orig) && local.is_none_or(|local| local.size.is_none()) {
+      return false;
+    }
+    if let Some(local) = local
+      && *local_shifts.entry(local.offset).or_insert(shift) != shift
+    {
+      return false;
+    }
+  }
+  true
+}
+
+/// The local variable the slot is part of, only matching the start of variables of unknown size.
+fn containing_local(locals: &[StackVariable], slot: StackSlot) -> Option<&StackVariable> {
+  locals.iter().find(|local| {
+    let size = local.size.unwrap_or(1) as i64;
+    local.register == slot.register && (local.offset..local.offset + size).contains(&slot.offset)
+  })
+}
+
+/// The register and displacement if the token at `index` is the displacement of an `ebp`- or `esp`-based memory
+/// operand, like `14h` in `[ebp-14h]` or `[esp+eax*4+8h]`.
+fn frame_displacement(tokens: &[&str], index: usize) -> Option<(&'static str, i64)> {
+  let sign = tokens.get(index.checked_sub(1)?)?;
+  if !tokens.get(index + 1)?.starts_with(']') {
+    return None;
+  }
+
+  let start = tokens[..index].iter().rposition(|token| token.contains('['))?;
+  let register = [FRAME_REGISTER, STACK_REGISTER]
+    .into_iter()
+    .find(|&register| register == tokens[start + 1])?;
+  let value = parse_immediate(tokens[index])?;
+  match *sign {
+    "-" => Some((register, -value)),
+    "+" => Some((register, value)),
+    _ => None,
+  }
+}
+
+/// Parses a number as printed by the disassembler, e.g. `14h`.
+fn parse_immediate(text: &str) -> Option<i64> {
+  match text.strip_suffix('h') {
+    Some(hex) => i64::from_str_radix(hex, 16).ok(),
+    None => text.parse().ok(),
+  }
+}
+
+/// How deep `esp` is below the return address before each instruction of a function, to tell which stack slot an
+/// `esp`-relative displacement refers to.
+///
+/// Pushes, pops and `add`/`sub esp` by a constant are followed along the control flow. The depth is unknown after
+/// a call that may have popped arguments itself, as `__stdcall` functions do, i.e. one with pushes before it and no
+/// `add esp` after it, after other writes to `esp`, and in code only reached through jump tables. If paths meet
+/// with different depths, none is known at all.
+struct StackFrame {
+  depths: Vec<Option<i64>>,
+  /// The depth after the prologue, which `esp`-based slots are counted from.
+  prologue: i64,
+}
+
+impl StackFrame {
+  fn new(lines: &[DisasmLine]) -> Self {
+    let untracked = || StackFrame {
+      depths: vec![None; lines.len()],
+      prologue: 0,
+    };
+
+    let mut depths = Vec::with_capacity(lines.len());
+    // the depth with the bytes pushed since the last call, which it may take as arguments
+    let mut state = Some((0, 0));
+    let mut at_targets = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+      state = match (state, at_targets.get(&line.ip)) {
+        (Some((depth, _)), Some(&(target_depth, _))) if depth != target_depth => return untracked(),
+        (Some((depth, pushed)), Some(&(_, target_pushed))) => Some((depth, i64::max(pushed, target_pushed))),
+        (None, target) => target.copied(),
+        (state, None) => state,
+      };
+      depths.push(state.map(|(depth, _)| depth));
+
+      let next = state.and_then(|state| stack_effect(line, lines.get(index + 1), state));
+      if let (Flow::Jump(target) | Flow::Branch(target), Some(next)) = (line.flow, next) {
+        // loops jump back to instructions that already have a depth
+        let earlier = lines[..=index].iter().position(|line| line.ip == target);
+        if earlier.is_some_and(|earlier| depths[earlier].is_some_and(|depth| depth != next.0)) {
+          return untracked();
+        }
+        match at_targets.insert(target, next) {
+          Some((depth, _)) if depth != next.0 => return untracked(),
+          _ => {}
+        }
+      }
+      state = match line.flow {
+        Flow::Next | Flow::Branch(_) => next,
+        Flow::Jump(_) | Flow::Exit => None,
+      };
+    }
+
+    let prologue = lines
+      .iter()
+      .zip(&depths)
+      .find(|(line, _)| !is_prologue(line))
+      .and_then(|(_, depth)| *depth)
+      .unwrap_or(0);
+    StackFrame { depths, prologue }
+  }
+
+  fn tracks(&self, line: usize) -> bool {
+    self.depths[line].is_some()
+  }
+
+  /// The stack slot of a displacement at the given line, if it's one that may be renamed: below `ebp`, or below the
+  /// return address for `esp`. Positive offsets from `ebp` and anything above the return address are arguments.
+  fn slot(&self, line: usize, displacement: Option<(&'static str, i64)>) -> Option<StackSlot> {
+    let (register, offset) = displacement?;
+    let offset = match register {
+      STACK_REGISTER => {
+        let depth = self.depths[line]?;
+        (offset < depth).then_some(offset - depth + self.prologue)?
+      }
+      _ => (offset < 0).then_some(offset)?,
+    };
+    Some(StackSlot { register, offset })
+  }
+}
+
+/// The depth of the stack and the bytes pushed since the last call after an instruction, see [`StackFrame`].
+fn stack_effect(line: &DisasmLine, next: Option<&DisasmLine>, (depth, pushed): (i64, i64)) -> Option<(i64, i64)> {
+  let change = match line.mnemonic.as_str() {
+    "push" | "pushfd" => 4,
+    "pushad" => 32,
+    "pop" | "popfd" => -4,
+    "popad" => -32,
+    // the caller pops the arguments, or there are none
+    "call" if pushed == 0 || next.and_then(esp_adjustment).is_some_and(|change| change < 0) => {
+      return Some((depth, 0));
+    }
+    "call" => return None,
+    _ => match esp_adjustment(line) {
+      Some(change) => change,
+      None
+        if line
+          .registers
+          .iter()
+          .any(|access| access.register == STACK_REGISTER && access.written) =>
+      {
+        return None;
+      }
+      None => 0,
+    },
+  };
+  Some((depth + change, (pushed + change).max(0)))
+}
+
+/// How much `sub esp, n` and `add esp, n` grow the stack.
+fn esp_adjustment(line: &DisasmLine) -> Option<i64> {
+  let [register, value] = line.operands.as_slice() else {
+    return None;
+  };
+  if register.text != STACK_REGISTER {
+    return None;
+  }
+  let value = parse_immediate(&value.text)?;
+  match line.mnemonic.as_str() {
+    "sub" => Some(value),
+    "add" => Some(-value),
+    _ => None,
+  }
+}
+
+/// Saving registers, setting up `ebp` and making room for the locals.
+fn is_prologue(line: &DisasmLine) -> bool {
+  match line.mnemonic.as_str() {
+    "push" => line.operands.first().is_some_and(|op| op.kind == OperandKind::Register),
+    "mov" => line.text == "mov ebp, esp",
+    _ => esp_adjustment(line).is_some_and(|change| change > 0),
+  }
+}
+
+/// The tokens of an instruction without the whitespace between them.
+fn significant_tokens(text: &str) -> Vec<&str> {
+  tokenize(text)
+    .into_iter()
+    .filter(|token| !token.trim().is_empty())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::disasm::{DisasmOpts, GlobalMap, disasm_lines};
+
+  const REGISTERS: RenameOpts = RenameOpts {
+    registers: true,
+    stack_slots: false,
+  };
+
+  fn lines(bytes: &[u8]) -> Vec<DisasmLine> {
+    let opts = DisasmOpts {
+      print_addresses: false,
+      show_mem_disp: true,
+      show_imms: true,
+      show_source: false,
+      pair_refs: false,
+    };
+    disasm_lines(bytes, &opts, 0x401000, &HashMap::new(), &GlobalMap::new()).unwrap()
+  }
+
+  const STACK_SLOTS: RenameOpts = RenameOpts {
+    registers: false,
+    stack_slots: true,
+  };
+
+  fn stack_slots(orig: &[u8], compare: &[u8]) -> Option<String> {
+    let renaming = find_renaming(&lines(orig), &lines(compare), STACK_SLOTS, &[])?;
+    Some(renaming.stack_slots.to_string())
+  }
+
+  fn displacements(text: &str) -> Vec<(&'static str, i64)> {
+    let tokens = significant_tokens(text);
+    (0..tokens.len())
+      .filter_map(|index| frame_displacement(&tokens, index))
+      .collect()
+  }
+
+  fn registers(orig: &[u8], compare: &[u8]) -> Option<Vec<(String, String)>> {
+    let renaming = find_renaming(&lines(orig), &lines(compare), REGISTERS, &[])?;
+    Some(renaming.registers.0.into_iter().collect())
+  }
+
+  fn pair(orig: &str, compare: &str) -> (String, String) {
+    (String::from(orig), String::from(compare))
+  }
+
+  #[test]
+  fn swapped_registers() {
+    // mov esi, [ebp+8]; mov edi, [ebp+0Ch]; add esi, edi; mov eax, esi; ret
+    let orig = [0x8B, 0x75, 0x08, 0x8B, 0x7D, 0x0C, 0x03, 0xF7, 0x8B, 0xC6, 0xC3];
+    // mov edi, [ebp+8]; mov esi, [ebp+0Ch]; add edi, esi; mov eax, edi; ret
+    let compare = [0x8B, 0x7D, 0x08, 0x8B, 0x75, 0x0C, 0x03, 0xFE, 0x8B, 0xC7, 0xC3];
+    assert_eq!(
+      registers(&orig, &compare),
+      Some(vec![pair("edi", "esi"), pair("esi", "edi")])
+    );
+  }
+
+  #[test]
+  fn renaming_has_to_be_one_to_one() {
+    // mov esi, [ebp+8]; mov edi, [ebp+0Ch]; ret
+    let orig = [0x8B, 0x75, 0x08, 0x8B, 0x7D, 0x0C, 0xC3];
+    // mov ebx, [ebp+8]; mov ebx, [ebp+0Ch]; ret
+    let compare = [0x8B, 0x5D, 0x08, 0x8B, 0x5D, 0x0C, 0xC3];
+    assert_eq!(registers(&orig, &compare), None);
+    assert_eq!(registers(&compare, &orig), None);
+  }
+
+  #[test]
+  fn argument_registers_of_calls_are_pinned() {
+    // mov ecx, esi; call 402000h; ret
+    let orig = [0x8B, 0xCE, 0xE8, 0xF9, 0x0F, 0x00, 0x00, 0xC3];
+    // mov edx, esi; call 402000h; ret
+    let compare = [0x8B, 0xD6, 0xE8, 0xF9, 0x0F, 0x00, 0x00, 0xC3];
+    assert_eq!(registers(&orig, &compare), None);
+  }
+
+  #[test]
+  fn incoming_arguments_are_pinned() {
+    // mov eax, [ecx+4]; ret
+    let orig = [0x8B, 0x41, 0x04, 0xC3];
+    // mov eax, [edx+4]; ret
+    let compare = [0x8B, 0x42, 0x04, 0xC3];
+    assert_eq!(registers(&orig, &compare), None);
+  }
+
+  #[test]
+  fn frame_displacements() {
+    assert_eq!(displacements("mov eax, dword ptr [ebp-14h]"), [("ebp", -0x14)]);
+    assert_eq!(displacements("mov eax, dword ptr [ebp+8h]"), [("ebp", 8)]);
+    assert_eq!(displacements("lea ecx, dword ptr [esp+eax*4+10h]"), [("esp", 0x10)]);
+    assert_eq!(displacements("mov eax, dword ptr [ecx+8h]"), []);
+    assert_eq!(displacements("mov eax, dword ptr [0x410000]"), []);
+    assert_eq!(displacements("add esp, 8h"), []);
+  }
+
+  #[test]
+  fn arguments_are_not_stack_slots() {
+    let frame = StackFrame {
+      depths: vec![Some(8)],
+      prologue: 8,
+    };
+    let slot = |register, offset| Some(StackSlot { register, offset });
+    assert_eq!(frame.slot(0, Some(("ebp", -0x14))), slot("ebp", -0x14));
+    assert_eq!(frame.slot(0, Some(("ebp", 8))), None);
+    assert_eq!(frame.slot(0, Some(("esp", 4))), slot("esp", 4));
+    // the return address and arguments above it
+    assert_eq!(frame.slot(0, Some(("esp", 8))), None);
+    assert_eq!(frame.slot(0, Some(("esp", 0xC))), None);
+  }
+
+  #[test]
+  fn swapped_ebp_locals() {
+    // mov eax, [ebp-4]; mov ecx, [ebp-8]; add eax, ecx; ret
+    let orig = [0x8B, 0x45, 0xFC, 0x8B, 0x4D, 0xF8, 0x03, 0xC1, 0xC3];
+    // mov eax, [ebp-8]; mov ecx, [ebp-4]; add eax, ecx; ret
+    let compare = [0x8B, 0x45, 0xF8, 0x8B, 0x4D, 0xFC, 0x03, 0xC1, 0xC3];
+    assert_eq!(
+      stack_slots(&orig, &compare).as_deref(),
+      Some("[ebp-8h] -> [ebp-4h], [ebp-4h] -> [ebp-8h]")
+    );
+    // mov eax, [ebp-4]; mov ecx, [ebp-4]; add eax, ecx; ret
+    let merged = [0x8B, 0x45, 0xFC, 0x8B, 0x4D, 0xFC, 0x03, 0xC1, 0xC3];
+    assert_eq!(stack_slots(&orig, &merged), None);
+  }
+
+  #[test]
+  fn ebp_arguments_stay() {
+    // mov eax, [ebp+8]; ret
+    let orig = [0x8B, 0x45, 0x08, 0xC3];
+    // mov eax, [ebp+0Ch]; ret
+    let compare = [0x8B, 0x45, 0x0C, 0xC3];
+    assert_eq!(stack_slots(&orig, &compare), None);
+  }
+
+  #[test]
+  fn swapped_esp_locals_across_pushes() {
+    // sub esp, 0Ch; mov eax, [esp+4]; push eax; call $+5; add esp, 4; mov ecx, [esp+8]; add esp, 0Ch; ret
+    let orig = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x04, 0x50, 0xE8, 0x00, 0x00, 0x00, 0x00, 0x83, 0xC4, 0x04, 0x8B, 0x4C, 0x24,
+      0x08, 0x83, 0xC4, 0x0C, 0xC3,
+    ];
+    // the same with [esp+8] and [esp+4] swapped
+    let compare = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x08, 0x50, 0xE8, 0x00, 0x00, 0x00, 0x00, 0x83, 0xC4, 0x04, 0x8B, 0x4C, 0x24,
+      0x04, 0x83, 0xC4, 0x0C, 0xC3,
+    ];
+    assert_eq!(
+      stack_slots(&orig, &compare).as_deref(),
+      Some("[esp+4h] -> [esp+8h], [esp+8h] -> [esp+4h]")
+    );
+
+    // sub esp, 0Ch; mov eax, [esp+4]; push eax; mov ecx, [esp+0Ch]; add esp, 10h; ret
+    let orig = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x04, 0x50, 0x8B, 0x4C, 0x24, 0x0C, 0x83, 0xC4, 0x10, 0xC3,
+    ];
+    // the push moves the same slot from [esp+4] to [esp+8]
+    let compare = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x04, 0x50, 0x8B, 0x4C, 0x24, 0x08, 0x83, 0xC4, 0x10, 0xC3,
+    ];
+    assert_eq!(stack_slots(&orig, &compare), None);
+  }
+
+  #[test]
+  fn esp_is_lost_after_stdcall_calls() {
+    // sub esp, 0Ch; mov eax, [esp+4]; push eax; call $+5; mov ecx, [esp+8]; add esp, 0Ch; ret
+    let orig = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x04, 0x50, 0xE8, 0x00, 0x00, 0x00, 0x00, 0x8B, 0x4C, 0x24, 0x08, 0x83, 0xC4,
+      0x0C, 0xC3,
+    ];
+    // the callee pops the argument, so [esp+8] is the slot that moved to [esp+4]
+    let compare = [
+      0x83, 0xEC, 0x0C, 0x8B, 0x44, 0x24, 0x08, 0x50, 0xE8, 0x00, 0x00, 0x00, 0x00, 0x8B, 0x4C, 0x24, 0x08, 0x83, 0xC4,
+      0x0C, 0xC3,
+    ];
+    assert_eq!(stack_slots(&orig, &compare), None);
+  }
+}
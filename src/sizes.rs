@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+
+use zydis::ffi::DecodedOperandKind;
+use zydis::{Decoder, InstructionCategory, Mnemonic, VisibleOperands};
+
+use super::comparer_config::ComparerConfig;
+use super::pe::PeImage;
+
+const PADDING_ALIGNMENT: u64 = 16;
+/// Shortest run of padding bytes that isn't taken for data when it doesn't follow an instruction.
+const MIN_PADDING_RUN: usize = 4;
+
+/// Infers the size of a function of the original binary from its code.
+///
+/// Follows every branch inside the function until each path ends in a `ret` or `jmp`. A function
+/// never reaches past the next configured function or the end of its section. Jump tables can't be
+/// followed, so functions using them extend up to the alignment padding (0xCC/0x90) after them.
+pub fn infer_size(bytes: &[u8], image: &PeImage, cfg: &ComparerConfig, addr: u64) -> Option<usize> {
+  infer_function(bytes, image, cfg, addr).map(|function| function.size)
+}
+
+/// Follows the code of a function of the original binary like [`infer_size`], returning its calls as well.
+pub fn infer_function(bytes: &[u8], image: &PeImage, cfg: &ComparerConfig, addr: u64) -> Option<FunctionFlow> {
+  let start = cfg.orig_file_offset(image, addr)?;
+  let end = cfg
+    .func
+    .iter()
+    .filter_map(|func| cfg.orig_file_offset(image, func.addr))
+    .filter(|&offset| offset > start)
+    .chain(image.raw_section_end(start))
+    .min()
+    .unwrap_or(bytes.len() as u64)
+    .min(bytes.len() as u64);
+
+  let code = bytes.get(start as usize..end as usize)?;
+  follow_function(code, addr)
+}
+
+/// What following the branches of a function starting at the beginning of some code found.
+pub struct FunctionFlow {
+  pub size: usize,
+  /// Targets of direct calls, inside the function or not.
+  pub call_targets: Vec<u64>,
+}
+
+/// Like [`infer_size`], but for code that is already cut off at the furthest possible end of the function.
+pub fn follow_function(code: &[u8], addr: u64) -> Option<FunctionFlow> {
+  let flow = follow_branches(code, addr)?;
+  let size = if flow.indirect_jump {
+    padding_start(code, addr, flow.end)
+  } else {
+    flow.end
+  };
+
+  (size > 0).then_some(FunctionFlow {
+    size,
+    call_targets: flow.call_targets,
+  })
+}
+
+struct Flow {
+  /// End of the last reachable instruction.
+  end: usize,
+  /// Whether any path ends in a jump to a computed address, e.g. through a jump table.
+  indirect_jump: bool,
+  call_targets: Vec<u64>,
+}
+
+fn follow_branches(code: &[u8], addr: u64) -> Option<Flow> {
+  let decoder = Decoder::new32();
+  let target_offset = |target: u64| {
+    let offset = target.checked_sub(addr)? as usize;
+    (offset < code.len()).then_some(offset)
+  };
+
+  let mut flow = Flow {
+    end: 0,
+    indirect_jump: false,
+    call_targets: Vec::new(),
+  };
+  let mut visited = HashSet::new();
+  let mut pending = vec![0];
+
+  while let Some(mut pos) = pending.pop() {
+    while pos < code.len() && visited.insert(pos) {
+      let insn = decoder.decode_first::<VisibleOperands>(&code[pos..]).ok()??;
+      let ip = addr + pos as u64;
+      let next = pos + insn.length as usize;
+      flow.end = flow.end.max(next);
+
+      let target = insn.visible_operands().first().and_then(|op| match op.kind {
+        DecodedOperandKind::Imm(_) => insn.calc_absolute_address(ip, op).ok(),
+        _ => None,
+      });
+
+      match insn.meta.category {
+        InstructionCategory::RET => break,
+        InstructionCategory::INTERRUPT if insn.mnemonic == Mnemonic::INT3 => break,
+        InstructionCategory::UNCOND_BR => {
+          match target {
+            // a jump out of the function is a tail call
+            Some(target) => pending.extend(target_offset(target)),
+            None => flow.indirect_jump = true,
+          }
+          break;
+        }
+        InstructionCategory::COND_BR => pending.extend(target.and_then(target_offset)),
+        InstructionCategory::CALL => flow.call_targets.extend(target),
+        _ => {}
+      }
+      pos = next;
+    }
+  }
+
+  Some(flow)
+}
+
+/// Bytes the linker and compiler fill gaps between functions with.
+pub fn is_padding(byte: &u8) -> bool {
+  *byte == 0xCC || *byte == 0x90
+}
+
+/// Finds the first run of 0xCC or 0x90 bytes after `from` that pads up to an aligned address.
+///
+/// A run starting right after a `ret`, `jmp` or `int3` of a linear disassembly from `from` only needs to
+/// reach the alignment, while one inside undecodable bytes like a jump table needs at least
+/// [`MIN_PADDING_RUN`] bytes. A run starting on an aligned address has to fill a whole alignment unit.
+fn padding_start(code: &[u8], addr: u64, from: usize) -> usize {
+  let decoder = Decoder::new32();
+  let mut next_insn = from;
+  // the code up to `from` ends in the last instruction of a path through the function
+  let mut after_terminator = true;
+
+  for pos in from..code.len() {
+    let at_boundary = pos == next_insn && after_terminator;
+    if pos == next_insn {
+      match decoder.decode_first::<VisibleOperands>(&code[pos..]) {
+        Ok(Some(insn)) => {
+          after_terminator = matches!(
+            insn.meta.category,
+            InstructionCategory::RET | InstructionCategory::UNCOND_BR
+          ) || insn.mnemonic == Mnemonic::INT3;
+          next_insn = pos + insn.length as usize;
+        }
+        _ => {
+          after_terminator = false;
+          next_insn = pos + 1;
+        }
+      }
+    }
+
+    let aligned = (addr + pos as u64 + 1).next_multiple_of(PADDING_ALIGNMENT);
+    let run = &code[pos..((aligned - addr) as usize).min(code.len())];
+    let min_run = if at_boundary { 1 } else { MIN_PADDING_RUN };
+    if run.len() >= min_run && run.iter().all(is_padding) {
+      return pos;
+    }
+  }
+  code.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ADDR: u64 = 0x401000;
+
+  fn size(code: &[u8]) -> Option<usize> {
+    follow_function(code, ADDR).map(|function| function.size)
+  }
+
+  #[test]
+  fn ends_at_the_last_reachable_instruction() {
+    // mov eax, 1; ret
+    assert_eq!(size(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3, 0xCC, 0xCC]), Some(6));
+    // test eax, eax; jz +1; ret; xor eax, eax; ret
+    assert_eq!(size(&[0x85, 0xC0, 0x74, 0x01, 0xC3, 0x33, 0xC0, 0xC3, 0xCC]), Some(8));
+    // jmp to a function after this one is a tail call
+    assert_eq!(size(&[0xE9, 0x10, 0x00, 0x00, 0x00, 0xCC, 0xCC]), Some(5));
+  }
+
+  #[test]
+  fn collects_call_targets() {
+    // call 0x401010; ret
+    let function = follow_function(&[0xE8, 0x0B, 0x00, 0x00, 0x00, 0xC3], ADDR).unwrap();
+    assert_eq!(function.call_targets, vec![0x401010]);
+  }
+
+  #[test]
+  fn padding_after_ret() {
+    // mov eax, 0CCCCCCCCh; ret; int3...
+    let mut code = vec![0xB8, 0xCC, 0xCC, 0xCC, 0xCC, 0xC3];
+    code.resize(0x20, 0xCC);
+    assert_eq!(padding_start(&code, ADDR, 0), 6);
+  }
+
+  #[test]
+  fn int3_inside_an_instruction() {
+    // nop at 40100Ah, mov eax, 0CCCCCC11h ending at the aligned 401010h, ret, int3...
+    let mut code = vec![0x90, 0xB8, 0x11, 0xCC, 0xCC, 0xCC, 0xC3];
+    code.resize(0x10, 0xCC);
+    assert_eq!(padding_start(&code, 0x40100A, 1), 7);
+  }
+
+  #[test]
+  fn padding_after_a_jump_table() {
+    // jmp dword ptr [eax*4+401010h], then a jump table of two entries, then padding
+    let mut code = vec![0xFF, 0x24, 0x85, 0x10, 0x10, 0x40, 0x00, 0x00];
+    code.extend([0x20, 0x10, 0x40, 0x00, 0x24, 0x10, 0x40, 0x00]);
+    code.resize(0x20, 0xCC);
+    assert_eq!(size(&code), Some(0x10));
+  }
+}
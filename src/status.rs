@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use super::comparer_config::*;
+use super::output::{FunctionRecord, OutputOpts};
+
+#[derive(Debug)]
+pub struct StatusCommandInfo {
+  pub status: Option<FunctionStatus>,
+  pub owner: Option<String>,
+  pub output: OutputOpts,
+}
+
+#[derive(Debug, Error)]
+pub enum StatusCommandError {
+  #[error("IO error: {0}")]
+  Io(#[from] std::io::Error),
+}
+
+pub fn run(info: StatusCommandInfo, cfg: &ComparerConfig) -> Result<(), StatusCommandError> {
+  // functions without a status haven't been started yet
+  let status_of = |func: &FunctionDefinition| func.status.unwrap_or(FunctionStatus::Todo);
+
+  let functions = cfg
+    .func
+    .iter()
+    .filter(|func| info.status.is_none_or(|status| status_of(func) == status))
+    .filter(|func| info.owner.is_none() || func.owner == info.owner)
+    .collect_vec();
+
+  if info.output.is_json() {
+    for func in functions {
+      info.output.record_function(FunctionRecord {
+        symbol: func.name.clone(),
+        orig_addr: Some(func.addr),
+        orig_size: func.size,
+        status: Some(status_of(func)),
+        owner: func.owner.clone(),
+        notes: func.notes.clone(),
+        ..Default::default()
+      })?;
+    }
+    return Ok(());
+  }
+
+  let stdout = std::io::stdout();
+  let mut stdout_lock = stdout.lock();
+
+  let width = functions.iter().map(|func| func.name.len()).max().unwrap_or(0).max(8);
+  writeln!(
+    stdout_lock,
+    "{:<width$}  {:>10}  {:>8}  {:<11}  {:<12}  Notes",
+    "Function", "Address", "Size", "Status", "Owner"
+  )?;
+  for func in &functions {
+    let line = format!(
+      "{:<width$}  {:>10}  {:>8}  {:<11}  {:<12}  {}",
+      func.name,
+      format!("{:#X}", func.addr),
+      func.size.map_or_else(|| String::from("-"), |size| format!("{size:#X}")),
+      status_of(func).to_string(),
+      func.owner.as_deref().unwrap_or("-"),
+      func
+        .notes
+        .as_deref()
+        .and_then(|notes| notes.lines().next())
+        .unwrap_or(""),
+    );
+    writeln!(stdout_lock, "{}", line.trim_end())?;
+  }
+
+  let counts = functions.iter().counts_by(|func| status_of(func));
+  let counts = counts.into_iter().collect::<BTreeMap<_, _>>();
+  writeln!(stdout_lock)?;
+  for (status, count) in counts {
+    writeln!(
+      stdout_lock,
+      "{:<11}  {:>5}  {:>6.2} %",
+      status.to_string(),
+      count,
+      count as f32 * 100.0 / functions.len() as f32
+    )?;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,574 @@
+//! Symbol lists of the original binary as written by linkers, disassemblers and spreadsheets.
+
+use std::io::{Result as IoResult, Write};
+use std::path::Path;
+
+use regex::Regex;
+use serde::Serialize;
+
+use super::demangle::demangle;
+use super::pe::PeImage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolEntry {
+  pub name: String,
+  pub addr: u64,
+  pub size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFileFormat {
+  /// A MAP file of the MSVC linker or IDA.
+  Map,
+  /// An IDC script exported by IDA.
+  Idc,
+  /// The symbol table exported by Ghidra as CSV.
+  GhidraCsv,
+  /// `name,addr,size` lines.
+  Csv,
+}
+
+impl SymbolFileFormat {
+  pub fn detect(path: &Path, contents: &str) -> Option<Self> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+      "map" => Some(SymbolFileFormat::Map),
+      "idc" => Some(SymbolFileFormat::Idc),
+      "csv"
+        if contents
+          .lines()
+          .next()
+          .is_some_and(|header| header.contains("Location")) =>
+      {
+        Some(SymbolFileFormat::GhidraCsv)
+      }
+      "csv" => Some(SymbolFileFormat::Csv),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolExportFormat {
+  /// An IDC script for IDA.
+  Idc,
+  /// A Python script for Ghidra's Script Manager.
+  Ghidra,
+  /// An x64dbg database.
+  X64dbg,
+  /// A MAP file in the format of the MSVC linker.
+  Map,
+}
+
+impl SymbolExportFormat {
+  /// The default file name template, `{symbol}` being the name of the binary.
+  pub fn file_template(self) -> &'static str {
+    match self {
+      SymbolExportFormat::Idc => "{symbol}.idc",
+      SymbolExportFormat::Ghidra => "{symbol}_ghidra.py",
+      SymbolExportFormat::X64dbg => "{symbol}.dd32",
+      SymbolExportFormat::Map => "{symbol}.map",
+    }
+  }
+}
+
+/// Reads all functions from a symbol list. Lines that don't describe a function are skipped.
+pub fn parse_symbols(contents: &str, format: SymbolFileFormat, image: &PeImage) -> Vec<SymbolEntry> {
+  match format {
+    SymbolFileFormat::Map => parse_map(contents, image),
+    SymbolFileFormat::Idc => parse_idc(contents),
+    SymbolFileFormat::GhidraCsv => parse_ghidra_csv(contents),
+    SymbolFileFormat::Csv => parse_csv(contents),
+  }
+}
+
+/// Parses the publics of a MAP file, e.g. ` 0001:00000230  ?foo@@YAXXZ  00401230 f  foo.obj`.
+/// MSVC writes the virtual address and marks functions with `f`, IDA only writes the section
+/// and offset.
+fn parse_map(contents: &str, image: &PeImage) -> Vec<SymbolEntry> {
+  contents
+    .lines()
+    .filter_map(|line| {
+      let mut fields = line.split_whitespace();
+      let (section, offset) = fields.next()?.split_once(':')?;
+      let section = usize::from_str_radix(section, 16).ok()?;
+      let offset = u64::from_str_radix(offset, 16).ok()?;
+      let name = fields.next()?;
+
+      let addr = match fields.next() {
+        Some(addr) => {
+          if fields.next() != Some("f") {
+            return None;
+          }
+          // absolute symbols have no address
+          u64::from_str_radix(addr, 16).ok().filter(|&addr| addr != 0)?
+        }
+        None => image.rva_to_va(image.sections.get(section.checked_sub(1)?)?.virtual_address + offset),
+      };
+
+      // the linker still has the C decorations, unlike IDA
+      let name = demangle(name).map_or_else(|| name.to_string(), |demangled| demangled.name);
+      Some(SymbolEntry { name, addr, size: None })
+    })
+    .filter(|symbol| !is_generated_name(&symbol.name))
+    .collect()
+}
+
+/// Parses `MakeName`/`set_name` and `MakeFunction`/`add_func` calls of an IDC script.
+fn parse_idc(contents: &str) -> Vec<SymbolEntry> {
+  let name_regex =
+    Regex::new(r#"(?:MakeNameEx|MakeName|set_name)\s*\(\s*(0[xX][0-9a-fA-F]+)\s*,\s*"([^"]+)""#).unwrap();
+  let function_regex =
+    Regex::new(r"(?:MakeFunction|add_func)\s*\(\s*(0[xX][0-9a-fA-F]+)\s*,\s*(0[xX][0-9a-fA-F]+)").unwrap();
+
+  let mut symbols = name_regex
+    .captures_iter(contents)
+    .filter_map(|captures| {
+      Some(SymbolEntry {
+        name: undecorate(&captures[2]),
+        addr: parse_number(&captures[1])?,
+        size: None,
+      })
+    })
+    .filter(|symbol| !is_generated_name(&symbol.name))
+    .collect::<Vec<_>>();
+
+  for captures in function_regex.captures_iter(contents) {
+    let (Some(start), Some(end)) = (parse_number(&captures[1]), parse_number(&captures[2])) else {
+      continue;
+    };
+    for symbol in symbols.iter_mut().filter(|symbol| symbol.addr == start) {
+      symbol.size = end.checked_sub(start).map(|size| size as usize);
+    }
+  }
+
+  symbols
+}
+
+/// Parses the functions of a Ghidra symbol table, keeping their namespace.
+fn parse_ghidra_csv(contents: &str) -> Vec<SymbolEntry> {
+  let mut lines = contents.lines();
+  let Some(header) = lines.next().map(split_csv_line) else {
+    return Vec::new();
+  };
+  let column = |name: &str| header.iter().position(|column| column == name);
+  let (Some(name_column), Some(location_column)) = (column("Name"), column("Location")) else {
+    return Vec::new();
+  };
+  let type_column = column("Type");
+  let namespace_column = column("Namespace");
+
+  lines
+    .map(split_csv_line)
+    .filter(|fields| type_column.is_none_or(|column| fields.get(column).is_some_and(|ty| ty == "Function")))
+    .filter_map(|fields| {
+      let name = fields.get(name_column)?;
+      let location = fields.get(location_column)?;
+      let location = location.rsplit(':').next()?;
+      let name = match namespace_column.and_then(|column| fields.get(column)) {
+        Some(namespace) if !namespace.is_empty() && namespace != "Global" => format!("{namespace}::{name}"),
+        _ => undecorate(name),
+      };
+
+      Some(SymbolEntry {
+        name,
+        addr: u64::from_str_radix(location.trim_start_matches("0x"), 16).ok()?,
+        size: None,
+      })
+    })
+    .filter(|symbol| !is_generated_name(&symbol.name))
+    .collect()
+}
+
+/// Parses `name,addr,size` lines, with an optional size and header.
+fn parse_csv(contents: &str) -> Vec<SymbolEntry> {
+  contents
+    .lines()
+    .map(split_csv_line)
+    .filter_map(|fields| {
+      let name = fields.first()?.trim();
+      let addr = parse_number(fields.get(1)?.trim())?;
+      let size = fields
+        .get(2)
+        .map(|size| size.trim())
+        .filter(|size| !size.is_empty())
+        .and_then(parse_number);
+
+      Some(SymbolEntry {
+        name: undecorate(name),
+        addr,
+        size: size.map(|size| size as usize),
+      })
+    })
+    .collect()
+}
+
+/// Writes functions and the entries of the import address table in a format other tools can load.
+/// `module` is the file name of the binary they belong to.
+pub fn write_symbols(
+  writer: &mut dyn Write,
+  format: SymbolExportFormat,
+  functions: &[SymbolEntry],
+  imports: &[SymbolEntry],
+  image: &PeImage,
+  module: &str,
+) -> IoResult<()> {
+  match format {
+    SymbolExportFormat::Idc => write_idc(writer, functions, imports, module),
+    SymbolExportFormat::Ghidra => write_ghidra_script(writer, functions, imports, module),
+    SymbolExportFormat::X64dbg => write_x64dbg_database(writer, functions, imports, image, module),
+    SymbolExportFormat::Map => write_map(writer, functions, imports, image, module),
+  }
+}
+
+fn write_idc(writer: &mut dyn Write, functions: &[SymbolEntry], imports: &[SymbolEntry], module: &str) -> IoResult<()> {
+  writeln!(writer, "// Symbols of {module}, generated by starsource-comparer.")?;
+  writeln!(writer, "#include <idc.idc>")?;
+  writeln!(writer)?;
+  writeln!(writer, "static main() {{")?;
+  for symbol in functions {
+    if let Some(size) = symbol.size {
+      writeln!(
+        writer,
+        "  add_func({:#X}, {:#X});",
+        symbol.addr,
+        symbol.addr + size as u64
+      )?;
+    }
+    writeln!(
+      writer,
+      "  set_name({:#X}, \"{}\", SN_NOCHECK | SN_NOWARN);",
+      symbol.addr,
+      escape_string(&symbol.name)
+    )?;
+  }
+  for symbol in imports {
+    writeln!(
+      writer,
+      "  set_name({:#X}, \"__imp_{}\", SN_NOCHECK | SN_NOWARN);",
+      symbol.addr,
+      escape_string(&symbol.name)
+    )?;
+  }
+  writeln!(writer, "}}")
+}
+
+fn write_ghidra_script(
+  writer: &mut dyn Write,
+  functions: &[SymbolEntry],
+  imports: &[SymbolEntry],
+  module: &str,
+) -> IoResult<()> {
+  writeln!(writer, "# Symbols of {module}, generated by starsource-comparer.")?;
+  writeln!(writer, "# @category Starsource")?;
+  writeln!(writer, "from ghidra.program.model.symbol import SourceType")?;
+  writeln!(writer)?;
+  writeln!(writer, "FUNCTIONS = [")?;
+  for symbol in functions {
+    writeln!(writer, "    ({:#X}, \"{}\"),", symbol.addr, escape_string(&symbol.name))?;
+  }
+  writeln!(writer, "]")?;
+  writeln!(writer, "IMPORTS = [")?;
+  for symbol in imports {
+    writeln!(writer, "    ({:#X}, \"{}\"),", symbol.addr, escape_string(&symbol.name))?;
+  }
+  writeln!(writer, "]")?;
+  writeln!(writer)?;
+  writeln!(writer, "for addr, name in FUNCTIONS:")?;
+  writeln!(writer, "    address = toAddr(addr)")?;
+  writeln!(writer, "    function = getFunctionAt(address)")?;
+  writeln!(writer, "    if function is None:")?;
+  writeln!(writer, "        function = createFunction(address, name)")?;
+  writeln!(writer, "    if function is not None:")?;
+  writeln!(writer, "        function.setName(name, SourceType.IMPORTED)")?;
+  writeln!(writer, "for addr, name in IMPORTS:")?;
+  writeln!(
+    writer,
+    "    createLabel(toAddr(addr), \"__imp_\" + name, True, SourceType.IMPORTED)"
+  )
+}
+
+#[derive(Serialize)]
+struct X64dbgDatabase {
+  labels: Vec<X64dbgLabel>,
+  functions: Vec<X64dbgFunction>,
+}
+
+#[derive(Serialize)]
+struct X64dbgLabel {
+  module: String,
+  /// Hex RVA.
+  address: String,
+  manual: bool,
+  text: String,
+}
+
+#[derive(Serialize)]
+struct X64dbgFunction {
+  module: String,
+  start: String,
+  /// RVA of the last byte of the function.
+  end: String,
+  manual: bool,
+  icount: u32,
+}
+
+fn write_x64dbg_database(
+  writer: &mut dyn Write,
+  functions: &[SymbolEntry],
+  imports: &[SymbolEntry],
+  image: &PeImage,
+  module: &str,
+) -> IoResult<()> {
+  let module = module.to_lowercase();
+  let rva = |addr: u64| format!("{:#x}", image.va_to_rva(addr).unwrap_or(addr));
+
+  let database = X64dbgDatabase {
+    labels: functions
+      .iter()
+      .map(|symbol| (symbol, symbol.name.clone()))
+      .chain(imports.iter().map(|symbol| (symbol, format!("__imp_{}", symbol.name))))
+      .map(|(symbol, text)| X64dbgLabel {
+        module: module.clone(),
+        address: rva(symbol.addr),
+        manual: true,
+        text,
+      })
+      .collect(),
+    functions: functions
+      .iter()
+      .filter_map(|symbol| {
+        let size = symbol.size.filter(|&size| size > 0)?;
+        Some(X64dbgFunction {
+          module: module.clone(),
+          start: rva(symbol.addr),
+          end: rva(symbol.addr + size as u64 - 1),
+          manual: true,
+          icount: 0,
+        })
+      })
+      .collect(),
+  };
+
+  serde_json::to_writer_pretty(&mut *writer, &database)?;
+  writeln!(writer)
+}
+
+/// Writes the publics of an MSVC MAP file, marking functions with `f` so the linker's map can be read back.
+fn write_map(
+  writer: &mut dyn Write,
+  functions: &[SymbolEntry],
+  imports: &[SymbolEntry],
+  image: &PeImage,
+  module: &str,
+) -> IoResult<()> {
+  let module_name = Path::new(module)
+    .file_stem()
+    .map_or_else(|| module.into(), |stem| stem.to_string_lossy());
+  writeln!(writer, " {module_name}")?;
+  writeln!(writer)?;
+  writeln!(writer, " Preferred load address is {:08X}", image.image_base)?;
+  writeln!(writer)?;
+  writeln!(
+    writer,
+    "  Address         Publics by Value              Rva+Base       Lib:Object"
+  )?;
+  writeln!(writer)?;
+
+  let mut publics = functions
+    .iter()
+    .map(|symbol| {
+      (
+        symbol,
+        symbol.name.replace(char::is_whitespace, "_"),
+        "f   comparer-config.toml",
+      )
+    })
+    .chain(
+      imports
+        .iter()
+        .map(|symbol| (symbol, format!("__imp_{}", symbol.name), "    <import>")),
+    )
+    .collect::<Vec<_>>();
+  publics.sort_by_key(|(symbol, ..)| symbol.addr);
+
+  for (symbol, name, object) in publics {
+    let (section, offset) = image
+      .section_offset(symbol.addr)
+      .unwrap_or((0, image.va_to_rva(symbol.addr).unwrap_or(symbol.addr)));
+    writeln!(
+      writer,
+      " {section:04X}:{offset:08X}       {name:<26} {:08X} {object}",
+      symbol.addr
+    )?;
+  }
+
+  Ok(())
+}
+
+/// Escapes a name for a string literal in IDC or Python.
+fn escape_string(name: &str) -> String {
+  name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn undecorate(name: &str) -> String {
+  match name.starts_with('?').then(|| demangle(name)).flatten() {
+    Some(demangled) => demangled.name,
+    None => name.to_string(),
+  }
+}
+
+/// Names that disassemblers make up for unnamed functions, and import address table entries.
+fn is_generated_name(name: &str) -> bool {
+  ["sub_", "nullsub_", "FUN_", "j_", "__imp_"]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+/// Parses a `0x` prefixed hex number or a decimal one.
+fn parse_number(value: &str) -> Option<u64> {
+  match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => value.parse().ok(),
+  }
+}
+
+/// Splits a line of CSV, removing the quotes around fields.
+fn split_csv_line(line: &str) -> Vec<String> {
+  let mut fields = Vec::new();
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = line.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => {
+        field.push('"');
+        chars.next();
+      }
+      '"' => quoted = !quoted,
+      ',' if !quoted => fields.push(std::mem::take(&mut field)),
+      _ => field.push(c),
+    }
+  }
+  fields.push(field);
+
+  fields
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pe::Section;
+
+  fn image() -> PeImage {
+    PeImage {
+      image_base: 0x400000,
+      sections: vec![Section {
+        name: String::from(".text"),
+        virtual_address: 0x1000,
+        raw_offset: 0x400,
+        raw_size: 0x10000,
+        executable: true,
+      }],
+    }
+  }
+
+  fn symbol(name: &str, addr: u64, size: Option<usize>) -> SymbolEntry {
+    SymbolEntry {
+      name: String::from(name),
+      addr,
+      size,
+    }
+  }
+
+  #[test]
+  fn msvc_map() {
+    let map = " Starcraft
+
+ Preferred load address is 00400000
+
+ Start         Length     Name                   Class
+ 0001:00000000 00012345H .text                   CODE
+
+  Address         Publics by Value              Rva+Base       Lib:Object
+
+ 0000:00000000       ___safe_se_handler_count   00000000     <absolute>
+ 0001:00000230       ?foo@@YAXXZ                00401230 f   foo.obj
+ 0001:00000300       _bar                       00401300 f   bar.obj
+ 0002:00000010       _data                      00413010     data.obj
+ 0001:00000400       _sub_401400                00401400 f   gen.obj
+";
+    assert_eq!(
+      parse_map(map, &image()),
+      vec![symbol("foo", 0x401230, None), symbol("bar", 0x401300, None)]
+    );
+  }
+
+  #[test]
+  fn ida_map() {
+    let map = "
+ Start         Length     Name                   Class
+ 0001:00000000 000A1234H .text                   CODE
+
+  Address         Publics by Value
+
+ 0001:00000230       foo
+ 0001:00000300       sub_401300
+";
+    assert_eq!(parse_map(map, &image()), vec![symbol("foo", 0x401230, None)]);
+  }
+
+  #[test]
+  fn idc() {
+    let idc = r#"
+static main() {
+  MakeName(0x401230, "?foo@@YAXXZ");
+  set_name(0X401300, "Bar::baz", SN_NOCHECK);
+  MakeName(0x401400, "sub_401400");
+  add_func(0x401300, 0x401340);
+}
+"#;
+    assert_eq!(
+      parse_idc(idc),
+      vec![symbol("foo", 0x401230, None), symbol("Bar::baz", 0x401300, Some(0x40))]
+    );
+  }
+
+  #[test]
+  fn csv() {
+    let csv = "name,addr,size
+foo,0x401230,0x20
+\"Bar::operator,\",4199168,
+";
+    assert_eq!(
+      parse_csv(csv),
+      vec![
+        symbol("foo", 0x401230, Some(0x20)),
+        symbol("Bar::operator,", 0x401300, None)
+      ]
+    );
+  }
+
+  #[test]
+  fn ghidra_csv() {
+    let csv = "\"Name\",\"Location\",\"Type\",\"Namespace\"
+\"foo\",\"00401230\",\"Function\",\"Global\"
+\"baz\",\"ram:00401300\",\"Function\",\"Bar\"
+\"s_text\",\"00413010\",\"Data Label\",\"Global\"
+\"FUN_00401400\",\"00401400\",\"Function\",\"Global\"
+";
+    assert_eq!(
+      parse_ghidra_csv(csv),
+      vec![symbol("foo", 0x401230, None), symbol("Bar::baz", 0x401300, None)]
+    );
+  }
+
+  #[test]
+  fn quoted_csv_fields() {
+    assert_eq!(
+      split_csv_line(r#"a,"b,c","say ""hi""",,"#),
+      ["a", "b,c", "say \"hi\"", "", ""]
+    );
+  }
+}